@@ -1,22 +1,37 @@
 //! The aggregate portion of the PPM protocol, per §4.3 of RFCXXXX
 
 use crate::{
+    aggregation_job::AggregationJobId,
+    clock::Clock,
+    differential_privacy::{Noise, NoisyAggregateShare},
     error::{IntoHttpApiProblem, ProblemDocumentType},
     hpke,
+    metrics::Metrics,
     parameters::{Parameters, TaskId},
+    replay::NonceIndex,
     report::{self, Report},
-    Interval, Nonce, Role,
+    storage::{AccumulatorRecord, Mutation, Store},
+    task::Task,
+    Interval, Nonce, Role, Time,
 };
+use hmac::{Hmac, Mac, NewMac};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use prio::{
-    codec::{decode_u16_items, encode_u16_items, CodecError, Decode, Encode, ParameterizedDecode},
+    codec::{
+        decode_u16_items, decode_u24_items, encode_u16_items, encode_u24_items, CodecError, Decode,
+        Encode, ParameterizedDecode,
+    },
     vdaf::{self, Aggregatable, PrepareTransition},
 };
+use rand::thread_rng;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::Entry, HashMap},
     convert::TryFrom,
     fmt::Debug,
     io::{Cursor, Read},
+    sync::{Arc, Mutex},
 };
 use tracing::{info, warn};
 
@@ -52,6 +67,26 @@ pub enum Error {
     CodecError(#[from] prio::codec::CodecError),
     #[error("unexpected prepare state transition: {0}")]
     UnexpectedStateTransition(String),
+    #[error("storage error {0}")]
+    Storage(#[from] crate::storage::Error),
+    #[error("stored accumulator error {0}")]
+    StoredAccumulator(#[source] serde_json::Error),
+    #[error("aggregate message tag does not verify")]
+    InvalidHmac,
+    #[error("report timestamp too far in the future: {0}")]
+    ReportTooEarly(Nonce),
+    #[error("report timestamp too far in the past: {0}")]
+    ReportTooLate(Nonce),
+    #[error("report replayed: {0}")]
+    ReportReplayed(Nonce),
+    #[error("invalid aggregation parameter: {0}")]
+    InvalidAggregationParameter(String),
+    #[error("no reports were aggregated under the aggregation parameter")]
+    UnknownAggregationParameter,
+    #[error("differential privacy error {0}")]
+    DifferentialPrivacy(#[from] crate::differential_privacy::Error),
+    #[error("batch interval partially overlaps previously collected {0}")]
+    BatchOverlap(Interval),
 }
 
 impl IntoHttpApiProblem for Error {
@@ -65,6 +100,17 @@ impl IntoHttpApiProblem for Error {
             Self::StaleReport(_) => Some(ProblemDocumentType::StaleReport),
             Self::UnknownHpkeConfig(_) => Some(ProblemDocumentType::OutdatedConfig),
             Self::UnrecognizedTask(_) => Some(ProblemDocumentType::UnrecognizedTask),
+            Self::InvalidHmac => Some(ProblemDocumentType::InvalidHmac),
+            Self::ReportTooEarly(_) => Some(ProblemDocumentType::ReportTooEarly),
+            Self::ReportTooLate(_) => Some(ProblemDocumentType::ReportTooLate),
+            Self::ReportReplayed(_) => Some(ProblemDocumentType::ReportReplayed),
+            Self::InvalidAggregationParameter(_) => {
+                Some(ProblemDocumentType::InvalidAggregationParameter)
+            }
+            Self::UnknownAggregationParameter => {
+                Some(ProblemDocumentType::UnknownAggregationParameter)
+            }
+            Self::BatchOverlap(_) => Some(ProblemDocumentType::BatchOverlap),
             _ => None,
         }
     }
@@ -77,6 +123,9 @@ impl From<Error> for TransitionError {
             Error::UnknownHpkeConfig(_) => TransitionError::HpkeUnknownConfigId,
             Error::Encryption(_) => TransitionError::HpkeDecryptError,
             Error::Vdaf(_) => TransitionError::VdafPrepError,
+            Error::ReportTooEarly(_) => TransitionError::ReportTooEarly,
+            Error::ReportTooLate(_) => TransitionError::ReportTooLate,
+            Error::ReportReplayed(_) => TransitionError::ReportReplayed,
             unhandled_error => {
                 warn!(?unhandled_error, "unhandled error!");
                 TransitionError::ReportDropped
@@ -86,7 +135,7 @@ impl From<Error> for TransitionError {
 }
 
 /// A report share transmitted from a leader to a helper
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ReportShare {
     pub nonce: Nonce,
     pub extensions: Vec<report::Extension>,
@@ -180,6 +229,8 @@ pub enum TransitionError {
     HpkeDecryptError = 4,
     VdafPrepError = 5,
     UnrecognizedNonce = 6,
+    ReportTooEarly = 7,
+    ReportTooLate = 8,
 }
 
 /// A state transition message exchanged between leader and helper
@@ -215,6 +266,19 @@ pub enum Aggregate {
     ShareResponse(hpke::Ciphertext),
 }
 
+impl Aggregate {
+    /// The task that a message sent by the leader pertains to. Returns `None`
+    /// for messages sent by the helper, which don't carry a task ID.
+    pub fn task_id(&self) -> Option<TaskId> {
+        match self {
+            Self::Initialize(req) => Some(req.task_id),
+            Self::Request(req) => Some(req.task_id),
+            Self::ShareRequest(req) => Some(req.task_id),
+            Self::Response(_) | Self::ShareResponse(_) => None,
+        }
+    }
+}
+
 impl Encode for Aggregate {
     fn encode(&self, bytes: &mut Vec<u8>) {
         // We encode the union discriminant and then the encoding of the
@@ -270,6 +334,8 @@ impl Decode for Aggregate {
 #[derive(Clone, Debug)]
 pub struct AggregateInitReq {
     pub task_id: TaskId,
+    /// The leader's identifier for the aggregation job
+    pub job_id: AggregationJobId,
     pub aggregation_parameter: Vec<u8>,
     pub report_shares: Vec<ReportShare>,
 }
@@ -277,6 +343,7 @@ pub struct AggregateInitReq {
 impl Encode for AggregateInitReq {
     fn encode(&self, bytes: &mut Vec<u8>) {
         self.task_id.encode(bytes);
+        self.job_id.encode(bytes);
         encode_u16_items(bytes, &(), &self.aggregation_parameter);
         encode_u16_items(bytes, &(), &self.report_shares);
     }
@@ -285,11 +352,13 @@ impl Encode for AggregateInitReq {
 impl Decode for AggregateInitReq {
     fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
         let task_id = TaskId::decode(bytes)?;
+        let job_id = AggregationJobId::decode(bytes)?;
         let aggregation_parameter = decode_u16_items(&(), bytes)?;
         let report_shares = decode_u16_items(&(), bytes)?;
 
         Ok(Self {
             task_id,
+            job_id,
             aggregation_parameter,
             report_shares,
         })
@@ -300,6 +369,10 @@ impl Decode for AggregateInitReq {
 #[derive(Clone, Debug)]
 pub struct AggregateReq {
     pub task_id: TaskId,
+    /// The leader's identifier for the aggregation job
+    pub job_id: AggregationJobId,
+    /// Number of aggregate requests of the job the helper has answered so far
+    pub round: u16,
     pub helper_state: Vec<u8>,
     pub transitions: Vec<TransitionMessage>,
 }
@@ -307,6 +380,8 @@ pub struct AggregateReq {
 impl Encode for AggregateReq {
     fn encode(&self, bytes: &mut Vec<u8>) {
         self.task_id.encode(bytes);
+        self.job_id.encode(bytes);
+        self.round.encode(bytes);
         encode_u16_items(bytes, &(), &self.helper_state);
         encode_u16_items(bytes, &(), &self.transitions);
     }
@@ -315,11 +390,15 @@ impl Encode for AggregateReq {
 impl Decode for AggregateReq {
     fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
         let task_id = TaskId::decode(bytes)?;
+        let job_id = AggregationJobId::decode(bytes)?;
+        let round = u16::decode(bytes)?;
         let helper_state = decode_u16_items(&(), bytes)?;
         let transitions = decode_u16_items(&(), bytes)?;
 
         Ok(Self {
             task_id,
+            job_id,
+            round,
             helper_state,
             transitions,
         })
@@ -357,12 +436,15 @@ impl Decode for AggregateResp {
 pub struct AggregateShareReq {
     pub task_id: TaskId,
     pub batch_interval: Interval,
+    /// Encoded aggregation parameter of the collect request
+    pub aggregation_parameter: Vec<u8>,
 }
 
 impl Encode for AggregateShareReq {
     fn encode(&self, bytes: &mut Vec<u8>) {
         self.task_id.encode(bytes);
         self.batch_interval.encode(bytes);
+        encode_u16_items(bytes, &(), &self.aggregation_parameter);
     }
 }
 
@@ -370,10 +452,12 @@ impl Decode for AggregateShareReq {
     fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
         let task_id = TaskId::decode(bytes)?;
         let batch_interval = Interval::decode(bytes)?;
+        let aggregation_parameter = decode_u16_items(&(), bytes)?;
 
         Ok(Self {
             task_id,
             batch_interval,
+            aggregation_parameter,
         })
     }
 }
@@ -387,6 +471,31 @@ pub struct AggregateMessage {
     pub tag: [u8; 32],
 }
 
+impl AggregateMessage {
+    /// Construct a message, computing its tag over the encoded `aggregate`
+    /// with the provided key, which should be the task's
+    /// `aggregator_auth_key`.
+    pub fn new(aggregate: Aggregate, key: &[u8]) -> Self {
+        let tag = Self::mac(&aggregate, key).finalize().into_bytes().into();
+
+        Self { aggregate, tag }
+    }
+
+    /// Check the message's tag against the provided key in constant time.
+    pub fn verify(&self, key: &[u8]) -> Result<(), Error> {
+        Self::mac(&self.aggregate, key)
+            .verify(&self.tag)
+            .map_err(|_| Error::InvalidHmac)
+    }
+
+    fn mac(aggregate: &Aggregate, key: &[u8]) -> Hmac<Sha256> {
+        // HMAC accepts keys of any length
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(&aggregate.get_encoded());
+        mac
+    }
+}
+
 impl Encode for AggregateMessage {
     fn encode(&self, bytes: &mut Vec<u8>) {
         self.aggregate.encode(bytes);
@@ -411,108 +520,436 @@ pub(crate) struct Accumulator<S> {
     pub(crate) accumulated: S,
     /// How many contributions are included
     pub(crate) contributions: u64,
+    /// Checksum over the nonces of the included contributions, as computed by
+    /// [`update_checksum`]
+    pub(crate) checksum: [u8; 32],
     /// Consumed privacy budget for the aggregation interval. Measured in number
     /// of queries.
     pub(crate) consumed_privacy_budget: u64,
+    /// Privacy budget reserved for aggregate shares about to be released. It
+    /// is only added to the consumed budget, and written to the store, once
+    /// the release is committed.
+    pub(crate) reserved_privacy_budget: u64,
+}
+
+/// Plaintext of an aggregate share encrypted to the collector. Besides the VDAF
+/// aggregate share, it summarizes the reports aggregated into the share, so
+/// that the collector can tell whether both aggregators aggregated the same
+/// reports.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AggregateSharePlaintext {
+    /// Number of reports aggregated into the share
+    pub report_count: u64,
+    /// XOR of the SHA-256 hashes of the nonces of the reports aggregated into
+    /// the share
+    pub checksum: [u8; 32],
+    /// Encoded VDAF aggregate share
+    pub aggregate_share: Vec<u8>,
+}
+
+impl Encode for AggregateSharePlaintext {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.report_count.encode(bytes);
+        bytes.extend_from_slice(&self.checksum);
+        encode_u24_items(bytes, &(), &self.aggregate_share);
+    }
 }
 
-pub(crate) fn dump_accumulators<S: Debug>(accumulators: &HashMap<Interval, Accumulator<S>>) {
+impl Decode for AggregateSharePlaintext {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        let report_count = u64::decode(bytes)?;
+        let mut checksum = [0u8; 32];
+        bytes.read_exact(&mut checksum)?;
+        let aggregate_share = decode_u24_items(&(), bytes)?;
+
+        Ok(Self {
+            report_count,
+            checksum,
+            aggregate_share,
+        })
+    }
+}
+
+/// Fold the report's nonce into a checksum over a set of reports.
+pub(crate) fn update_checksum(checksum: &mut [u8; 32], nonce: Nonce) {
+    merge_checksum(checksum, &Sha256::digest(&nonce.get_encoded()));
+}
+
+/// Fold a checksum over another set of reports into a checksum.
+fn merge_checksum(checksum: &mut [u8; 32], other: &[u8]) {
+    for (byte, other_byte) in checksum.iter_mut().zip(other) {
+        *byte ^= other_byte;
+    }
+}
+
+/// Accumulators are kept per batch interval and encoded aggregation parameter
+type AccumulatorKey = (Interval, Vec<u8>);
+
+/// Privacy budget reserved by an extracted aggregate share, to be committed or
+/// rolled back by the [`Aggregator`] that extracted it.
+#[must_use]
+#[derive(Debug)]
+pub(crate) struct PendingRelease {
+    batch_interval: Interval,
+    /// The accumulators whose budget the share consumes
+    accumulator_keys: Vec<AccumulatorKey>,
+}
+
+/// The record under which the accumulator for the interval and aggregation
+/// parameter is persisted. Privacy budget that is only reserved isn't part of
+/// it.
+fn accumulator_record<S: Serialize>(
+    (interval, aggregation_parameter): &AccumulatorKey,
+    accumulator: &Accumulator<S>,
+) -> Result<AccumulatorRecord, Error> {
+    Ok(AccumulatorRecord {
+        interval: *interval,
+        aggregation_parameter: aggregation_parameter.clone(),
+        accumulated: serde_json::to_value(&accumulator.accumulated)
+            .map_err(Error::StoredAccumulator)?,
+        contributions: accumulator.contributions,
+        checksum: accumulator.checksum,
+        consumed_privacy_budget: accumulator.consumed_privacy_budget,
+    })
+}
+
+pub(crate) fn dump_accumulators<S: Debug>(accumulators: &HashMap<AccumulatorKey, Accumulator<S>>) {
     if accumulators.is_empty() {
         info!("accumulators are empty");
     }
-    for (interval, accumulated) in accumulators {
-        info!(?interval, ?accumulated, "accumulated value for interval");
+    for ((interval, aggregation_parameter), accumulated) in accumulators {
+        info!(
+            ?interval,
+            aggregation_parameter = %hex::encode(aggregation_parameter),
+            ?accumulated,
+            "accumulated value for interval"
+        );
     }
 }
 
-#[derive(Clone, Debug)]
+/// State an aggregator keeps about batch intervals. Both parts are guarded by
+/// one lock so that reports can't be accumulated into an interval while its
+/// aggregate share is being extracted.
+#[derive(Debug)]
+struct Batches<S> {
+    /// The batch intervals for which this aggregator has received either a
+    /// collect request or an aggregate share request, depending on the role,
+    /// as requested. They may span several minimum batch durations but never
+    /// partially overlap each other. Each is mapped to the number of aggregate
+    /// shares released or about to be released for it.
+    collected_batch_intervals: HashMap<Interval, u64>,
+    /// Accumulated sums over inputs that have been verified in conjunction with
+    /// the helper. The key is the batch interval and the encoded aggregation
+    /// parameter the inputs were prepared under.
+    accumulators: HashMap<AccumulatorKey, Accumulator<S>>,
+}
+
+impl<S> Batches<S> {
+    /// Whether the minimum duration interval is part of a collected batch
+    /// interval.
+    fn interval_collected(&self, interval: Interval) -> bool {
+        self.collected_batch_intervals
+            .keys()
+            .any(|collected| collected.contains(interval.start))
+    }
+
+    /// Number of times aggregate shares have been extracted for the batch
+    /// interval, under any aggregation parameter, including those whose release
+    /// is pending.
+    fn consumed_privacy_budget(&self, interval: Interval) -> u64 {
+        self.accumulators
+            .iter()
+            .filter(|((accumulator_interval, _), _)| *accumulator_interval == interval)
+            .map(|(_, accumulator)| {
+                accumulator.consumed_privacy_budget + accumulator.reserved_privacy_budget
+            })
+            .sum()
+    }
+}
+
+/// VDAF preparation and accumulation for one task. All methods take `&self`,
+/// so that an aggregator may be shared by concurrent requests.
+#[derive(Debug)]
 pub(crate) struct Aggregator<A: vdaf::Aggregator> {
     role: Role,
     hpke_config: hpke::Config,
     pub aggregator: A,
     pub verify_parameter: A::VerifyParam,
-    aggregation_parameter: A::AggregationParam,
-    /// The batch intervals for which this aggregator has received either a
-    /// collect request or an aggregate share request, depending on the role.
-    collected_batch_intervals: HashSet<Interval>,
+    /// Parameter reports are aggregated under as soon as they arrive. If
+    /// unset, reports are prepared under the parameter of each collect request
+    /// instead.
+    aggregation_parameter: Option<A::AggregationParam>,
     task_parameters: Parameters,
-    /// Accumulated sums over inputs that have been verified in conjunction with
-    /// the helper. The key is the batch interval.
-    accumulators: HashMap<Interval, Accumulator<A::AggregateShare>>,
+    /// Noise added to released aggregate shares, if the task requires
+    /// differential privacy
+    noise: Option<Noise>,
+    batches: Mutex<Batches<A::AggregateShare>>,
+    /// Nonces of the reports this aggregator has taken on
+    nonce_index: NonceIndex,
+    /// Durable copy of the accumulators and collected batch intervals.
+    store: Arc<dyn Store>,
+    clock: Arc<dyn Clock>,
+    metrics: Arc<Metrics>,
 }
 
-impl<A: vdaf::Aggregator> Aggregator<A> {
+impl<A: vdaf::Aggregator> Aggregator<A>
+where
+    A::AggregateShare: Serialize + DeserializeOwned + NoisyAggregateShare,
+{
+    /// Construct an aggregator for the task, restoring any accumulators and
+    /// collected batch intervals previously written to the task's store.
     pub(crate) fn new(
         role: Role,
         hpke_config: &hpke::Config,
-        aggregator: &A,
-        verify_parameter: &A::VerifyParam,
-        aggregation_parameter: &A::AggregationParam,
-        task_parameters: &Parameters,
-    ) -> Self {
-        // TODO: construct accumulators here once we stop storing them in
-        // state blob
+        task: &Task<A>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, Error> {
         // TODO: construct aggregator here from task_parameters
-        Self {
+        let store = task.store.clone();
+        let accumulators = store
+            .accumulators()?
+            .into_iter()
+            .map(|record| {
+                Ok((
+                    (record.interval, record.aggregation_parameter),
+                    Accumulator {
+                        accumulated: serde_json::from_value(record.accumulated)
+                            .map_err(Error::StoredAccumulator)?,
+                        contributions: record.contributions,
+                        checksum: record.checksum,
+                        consumed_privacy_budget: record.consumed_privacy_budget,
+                        reserved_privacy_budget: 0,
+                    },
+                ))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Self {
             role,
             hpke_config: hpke_config.clone(),
-            aggregator: aggregator.clone(),
-            collected_batch_intervals: HashSet::new(),
-            verify_parameter: verify_parameter.clone(),
-            task_parameters: task_parameters.clone(),
-            aggregation_parameter: aggregation_parameter.clone(),
-            accumulators: HashMap::new(),
+            aggregator: task.vdaf.clone(),
+            verify_parameter: task.verify_parameter.clone(),
+            task_parameters: task.parameters.clone(),
+            noise: task.parameters.noise()?,
+            aggregation_parameter: task.aggregation_parameter.clone(),
+            batches: Mutex::new(Batches {
+                collected_batch_intervals: store
+                    .collected_batch_intervals()?
+                    .into_iter()
+                    .map(|interval| (interval, 1))
+                    .collect(),
+                accumulators,
+            }),
+            nonce_index: NonceIndex::new(task.parameters.min_batch_duration),
+            store,
+            clock: task.clock.clone(),
+            metrics,
+        })
+    }
+
+    /// The parameter reports are aggregated under as soon as they arrive, if
+    /// any.
+    pub(crate) fn eager_aggregation_parameter(&self) -> Option<&A::AggregationParam> {
+        self.aggregation_parameter.as_ref()
+    }
+
+    /// Decode an aggregation parameter received from a peer, checking that it
+    /// is canonically encoded and, if the task has an eager aggregation
+    /// parameter, that it is that one.
+    pub(crate) fn validate_aggregation_parameter(
+        &self,
+        aggregation_parameter: &[u8],
+    ) -> Result<A::AggregationParam, Error> {
+        let decoded = A::AggregationParam::get_decoded(aggregation_parameter)
+            .map_err(|e| Error::InvalidAggregationParameter(e.to_string()))?;
+        if decoded.get_encoded() != aggregation_parameter {
+            return Err(Error::InvalidAggregationParameter(
+                "not canonically encoded".to_string(),
+            ));
+        }
+
+        if let Some(eager_aggregation_parameter) = &self.aggregation_parameter {
+            if eager_aggregation_parameter.get_encoded() != aggregation_parameter {
+                return Err(Error::InvalidAggregationParameter(
+                    "task aggregates under a different aggregation parameter".to_string(),
+                ));
+            }
+        }
+
+        Ok(decoded)
+    }
+
+    /// Whether the batch interval is done with: no further aggregate shares
+    /// will be extracted for it, so its reports have served their purpose.
+    /// With an eager aggregation parameter, that is the case as soon as the
+    /// interval has been collected. Otherwise, the interval may be collected
+    /// again under other aggregation parameters until its privacy budget is
+    /// used up.
+    fn interval_closed(&self, batches: &Batches<A::AggregateShare>, interval: Interval) -> bool {
+        if self.aggregation_parameter.is_some() {
+            batches.interval_collected(interval)
+        } else {
+            batches.consumed_privacy_budget(interval) >= self.task_parameters.max_batch_lifetime
+        }
+    }
+
+    /// Whether the batch interval the report falls into is done with, as
+    /// described in [`Self::interval_closed`].
+    pub(crate) fn batch_closed(&self, nonce: Nonce) -> bool {
+        self.interval_closed(
+            &self.batches.lock().unwrap(),
+            nonce
+                .time
+                .batch_interval(self.task_parameters.min_batch_duration),
+        )
+    }
+
+    /// Whether the batch interval the report falls into has been collected.
+    pub(crate) fn batch_collected(&self, nonce: Nonce) -> bool {
+        self.batches.lock().unwrap().interval_collected(
+            nonce
+                .time
+                .batch_interval(self.task_parameters.min_batch_duration),
+        )
+    }
+
+    /// Record that the aggregator has taken on the report under the encoded
+    /// aggregation parameter, failing if it already had.
+    pub(crate) fn record_nonce(
+        &self,
+        nonce: Nonce,
+        aggregation_parameter: &[u8],
+    ) -> Result<(), Error> {
+        if self.nonce_index.insert(nonce, aggregation_parameter) {
+            Ok(())
+        } else {
+            Err(Error::ReportReplayed(nonce))
+        }
+    }
+
+    /// Undo [`Self::record_nonce`] for a report the aggregator failed to take
+    /// on after all.
+    pub(crate) fn forget_nonce(&self, nonce: Nonce, aggregation_parameter: &[u8]) {
+        self.nonce_index.remove(nonce, aggregation_parameter);
+    }
+
+    /// Whether the aggregator has already taken on the report under the
+    /// encoded aggregation parameter.
+    pub(crate) fn nonce_seen(&self, nonce: Nonce, aggregation_parameter: &[u8]) -> bool {
+        self.nonce_index.contains(nonce, aggregation_parameter)
+    }
+
+    /// Forget the nonces of reports in batch intervals that can no longer
+    /// accept reports, because they are done with or because all their
+    /// reports would be rejected as too late.
+    pub(crate) fn prune_nonce_index(&self) {
+        let now = self.now();
+        let max_report_age = self.task_parameters.max_report_age;
+        let batches = self.batches.lock().unwrap();
+
+        let forgotten = self.nonce_index.forget_closed(|interval| {
+            let too_old = match max_report_age {
+                Some(max_report_age) => {
+                    interval
+                        .start
+                        .0
+                        .saturating_add(interval.duration.0)
+                        .saturating_add(max_report_age.0)
+                        <= now.0
+                }
+                None => false,
+            };
+
+            self.interval_closed(&batches, interval) || too_old
+        });
+        if forgotten > 0 {
+            info!(
+                forgotten,
+                remaining = self.nonce_index.len(),
+                "pruned nonce index"
+            );
         }
     }
 
+    /// The current time according to the task's clock.
+    pub(crate) fn now(&self) -> Time {
+        self.clock.now()
+    }
+
+    /// Check that the report's timestamp is neither too far in the future,
+    /// allowing for the task's tolerable clock skew, nor older than the task's
+    /// maximum report age.
+    pub(crate) fn check_report_time(&self, nonce: Nonce) -> Result<(), Error> {
+        let now = self.now();
+        if nonce.time.0
+            > now
+                .0
+                .saturating_add(self.task_parameters.tolerable_clock_skew.0)
+        {
+            return Err(Error::ReportTooEarly(nonce));
+        }
+
+        if let Some(max_report_age) = self.task_parameters.max_report_age {
+            if nonce.time.0 < now.0.saturating_sub(max_report_age.0) {
+                return Err(Error::ReportTooLate(nonce));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that a newly received report's batch interval hasn't been
+    /// collected and that this aggregator's input share decrypts and decodes,
+    /// without preparing it. Used for reports that wait for a collect request
+    /// to supply the aggregation parameter to prepare them under.
     #[tracing::instrument(skip(self, extensions, report_share), err)]
-    pub(crate) fn prepare_message(
+    pub(crate) fn check_input_share(
         &self,
         report_task_id: TaskId,
         nonce: Nonce,
         extensions: &[report::Extension],
         report_share: &hpke::Ciphertext,
-    ) -> Result<(A::PrepareStep, A::PrepareMessage), Error> {
-        if self.task_parameters.task_id != report_task_id {
-            return Err(Error::UnrecognizedTask(report_task_id));
-        }
-
-        if self.collected_batch_intervals.contains(
-            &nonce
-                .time
-                .batch_interval(self.task_parameters.min_batch_duration),
-        ) {
+    ) -> Result<(), Error> {
+        if self.batch_collected(nonce) {
             return Err(Error::StaleReport(nonce));
         }
 
-        if report_share.config_id != self.hpke_config.id {
-            return Err(Error::UnknownHpkeConfig(report_share.config_id));
-        }
+        self.open_input_share(report_task_id, nonce, extensions, report_share)?;
 
-        let hpke_recipient = self.hpke_config.recipient(
-            &self.task_parameters.task_id,
-            hpke::Label::InputShare,
-            Role::Client,
-            self.role,
-            &report_share.encapsulated_context,
-        )?;
+        Ok(())
+    }
 
-        let plaintext =
-            hpke_recipient.open(report_share, &Report::associated_data(nonce, extensions))?;
-        info!(plaintext_len = ?plaintext.len(), "decoding input share");
-        let input_share =
-            A::InputShare::get_decoded_with_param(&self.verify_parameter, &plaintext)?;
-        info!("decoded input share");
+    /// Prepare the report under the aggregation parameter, returning the
+    /// prepare step and this aggregator's first prepare message.
+    #[tracing::instrument(skip(self, extensions, report_share, aggregation_parameter), err)]
+    pub(crate) fn prepare_message(
+        &self,
+        report_task_id: TaskId,
+        nonce: Nonce,
+        extensions: &[report::Extension],
+        report_share: &hpke::Ciphertext,
+        aggregation_parameter: &A::AggregationParam,
+    ) -> Result<(A::PrepareStep, A::PrepareMessage), Error> {
+        // Without an eager aggregation parameter, the reports of a collected
+        // interval get prepared again for each collect request
+        if self.aggregation_parameter.is_some() && self.batch_collected(nonce) {
+            return Err(Error::StaleReport(nonce));
+        }
+
+        let input_share = self.open_input_share(report_task_id, nonce, extensions, report_share)?;
 
         let step = self.aggregator.prepare_init(
             &self.verify_parameter,
-            &self.aggregation_parameter,
+            aggregation_parameter,
             &nonce.get_encoded(),
             &input_share,
         )?;
 
         match self.aggregator.prepare_step(step, None) {
-            PrepareTransition::Continue(step, message) => Ok((step, message)),
+            PrepareTransition::Continue(step, message) => {
+                self.metrics.report_prepared();
+                Ok((step, message))
+            }
             PrepareTransition::Finish(f) => {
                 Err(Error::UnexpectedStateTransition(format!("{:?}", f)))
             }
@@ -520,47 +957,116 @@ impl<A: vdaf::Aggregator> Aggregator<A> {
         }
     }
 
+    /// Decrypt and decode this aggregator's input share of a report.
+    fn open_input_share(
+        &self,
+        report_task_id: TaskId,
+        nonce: Nonce,
+        extensions: &[report::Extension],
+        report_share: &hpke::Ciphertext,
+    ) -> Result<A::InputShare, Error> {
+        if self.task_parameters.task_id != report_task_id {
+            return Err(Error::UnrecognizedTask(report_task_id));
+        }
+
+        if report_share.config_id != self.hpke_config.id {
+            return Err(Error::UnknownHpkeConfig(report_share.config_id));
+        }
+
+        let plaintext = self
+            .hpke_config
+            .recipient(
+                &self.task_parameters.task_id,
+                hpke::Label::InputShare,
+                Role::Client,
+                self.role,
+                &report_share.encapsulated_context,
+            )
+            .and_then(|hpke_recipient| {
+                hpke_recipient.open(report_share, &Report::associated_data(nonce, extensions))
+            });
+        if plaintext.is_err() {
+            self.metrics.hpke_decrypt_failure();
+        }
+        let plaintext = plaintext?;
+        info!(plaintext_len = ?plaintext.len(), "decoding input share");
+        let input_share =
+            A::InputShare::get_decoded_with_param(&self.verify_parameter, &plaintext)?;
+        info!("decoded input share");
+
+        Ok(input_share)
+    }
+
+    /// Accumulate the report's output share and write the accumulator to the
+    /// store in a single commit with `mutations`, which record what became of
+    /// the report. If the commit fails, the accumulator is left unchanged.
     pub(crate) fn accumulate_report(
-        &mut self,
+        &self,
         timestamp: Nonce,
+        aggregation_parameter: &A::AggregationParam,
         output_share: A::OutputShare,
+        mut mutations: Vec<Mutation>,
     ) -> Result<(), Error> {
         // Proof checked out. Now accumulate the output share into the accumulator
-        // for the batch interval corresponding to the report timestamp.
-        let interval = timestamp
-            .time
-            .batch_interval(self.task_parameters.min_batch_duration);
-
-        if let Some(accumulator) = self.accumulators.get_mut(&interval) {
-            accumulator.accumulated.accumulate(&output_share)?;
-            accumulator.contributions += 1;
-        } else {
+        // for the batch interval corresponding to the report timestamp and the
+        // aggregation parameter it was prepared under.
+        let key = (
+            timestamp
+                .time
+                .batch_interval(self.task_parameters.min_batch_duration),
+            aggregation_parameter.get_encoded(),
+        );
+
+        let mut batches = self.batches.lock().unwrap();
+        let mut accumulator = match batches.accumulators.get(&key) {
+            Some(accumulator) => {
+                let mut accumulator = accumulator.clone();
+                accumulator.accumulated.accumulate(&output_share)?;
+                accumulator.contributions += 1;
+                accumulator
+            }
             // This is the first input we have seen for this batch interval.
             // Initialize the accumulator.
-            self.accumulators.insert(
-                interval,
-                Accumulator {
-                    accumulated: self
-                        .aggregator
-                        .aggregate(&self.aggregation_parameter, [output_share])?,
-                    contributions: 1,
-                    consumed_privacy_budget: 0,
-                },
-            );
-        }
+            None => Accumulator {
+                accumulated: self
+                    .aggregator
+                    .aggregate(aggregation_parameter, [output_share])?,
+                contributions: 1,
+                checksum: [0; 32],
+                consumed_privacy_budget: 0,
+                reserved_privacy_budget: 0,
+            },
+        };
+        update_checksum(&mut accumulator.checksum, timestamp);
+
+        mutations.push(Mutation::PutAccumulator(accumulator_record(
+            &key,
+            &accumulator,
+        )?));
+        self.store.commit(mutations)?;
+        batches.accumulators.insert(key, accumulator);
 
         Ok(())
     }
 
+    /// Extract the aggregate share over the batch interval of the reports
+    /// prepared under the encoded aggregation parameter. The privacy budget the
+    /// share consumes is only reserved: it must be committed with
+    /// [`Self::commit_release`] once the share has been released, or given back
+    /// with [`Self::roll_back_release`] if it won't be. Failed requests don't
+    /// consume any budget.
     pub(crate) fn extract_aggregate_share(
-        &mut self,
+        &self,
         requested_task_id: TaskId,
         batch_interval: Interval,
-    ) -> Result<hpke::Ciphertext, Error> {
+        aggregation_parameter: &[u8],
+    ) -> Result<(hpke::Ciphertext, PendingRelease), Error> {
         if self.task_parameters.task_id != requested_task_id {
             return Err(Error::UnrecognizedTask(requested_task_id));
         }
 
+        self.validate_aggregation_parameter(aggregation_parameter)?;
+
         if !self.task_parameters.validate_batch_interval(batch_interval) {
             return Err(Error::InvalidBatchInterval(batch_interval));
         }
@@ -574,25 +1080,42 @@ impl<A: vdaf::Aggregator> Aggregator<A> {
 
         let mut aggregate_shares = vec![];
         let mut total_contributions = 0;
+        let mut checksum = [0; 32];
+        let mut batches = self.batches.lock().unwrap();
+
+        // Batch intervals may be collected again as they are, but results over
+        // partially overlapping intervals could be subtracted from each other
+        // to learn about the reports in the difference
+        if let Some(collected) = batches
+            .collected_batch_intervals
+            .keys()
+            .find(|collected| **collected != batch_interval && collected.overlaps(&batch_interval))
+        {
+            return Err(Error::BatchOverlap(*collected));
+        }
 
+        let mut accumulator_keys = vec![];
         for i in 0..num_intervals_in_request {
             let current_interval = first_interval
                 .add(self.task_parameters.min_batch_duration.multiple(i))
                 .batch_interval(self.task_parameters.min_batch_duration);
 
-            self.collected_batch_intervals.insert(current_interval);
+            // The privacy budget of an interval is shared between all
+            // aggregation parameters
+            if batches.consumed_privacy_budget(current_interval)
+                >= self.task_parameters.max_batch_lifetime
+            {
+                self.metrics.privacy_budget_rejection();
+                return Err(Error::PrivacyBudgetExceeded);
+            }
 
-            match self.accumulators.get_mut(&current_interval) {
+            let key = (current_interval, aggregation_parameter.to_vec());
+            match batches.accumulators.get(&key) {
                 Some(accumulator) => {
-                    if accumulator.consumed_privacy_budget
-                        == self.task_parameters.max_batch_lifetime
-                    {
-                        return Err(Error::PrivacyBudgetExceeded);
-                    }
                     aggregate_shares.push(accumulator.accumulated.clone());
-
-                    accumulator.consumed_privacy_budget += 1;
                     total_contributions += accumulator.contributions;
+                    merge_checksum(&mut checksum, &accumulator.checksum);
+                    accumulator_keys.push(key);
                 }
                 None => {
                     // Most likely there are no contributions for this batch interval yet
@@ -602,6 +1125,17 @@ impl<A: vdaf::Aggregator> Aggregator<A> {
             }
         }
 
+        // Distinguish a parameter no report was aggregated under from an
+        // interval without reports
+        if aggregate_shares.is_empty()
+            && batches
+                .accumulators
+                .keys()
+                .any(|(interval, _)| batch_interval.contains(interval.start))
+        {
+            return Err(Error::UnknownAggregationParameter);
+        }
+
         if total_contributions < self.task_parameters.min_batch_size {
             return Err(Error::InsufficientBatchSize(total_contributions));
         }
@@ -612,6 +1146,12 @@ impl<A: vdaf::Aggregator> Aggregator<A> {
             aggregate_shares[0].merge(&aggregate_share)?;
         }
 
+        // Noise is drawn afresh for every release. Each release consumes
+        // privacy budget, so the number of noisy releases is bounded.
+        if let Some(noise) = &self.noise {
+            aggregate_shares[0].add_noise(noise, &mut thread_rng())?;
+        }
+
         let hpke_sender = self.task_parameters.collector_config.sender(
             &self.task_parameters.task_id,
             hpke::Label::AggregateShare,
@@ -619,13 +1159,99 @@ impl<A: vdaf::Aggregator> Aggregator<A> {
             Role::Collector,
         )?;
 
-        Ok(hpke_sender.seal(
-            &aggregate_shares[0].get_encoded(),
-            &batch_interval.associated_data(),
-        )?)
+        let plaintext = AggregateSharePlaintext {
+            report_count: total_contributions,
+            checksum,
+            aggregate_share: aggregate_shares[0].get_encoded(),
+        };
+
+        let ciphertext = hpke_sender.seal(
+            &plaintext.get_encoded(),
+            &batch_interval.associated_data(aggregation_parameter),
+        )?;
+
+        // Nothing can fail any more, so reserve the privacy budget. The lock is
+        // held since checking it, so concurrent requests can't overspend it.
+        *batches
+            .collected_batch_intervals
+            .entry(batch_interval)
+            .or_default() += 1;
+        for key in &accumulator_keys {
+            if let Some(accumulator) = batches.accumulators.get_mut(key) {
+                accumulator.reserved_privacy_budget += 1;
+            }
+        }
+
+        Ok((
+            ciphertext,
+            PendingRelease {
+                batch_interval,
+                accumulator_keys,
+            },
+        ))
+    }
+
+    /// Write the privacy budget reserved for an aggregate share that is being
+    /// released to the store. If that fails, the share mustn't be released, so
+    /// the budget is given back.
+    pub(crate) fn commit_release(&self, release: PendingRelease) -> Result<(), Error> {
+        let result = self.store_release(&release);
+        if result.is_err() {
+            self.roll_back_release(release);
+        }
+
+        result
+    }
+
+    /// Write the collected batch interval and the budget the release consumes
+    /// to the store in a single commit, then turn the reserved budget into
+    /// consumed budget.
+    fn store_release(&self, release: &PendingRelease) -> Result<(), Error> {
+        let mut batches = self.batches.lock().unwrap();
+
+        let mut mutations = vec![Mutation::PutCollectedBatchInterval(release.batch_interval)];
+        for key in &release.accumulator_keys {
+            if let Some(accumulator) = batches.accumulators.get(key) {
+                let mut record = accumulator_record(key, accumulator)?;
+                record.consumed_privacy_budget += 1;
+                mutations.push(Mutation::PutAccumulator(record));
+            }
+        }
+        self.store.commit(mutations)?;
+
+        for key in &release.accumulator_keys {
+            if let Some(accumulator) = batches.accumulators.get_mut(key) {
+                accumulator.reserved_privacy_budget -= 1;
+                accumulator.consumed_privacy_budget += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Give back the privacy budget reserved for an aggregate share that won't
+    /// be released.
+    pub(crate) fn roll_back_release(&self, release: PendingRelease) {
+        let mut batches = self.batches.lock().unwrap();
+
+        if let Entry::Occupied(mut entry) = batches
+            .collected_batch_intervals
+            .entry(release.batch_interval)
+        {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+        for key in &release.accumulator_keys {
+            if let Some(accumulator) = batches.accumulators.get_mut(key) {
+                accumulator.reserved_privacy_budget -= 1;
+            }
+        }
+        info!(batch_interval = %release.batch_interval, "rolled back release of aggregate share");
     }
 
     pub(crate) fn dump_accumulators(&self) {
-        dump_accumulators(&self.accumulators)
+        dump_accumulators(&self.batches.lock().unwrap().accumulators)
     }
 }
@@ -0,0 +1,262 @@
+//! Aggregation jobs.
+//!
+//! The leader doesn't aggregate all the reports it has received in one go.
+//! Instead, it groups reports waiting to be aggregated into jobs of bounded
+//! size and runs the aggregate protocol with the helper for each job
+//! separately. Each job tracks its own round and helper state, so a job that
+//! fails can be retried without disturbing the others.
+//!
+//! The leader runs its aggregation jobs periodically, as configured by an
+//! [`AggregationDriverConfig`].
+
+use crate::{aggregate::TransitionMessage, config_path, Duration, Nonce};
+use prio::codec::{CodecError, Decode, Encode};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io::{Cursor, ErrorKind, Read},
+    path::PathBuf,
+};
+
+/// Default maximum number of reports in an aggregation job, used if a task's
+/// parameters don't set one
+pub const DEFAULT_MAX_AGGREGATION_JOB_SIZE: usize = 100;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("JSON parse error")]
+    JsonParse(#[from] serde_json::error::Error),
+    #[error("file error: {1}")]
+    File(#[source] std::io::Error, PathBuf),
+}
+
+/// Settings for the leader's background aggregation driver, which
+/// periodically puts pending reports into aggregation jobs and runs them.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct AggregationDriverConfig {
+    /// Time between aggregation runs
+    pub interval: Duration,
+    /// Longest time between aggregation runs while the helper keeps failing.
+    /// The time between runs doubles with each consecutive failure until it
+    /// reaches this.
+    pub max_backoff: Duration,
+}
+
+impl Default for AggregationDriverConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration(10),
+            max_backoff: Duration(300),
+        }
+    }
+}
+
+impl AggregationDriverConfig {
+    /// Load the driver config from `leader.json` in the default configuration
+    /// directory, falling back to the defaults if that file doesn't exist.
+    pub fn from_config_file() -> Result<Self, Error> {
+        let config_path = config_path().join("leader.json");
+
+        match File::open(&config_path) {
+            Ok(file) => Self::from_json_reader(file),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(Error::File(e, config_path)),
+        }
+    }
+
+    pub fn from_json_reader<R: Read>(reader: R) -> Result<Self, Error> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// How long to wait before the next aggregation run, given how many runs
+    /// in a row have failed.
+    pub(crate) fn delay(&self, consecutive_failures: u32) -> std::time::Duration {
+        let delay = self
+            .interval
+            .0
+            .saturating_mul(2u64.saturating_pow(consecutive_failures));
+        let delay = if consecutive_failures > 0 {
+            delay.min(self.max_backoff.0.max(self.interval.0))
+        } else {
+            delay
+        };
+
+        std::time::Duration::from_secs(delay)
+    }
+}
+
+/// Identifies an aggregation job within a leader.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct AggregationJobId(pub u64);
+
+impl Display for AggregationJobId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Encode for AggregationJobId {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.0.encode(bytes);
+    }
+}
+
+impl Decode for AggregationJobId {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        Ok(Self(u64::decode(bytes)?))
+    }
+}
+
+/// What a job needs to do next to make progress.
+#[derive(Clone, Debug)]
+pub(crate) enum AggregationJobStep {
+    /// The job's reports must be sent to the helper in an aggregate init
+    /// request.
+    Initialize,
+    /// The provided transitions must be sent to the helper in an aggregate
+    /// request.
+    Continue(Vec<TransitionMessage>),
+}
+
+/// State of one aggregation job.
+#[derive(Clone, Debug)]
+pub(crate) struct AggregationJob {
+    pub(crate) id: AggregationJobId,
+    /// Reports in the job that are still being prepared, in the order they are
+    /// sent to the helper
+    pub(crate) nonces: Vec<Nonce>,
+    /// Encoded aggregation parameter the job's reports are prepared under
+    pub(crate) aggregation_parameter: Vec<u8>,
+    /// Number of aggregate requests the helper has answered for the job
+    pub(crate) round: u16,
+    /// Opaque state handed to the leader by the helper in its last response
+    pub(crate) helper_state: Vec<u8>,
+    pub(crate) next_step: AggregationJobStep,
+    /// How many times the job has been restarted after failing, for logging
+    pub(crate) retries: u32,
+}
+
+impl AggregationJob {
+    pub(crate) fn new(
+        id: AggregationJobId,
+        nonces: Vec<Nonce>,
+        aggregation_parameter: &[u8],
+    ) -> Self {
+        Self {
+            id,
+            nonces,
+            aggregation_parameter: aggregation_parameter.to_vec(),
+            round: 0,
+            helper_state: vec![],
+            next_step: AggregationJobStep::Initialize,
+            retries: 0,
+        }
+    }
+
+    /// Whether every report in the job has been accumulated or has failed.
+    pub(crate) fn is_finished(&self) -> bool {
+        self.nonces.is_empty()
+    }
+
+    /// Send the job back to its start, so that its reports get prepared
+    /// afresh.
+    pub(crate) fn restart(&mut self) {
+        self.round = 0;
+        self.helper_state.clear();
+        self.next_step = AggregationJobStep::Initialize;
+        self.retries += 1;
+    }
+}
+
+/// Group `nonces` into jobs of at most `max_size` reports each, numbering them
+/// from `next_id`. The reports are to be prepared under the encoded
+/// `aggregation_parameter`.
+pub(crate) fn plan_aggregation_jobs(
+    nonces: Vec<Nonce>,
+    max_size: usize,
+    next_id: &mut u64,
+    aggregation_parameter: &[u8],
+) -> Vec<AggregationJob> {
+    nonces
+        .chunks(max_size.max(1))
+        .map(|chunk| {
+            let id = AggregationJobId(*next_id);
+            *next_id += 1;
+            AggregationJob::new(id, chunk.to_vec(), aggregation_parameter)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Time;
+    use std::io::Cursor;
+
+    #[test]
+    fn driver_config_defaults() {
+        let config =
+            AggregationDriverConfig::from_json_reader(Cursor::new(r#"{"interval": 2}"#.as_bytes()))
+                .unwrap();
+
+        assert_eq!(
+            config,
+            AggregationDriverConfig {
+                interval: Duration(2),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn driver_backs_off() {
+        let config = AggregationDriverConfig {
+            interval: Duration(10),
+            max_backoff: Duration(60),
+        };
+
+        let delays: Vec<_> = (0..5)
+            .map(|failures| config.delay(failures).as_secs())
+            .collect();
+        assert_eq!(delays, vec![10, 20, 40, 60, 60]);
+        assert_eq!(config.delay(u32::MAX).as_secs(), 60);
+    }
+
+    #[test]
+    fn jobs_are_bounded() {
+        let nonces: Vec<_> = (0..25)
+            .map(|time| Nonce {
+                time: Time(time),
+                rand: 0,
+            })
+            .collect();
+        let mut next_id = 3;
+
+        let jobs = plan_aggregation_jobs(nonces.clone(), 10, &mut next_id, &[1, 2]);
+
+        assert_eq!(
+            jobs.iter().map(|job| job.nonces.len()).collect::<Vec<_>>(),
+            vec![10, 10, 5]
+        );
+        assert_eq!(
+            jobs.iter().map(|job| job.id).collect::<Vec<_>>(),
+            vec![
+                AggregationJobId(3),
+                AggregationJobId(4),
+                AggregationJobId(5)
+            ]
+        );
+        assert_eq!(next_id, 6);
+        assert!(jobs.iter().all(|job| job.aggregation_parameter == [1, 2]));
+        assert_eq!(
+            jobs.into_iter()
+                .flat_map(|job| job.nonces)
+                .collect::<Vec<_>>(),
+            nonces
+        );
+
+        assert!(plan_aggregation_jobs(vec![], 10, &mut next_id, &[]).is_empty());
+    }
+}
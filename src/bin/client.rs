@@ -1,6 +1,12 @@
 use color_eyre::eyre::{Context, Result};
-use ppm_prototype::{client::PpmClient, parameters::Parameters, trace};
-use prio::vdaf::prio3::Prio3Aes128Sum;
+use ppm_prototype::{
+    client::PpmClient,
+    clock::RealClock,
+    parameters::{Parameters, VdafInstance},
+    trace,
+};
+use prio::vdaf::{poplar1::IdpfInput, Client};
+use std::sync::Arc;
 use tracing::info;
 
 #[tokio::main]
@@ -10,15 +16,42 @@ async fn main() -> Result<()> {
     trace::install_subscriber();
 
     let ppm_parameters = Parameters::from_config_file().wrap_err("loading task parameters")?;
-    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
 
-    let client = PpmClient::new(&ppm_parameters, &vdaf, ()).await?;
-
-    for count in 0..100 {
-        client.do_upload(1631907500 + count, &1).await?;
+    match ppm_parameters
+        .vdaf_instance()
+        .wrap_err("instantiating VDAF")?
+    {
+        VdafInstance::Prio3Count64(vdaf) => upload(&ppm_parameters, &vdaf, |_| Ok(1)).await?,
+        VdafInstance::Prio3Sum64(vdaf) => upload(&ppm_parameters, &vdaf, |_| Ok(1)).await?,
+        VdafInstance::Prio3Histogram64(vdaf) => upload(&ppm_parameters, &vdaf, |_| Ok(1)).await?,
+        // Spread the uploads over a few distinct strings
+        VdafInstance::Hits { vdaf, bits } => {
+            upload(&ppm_parameters, &vdaf, |count| {
+                Ok(IdpfInput::new(&(count % 3).to_le_bytes(), bits)?)
+            })
+            .await?
+        }
     }
 
     info!("completed uploads");
 
     Ok(())
 }
+
+/// Upload 100 reports, the measurement for each of which is obtained from its
+/// index
+async fn upload<C, M>(ppm_parameters: &Parameters, vdaf: &C, measurement: M) -> Result<()>
+where
+    C: Client<PublicParam = ()>,
+    M: Fn(u64) -> Result<C::Measurement>,
+{
+    let client = PpmClient::new(ppm_parameters, vdaf, (), Arc::new(RealClock)).await?;
+
+    for count in 0..100 {
+        client
+            .do_upload(1631907500 + count, &measurement(count)?)
+            .await?;
+    }
+
+    Ok(())
+}
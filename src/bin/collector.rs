@@ -1,8 +1,13 @@
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Context, Result};
 use ppm_prototype::{
-    collect::run_collect, hpke, parameters::Parameters, trace, Duration, Interval, Role, Time,
+    collect::{run_collect, run_heavy_hitters},
+    differential_privacy::NoisyAggregateShare,
+    hpke,
+    parameters::{Parameters, Poplar1Aes128, VdafInstance},
+    trace, Duration, Interval, Role, Time,
 };
-use prio::vdaf::prio3::Prio3Aes128Sum;
+use prio::vdaf::{poplar1::IdpfInput, Collector};
+use std::collections::BTreeSet;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -10,25 +15,138 @@ async fn main() -> Result<()> {
     color_eyre::install()?;
     trace::install_subscriber();
 
+    // `collector heavy-hitters <threshold>` walks the prefix tree of a Hits
+    // task instead of making a single collect request
+    let mut args = std::env::args().skip(1);
+    let heavy_hitters_threshold: Option<u64> = match args.next().as_deref() {
+        None => None,
+        Some("heavy-hitters") => Some(
+            args.next()
+                .ok_or_else(|| eyre!("heavy-hitters mode requires a threshold"))?
+                .parse()
+                .wrap_err("parsing threshold")?,
+        ),
+        Some(mode) => return Err(eyre!("unknown mode {}", mode)),
+    };
+
     let ppm_parameters = Parameters::from_config_file()?;
     let hpke_config = hpke::Config::from_config_file(Role::Collector)?;
-    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
-    let aggregate_share_length = vdaf.output_len();
 
-    let sum = run_collect(
-        &ppm_parameters,
-        &hpke_config,
+    match (
+        ppm_parameters
+            .vdaf_instance()
+            .wrap_err("instantiating VDAF")?,
+        heavy_hitters_threshold,
+    ) {
+        (VdafInstance::Hits { vdaf, bits }, Some(threshold)) => {
+            heavy_hitters(&ppm_parameters, &hpke_config, vdaf, bits, threshold).await
+        }
+        (_, Some(_)) => Err(eyre!("heavy-hitters mode requires the Hits VDAF")),
+        (VdafInstance::Prio3Count64(vdaf), None) => {
+            let aggregate_share_length = vdaf.output_len();
+            collect(
+                &ppm_parameters,
+                &hpke_config,
+                vdaf,
+                &(),
+                aggregate_share_length,
+            )
+            .await
+        }
+        (VdafInstance::Prio3Sum64(vdaf), None) => {
+            let aggregate_share_length = vdaf.output_len();
+            collect(
+                &ppm_parameters,
+                &hpke_config,
+                vdaf,
+                &(),
+                aggregate_share_length,
+            )
+            .await
+        }
+        (VdafInstance::Prio3Histogram64(vdaf), None) => {
+            let aggregate_share_length = vdaf.output_len();
+            collect(
+                &ppm_parameters,
+                &hpke_config,
+                vdaf,
+                &(),
+                aggregate_share_length,
+            )
+            .await
+        }
+        (VdafInstance::Hits { vdaf, .. }, None) => {
+            // Count the measurements by their first bit
+            let prefixes: BTreeSet<_> = [0u8, 1]
+                .iter()
+                .map(|bit| IdpfInput::new(&[*bit], 1))
+                .collect::<Result<_, _>>()?;
+            let aggregate_share_length = prefixes.len();
+            collect(
+                &ppm_parameters,
+                &hpke_config,
+                vdaf,
+                &prefixes,
+                aggregate_share_length,
+            )
+            .await
+        }
+    }
+}
+
+async fn heavy_hitters(
+    ppm_parameters: &Parameters,
+    hpke_config: &hpke::Config,
+    vdaf: Poplar1Aes128,
+    bits: usize,
+    threshold: u64,
+) -> Result<()> {
+    let heavy_hitters = run_heavy_hitters(
+        ppm_parameters,
+        hpke_config,
+        Interval {
+            start: Time(1631907500),
+            duration: Duration(100),
+        },
+        vdaf,
+        bits,
+        threshold,
+    )
+    .await?;
+
+    println!("Heavy hitters (first bit rightmost):");
+    for (string, count) in heavy_hitters {
+        println!("{:0width$b}: {}", string, count, width = bits);
+    }
+
+    Ok(())
+}
+
+async fn collect<C>(
+    ppm_parameters: &Parameters,
+    hpke_config: &hpke::Config,
+    vdaf: C,
+    aggregation_parameter: &C::AggregationParam,
+    aggregate_share_length: usize,
+) -> Result<()>
+where
+    C: Collector,
+    C::AggregateShare: NoisyAggregateShare,
+{
+    let result = run_collect(
+        ppm_parameters,
+        hpke_config,
         Interval {
             start: Time(1631907500),
             duration: Duration(100),
         },
         vdaf,
-        &(),
+        aggregation_parameter,
         aggregate_share_length,
     )
     .await?;
 
-    println!("Sum: {:?}", sum);
+    println!("Result: {:?}", result);
 
     Ok(())
 }
@@ -1,20 +1,47 @@
 use color_eyre::eyre::{Context, Result};
-use ppm_prototype::{helper::run_helper, hpke, parameters::Parameters, trace, Role};
-use prio::vdaf::prio3::Prio3Aes128Sum;
+use ppm_prototype::{
+    clock::RealClock,
+    helper::HelperBuilder,
+    helper_state::HelperStateKey,
+    hpke,
+    parameters::Parameters,
+    server::termination_signal,
+    storage::FileStore,
+    task::{ServeTasks, TaskOptions},
+    tls::TlsConfig,
+    trace, Role,
+};
+use std::sync::Arc;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
     trace::install_subscriber();
 
-    let ppm_parameters = Parameters::from_config_file().wrap_err("loading task parameters")?;
     let hpke_config =
         hpke::Config::from_config_file(Role::Helper).wrap_err("loading HPKE config")?;
-    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let helper_state_key =
+        HelperStateKey::from_config_file().wrap_err("loading helper state key")?;
+    let tls = TlsConfig::from_config_file(Role::Helper).wrap_err("loading TLS config")?;
 
-    let verify_param = ppm_parameters
-        .decode_vdaf_verification_parameter(Role::Helper, &vdaf)
-        .wrap_err("decoding VDAF verification parameter")?;
+    // On SIGTERM, finish the requests under way and flush state to disk
+    let mut builder = HelperBuilder::new(&hpke_config).shutdown_signal(termination_signal());
+    if let Some(tls) = tls {
+        builder = builder.tls(tls);
+    }
 
-    run_helper(&ppm_parameters, &vdaf, &verify_param, &(), &hpke_config).await
+    for parameters in Parameters::tasks_from_config_file().wrap_err("loading task parameters")? {
+        let store = FileStore::from_data_dir(Role::Helper, &parameters.task_id)
+            .wrap_err("opening helper storage")?;
+        let options = TaskOptions {
+            store: Arc::new(store),
+            clock: Arc::new(RealClock),
+            helper_state_key: helper_state_key.clone(),
+        };
+        builder = builder
+            .task_from_parameters(parameters, options)
+            .wrap_err("constructing task")?;
+    }
+
+    builder.run().await
 }
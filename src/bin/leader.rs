@@ -1,20 +1,50 @@
 use color_eyre::eyre::{Context, Result};
-use ppm_prototype::{hpke, leader::run_leader, parameters::Parameters, trace, Role};
-use prio::vdaf::prio3::Prio3Aes128Sum;
+use ppm_prototype::{
+    aggregation_job::AggregationDriverConfig,
+    clock::RealClock,
+    hpke,
+    leader::LeaderBuilder,
+    parameters::Parameters,
+    server::termination_signal,
+    storage::FileStore,
+    task::{ServeTasks, TaskOptions},
+    tls::TlsConfig,
+    trace, Role,
+};
+use std::sync::Arc;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
     trace::install_subscriber();
 
-    let ppm_parameters = Parameters::from_config_file().wrap_err("loading task parameters")?;
     let hpke_config =
         hpke::Config::from_config_file(Role::Leader).wrap_err("loading hpke config")?;
-    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let aggregation_driver =
+        AggregationDriverConfig::from_config_file().wrap_err("loading leader config")?;
+    let tls = TlsConfig::from_config_file(Role::Leader).wrap_err("loading TLS config")?;
 
-    let verify_param = ppm_parameters
-        .decode_vdaf_verification_parameter(Role::Leader, &vdaf)
-        .wrap_err("decoding VDAF verification parameter")?;
+    // On SIGTERM, stop taking uploads, let aggregation rounds and collect jobs
+    // under way finish, and flush state to disk
+    let mut builder = LeaderBuilder::new(&hpke_config)
+        .aggregation_driver(aggregation_driver)
+        .shutdown_signal(termination_signal());
+    if let Some(tls) = tls {
+        builder = builder.tls(tls);
+    }
 
-    run_leader(&ppm_parameters, &vdaf, &verify_param, &(), &hpke_config).await
+    for parameters in Parameters::tasks_from_config_file().wrap_err("loading task parameters")? {
+        let store = FileStore::from_data_dir(Role::Leader, &parameters.task_id)
+            .wrap_err("opening leader storage")?;
+        let options = TaskOptions {
+            store: Arc::new(store),
+            clock: Arc::new(RealClock),
+            helper_state_key: None,
+        };
+        builder = builder
+            .task_from_parameters(parameters, options)
+            .wrap_err("constructing task")?;
+    }
+
+    builder.run().await
 }
@@ -1,4 +1,5 @@
 use crate::{
+    clock::Clock,
     hpke::{self, Label},
     parameters::Parameters,
     report::Report,
@@ -8,6 +9,7 @@ use http::{header::CONTENT_TYPE, StatusCode};
 use http_api_problem::HttpApiProblem;
 use prio::{codec::Encode, vdaf::Client};
 use reqwest::Response;
+use std::sync::Arc;
 use tracing::info;
 
 #[derive(Debug, thiserror::Error)]
@@ -38,6 +40,12 @@ static CLIENT_USER_AGENT: &str = concat!(
     "client"
 );
 
+/// Builder for the HTTP client [`PpmClient::new`] uses, for callers that need
+/// to configure it further, e.g. to trust aggregators' self-signed certificates
+pub fn http_client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder().user_agent(CLIENT_USER_AGENT)
+}
+
 #[derive(Debug)]
 pub struct PpmClient<C: Client> {
     http_client: reqwest::Client,
@@ -46,6 +54,8 @@ pub struct PpmClient<C: Client> {
     helper_hpke_config: hpke::Config,
     vdaf: C,
     public_parameter: C::PublicParam,
+    /// Timestamps reports uploaded with [`Self::upload`]
+    clock: Arc<dyn Clock>,
 }
 
 impl<C: Client> PpmClient<C> {
@@ -54,10 +64,28 @@ impl<C: Client> PpmClient<C> {
         ppm_parameters: &Parameters,
         vdaf_client: &C,
         public_parameter: C::PublicParam,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self, Error> {
+        Self::with_http_client(
+            ppm_parameters,
+            vdaf_client,
+            public_parameter,
+            clock,
+            http_client_builder().build()?,
+        )
+        .await
+    }
+
+    /// Like [`Self::new`], but talking to the aggregators with `http_client`,
+    /// which should be built from [`http_client_builder`].
+    #[tracing::instrument(skip(http_client), err)]
+    pub async fn with_http_client(
+        ppm_parameters: &Parameters,
+        vdaf_client: &C,
+        public_parameter: C::PublicParam,
+        clock: Arc<dyn Clock>,
+        http_client: reqwest::Client,
     ) -> Result<Self, Error> {
-        let http_client = reqwest::Client::builder()
-            .user_agent(CLIENT_USER_AGENT)
-            .build()?;
         let leader_hpke_config = ppm_parameters
             .hpke_config(Role::Leader, &http_client)
             .await?;
@@ -74,9 +102,15 @@ impl<C: Client> PpmClient<C> {
             helper_hpke_config,
             vdaf: vdaf_client.clone(),
             public_parameter,
+            clock,
         })
     }
 
+    /// Upload a report of the measurement, timestamped with the current time.
+    pub async fn upload(&self, input: &C::Measurement) -> Result<(), Error> {
+        self.do_upload(self.clock.now().0, input).await
+    }
+
     pub async fn do_upload(&self, time: u64, input: &C::Measurement) -> Result<(), Error> {
         let tamper_func = |input_share: &C::InputShare| input_share.clone();
         let tamper_func_ref = &tamper_func as &dyn Fn(&C::InputShare) -> C::InputShare;
@@ -92,6 +126,30 @@ impl<C: Client> PpmClient<C> {
         tamper_leader_share: &dyn Fn(&C::InputShare) -> C::InputShare,
         tamper_helper_share: &dyn Fn(&C::InputShare) -> C::InputShare,
     ) -> Result<(), Error> {
+        let report =
+            self.build_report_tamper(time, input, tamper_leader_share, tamper_helper_share)?;
+
+        self.upload_report(&report).await
+    }
+
+    /// Construct a report of the measurement with the provided timestamp,
+    /// without uploading it.
+    #[allow(clippy::result_large_err)]
+    pub fn build_report(&self, time: u64, input: &C::Measurement) -> Result<Report, Error> {
+        let tamper_func = |input_share: &C::InputShare| input_share.clone();
+        let tamper_func_ref = &tamper_func as &dyn Fn(&C::InputShare) -> C::InputShare;
+
+        self.build_report_tamper(time, input, tamper_func_ref, tamper_func_ref)
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn build_report_tamper(
+        &self,
+        time: u64,
+        input: &C::Measurement,
+        tamper_leader_share: &dyn Fn(&C::InputShare) -> C::InputShare,
+        tamper_helper_share: &dyn Fn(&C::InputShare) -> C::InputShare,
+    ) -> Result<Report, Error> {
         let timestamp = Nonce {
             time: Time(time),
             rand: rand::random(),
@@ -142,6 +200,11 @@ impl<C: Client> PpmClient<C> {
             extensions: vec![],
         };
 
+        Ok(report)
+    }
+
+    /// Upload a previously constructed report to the leader.
+    pub async fn upload_report(&self, report: &Report) -> Result<(), Error> {
         let upload_response = self
             .http_client
             .post(self.parameters.upload_endpoint()?)
@@ -166,6 +229,9 @@ impl<C: Client> PpmClient<C> {
         Ok(())
     }
 
+    /// Ask the leader to aggregate the reports it has received right away.
+    /// Leaders normally aggregate on their own schedule, so this is mostly
+    /// useful in tests.
     pub async fn run_aggregate(&self) -> Result<(), Error> {
         let aggregate_response = self
             .http_client
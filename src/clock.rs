@@ -0,0 +1,79 @@
+//! Sources of the current time.
+//!
+//! Aggregators and clients never read the system clock directly but ask a
+//! [`Clock`], so that tests can control the passage of time with a
+//! [`MockClock`].
+
+use crate::{Duration, Time};
+use chrono::Utc;
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Tells the current time.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Time;
+}
+
+/// Clock backed by the system clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Time {
+        Time(Utc::now().timestamp() as u64)
+    }
+}
+
+/// Clock that only moves when told to. Clones share the same time, so a test
+/// can hand clones to aggregators and clients and then advance all of them at
+/// once.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    current: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    pub fn new(time: Time) -> Self {
+        Self {
+            current: Arc::new(AtomicU64::new(time.0)),
+        }
+    }
+
+    /// Move the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        self.current.fetch_add(duration.0, Ordering::SeqCst);
+    }
+
+    /// Set the clock to `time`, which may be in its past.
+    pub fn set(&self, time: Time) {
+        self.current.store(time.0, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Time {
+        Time(self.current.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock() {
+        let clock = MockClock::new(Time(1000));
+        let other = clock.clone();
+
+        clock.advance(Duration(50));
+        assert_eq!(other.now(), Time(1050));
+
+        other.set(Time(10));
+        assert_eq!(clock.now(), Time(10));
+    }
+}
@@ -1,20 +1,31 @@
 //! The collect portion of the PPM protocol
 
 use crate::{
+    aggregate::AggregateSharePlaintext,
+    differential_privacy::NoisyAggregateShare,
     error::{IntoHttpApiProblem, ProblemDocumentType},
     hpke,
     parameters::{Parameters, TaskId},
     Interval, Role,
 };
-use http::{header::CONTENT_TYPE, StatusCode};
+use http::{
+    header::{CONTENT_TYPE, LOCATION},
+    StatusCode,
+};
 use http_api_problem::HttpApiProblem;
 use prio::{
     codec::{decode_u16_items, encode_u16_items, CodecError, Decode, Encode, ParameterizedDecode},
-    vdaf::{Collector, Vdaf},
+    vdaf::{poplar1::IdpfInput, Aggregatable, Collector, Vdaf},
+};
+use reqwest::{redirect::Policy, Client, ClientBuilder, Response, Url};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display, Formatter},
+    io::Cursor,
+    num::ParseIntError,
+    str::FromStr,
 };
-use reqwest::{Client, Response};
-use std::io::Cursor;
-use tracing::info;
+use tracing::{debug, info};
 
 static COLLECTOR_USER_AGENT: &str = concat!(
     env!("CARGO_PKG_NAME"),
@@ -23,6 +34,14 @@ static COLLECTOR_USER_AGENT: &str = concat!(
     "/",
     "collector"
 );
+
+/// How long the collector waits before polling an unfinished collect job again.
+/// The wait doubles with each poll, up to [`MAX_COLLECT_POLL_INTERVAL`].
+const COLLECT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Longest wait between polls of a collect job
+const MAX_COLLECT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("JSON parse error")]
@@ -33,8 +52,14 @@ pub enum Error {
     ProblemDocument(HttpApiProblem),
     #[error("HTTP response status {0} body:\n{1:?}")]
     HttpFailure(StatusCode, Option<Response>),
-    #[error("lengths do not match: leader {0} helper {1}")]
-    LengthMismatch(u64, u64),
+    #[error(
+        "aggregators disagree on the reports in the batch: leader aggregated \
+         {leader_report_count} reports, helper {helper_report_count}"
+    )]
+    BatchMismatch {
+        leader_report_count: u64,
+        helper_report_count: u64,
+    },
     #[error("reqwest error")]
     Reqwest(#[from] reqwest::Error),
     #[error("parameters")]
@@ -100,6 +125,26 @@ impl<V: Vdaf> Decode for CollectRequest<V> {
     }
 }
 
+/// Identifies a collect job within a leader. The leader answers a collect
+/// request by creating a collect job and redirecting the collector to the job's
+/// URI, which the collector polls until the aggregate shares are ready.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct CollectJobId(pub u64);
+
+impl Display for CollectJobId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for CollectJobId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
 /// The response to a collect request
 /// struct {
 ///   HpkeCiphertext encrypted_agg_shares shares<1..2^16-1>;
@@ -125,6 +170,34 @@ impl Decode for CollectResponse {
     }
 }
 
+/// Construct an error from a response from the leader that indicates neither
+/// progress nor success, decoding the problem document it carries, if any.
+async fn unexpected_response(response: Response) -> Error {
+    let status = response.status();
+    match response.headers().get(CONTENT_TYPE) {
+        Some(content_type) if content_type == "application/problem+json" => {
+            match response.json().await {
+                Ok(problem_document) => Error::ProblemDocument(problem_document),
+                Err(_) => Error::HttpFailure(status, None),
+            }
+        }
+        _ => Error::HttpFailure(status, Some(response)),
+    }
+}
+
+/// Builder for the HTTP client [`run_collect`] uses, for callers that need to
+/// configure it further, e.g. to trust aggregators' self-signed certificates
+pub fn http_client_builder() -> ClientBuilder {
+    // The leader redirects us to the collect job, whose URI we need to poll it
+    Client::builder()
+        .user_agent(COLLECTOR_USER_AGENT)
+        .redirect(Policy::none())
+}
+
+/// Run the collect protocol over the batch interval: send a collect request to
+/// the leader, poll the resulting collect job until it finishes, then decrypt
+/// and unshard the aggregate shares. Polling a collect job doesn't consume
+/// privacy budget, only the collect request does.
 pub async fn run_collect<C: Collector>(
     ppm_parameters: &Parameters,
     hpke_config: &hpke::Config,
@@ -132,9 +205,37 @@ pub async fn run_collect<C: Collector>(
     vdaf: C,
     aggregation_parameter: &C::AggregationParam,
     aggregate_share_length: usize,
-) -> Result<C::AggregateResult, Error> {
-    let http_client = Client::builder().user_agent(COLLECTOR_USER_AGENT).build()?;
+) -> Result<C::AggregateResult, Error>
+where
+    C::AggregateShare: NoisyAggregateShare,
+{
+    run_collect_with_http_client(
+        &http_client_builder().build()?,
+        ppm_parameters,
+        hpke_config,
+        batch_interval,
+        vdaf,
+        aggregation_parameter,
+        aggregate_share_length,
+    )
+    .await
+}
 
+/// Like [`run_collect`], but talking to the leader with `http_client`, which
+/// must be built from [`http_client_builder`] so that it doesn't follow the
+/// leader's redirect.
+pub async fn run_collect_with_http_client<C: Collector>(
+    http_client: &Client,
+    ppm_parameters: &Parameters,
+    hpke_config: &hpke::Config,
+    batch_interval: Interval,
+    vdaf: C,
+    aggregation_parameter: &C::AggregationParam,
+    aggregate_share_length: usize,
+) -> Result<C::AggregateResult, Error>
+where
+    C::AggregateShare: NoisyAggregateShare,
+{
     let collect_request: CollectRequest<C> = CollectRequest {
         task_id: ppm_parameters.task_id,
         batch_interval,
@@ -149,19 +250,36 @@ pub async fn run_collect<C: Collector>(
 
     let status = collect_response.status();
     info!(http_status = ?status, "collect request HTTP status");
-    if !status.is_success() {
-        match collect_response.headers().get(CONTENT_TYPE) {
-            Some(content_type) if content_type == "application/problem+json" => {
-                match collect_response.json().await {
-                    Ok(problem_document) => return Err(Error::ProblemDocument(problem_document)),
-                    Err(_) => return Err(Error::HttpFailure(status, None)),
-                }
-            }
-            _ => return Err(Error::HttpFailure(status, Some(collect_response))),
-        }
+    if status != StatusCode::SEE_OTHER {
+        return Err(unexpected_response(collect_response).await);
     }
 
-    let collect_response = CollectResponse::get_decoded(&collect_response.bytes().await?)?;
+    let collect_job_uri = collect_response
+        .headers()
+        .get(LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .and_then(|location| Url::parse(location).ok())
+        .ok_or(Error::Unspecified(
+            "collect response lacks a valid Location header",
+        ))?;
+    info!(%collect_job_uri, "leader created collect job");
+
+    // Poll the collect job until the leader has both aggregate shares
+    let mut poll_interval = COLLECT_POLL_INTERVAL;
+    let collect_response = loop {
+        let poll_response = http_client.get(collect_job_uri.clone()).send().await?;
+        match poll_response.status() {
+            StatusCode::OK => {
+                break CollectResponse::get_decoded(&poll_response.bytes().await?)?;
+            }
+            StatusCode::ACCEPTED => {
+                debug!(?poll_interval, "collect job not yet finished");
+                tokio::time::sleep(poll_interval).await;
+                poll_interval = (poll_interval * 2).min(MAX_COLLECT_POLL_INTERVAL);
+            }
+            _ => return Err(unexpected_response(poll_response).await),
+        }
+    };
 
     let leader_ciphertext = &collect_response.encrypted_agg_shares[Role::Leader.index()];
 
@@ -173,10 +291,10 @@ pub async fn run_collect<C: Collector>(
         &leader_ciphertext.encapsulated_context,
     )?;
 
-    let leader_share = C::AggregateShare::get_decoded_with_param(
-        &aggregate_share_length,
-        &leader_recipient.open(leader_ciphertext, &batch_interval.associated_data())?,
-    )?;
+    let leader_plaintext = AggregateSharePlaintext::get_decoded(&leader_recipient.open(
+        leader_ciphertext,
+        &batch_interval.associated_data(&aggregation_parameter.get_encoded()),
+    )?)?;
 
     let helper_ciphertext = &collect_response.encrypted_agg_shares[Role::Helper.index()];
 
@@ -188,18 +306,116 @@ pub async fn run_collect<C: Collector>(
         &helper_ciphertext.encapsulated_context,
     )?;
 
+    let helper_plaintext = AggregateSharePlaintext::get_decoded(&helper_recipient.open(
+        helper_ciphertext,
+        &batch_interval.associated_data(&aggregation_parameter.get_encoded()),
+    )?)?;
+
+    // Combining shares over different sets of reports would yield garbage
+    if leader_plaintext.report_count != helper_plaintext.report_count
+        || leader_plaintext.checksum != helper_plaintext.checksum
+    {
+        return Err(Error::BatchMismatch {
+            leader_report_count: leader_plaintext.report_count,
+            helper_report_count: helper_plaintext.report_count,
+        });
+    }
+
+    let mut aggregate = C::AggregateShare::get_decoded_with_param(
+        &aggregate_share_length,
+        &leader_plaintext.aggregate_share,
+    )?;
     let helper_share = C::AggregateShare::get_decoded_with_param(
         &aggregate_share_length,
-        &helper_recipient.open(helper_ciphertext, &batch_interval.associated_data())?,
+        &helper_plaintext.aggregate_share,
     )?;
 
-    // TODO: include contribution count in aggregate share somehow
-    // if decrypted_leader_share.contributions != decrypted_helper_share.contributions {
-    //     return Err(Error::LengthMismatch(
-    //         decrypted_leader_share.contributions,
-    //         decrypted_helper_share.contributions,
-    //     ));
-    // }
+    aggregate.merge(&helper_share)?;
+
+    // Noise may push small aggregates below zero, which VDAFs can't represent.
+    // Clamping them is post-processing, so doesn't weaken the guarantee.
+    if ppm_parameters.differential_privacy.is_some() {
+        aggregate.clamp_negative();
+    }
+
+    Ok(vdaf.unshard(aggregation_parameter, [aggregate])?)
+}
+
+/// Find the `bits`-bit strings that occur in at least `threshold` reports in
+/// the batch interval, by walking the prefix tree one level per collect
+/// request. The first request counts both 1-bit prefixes. Each following
+/// request extends the prefixes counted at least `threshold` times by one bit,
+/// until the prefixes are full strings. Each collect request consumes privacy
+/// budget, so the task's `max_batch_lifetime` must be at least `bits`.
+///
+/// Strings are returned as integers whose bit `i` is bit `i` of the string,
+/// matching `IdpfInput::new(&string.to_le_bytes(), bits)`, mapped to their
+/// counts.
+pub async fn run_heavy_hitters<C>(
+    ppm_parameters: &Parameters,
+    hpke_config: &hpke::Config,
+    batch_interval: Interval,
+    vdaf: C,
+    bits: usize,
+    threshold: u64,
+) -> Result<BTreeMap<u64, u64>, Error>
+where
+    C: Collector<
+            AggregationParam = BTreeSet<IdpfInput>,
+            AggregateResult = BTreeMap<IdpfInput, u64>,
+        > + Clone,
+    C::AggregateShare: NoisyAggregateShare,
+{
+    if bits == 0 || bits > 64 {
+        return Err(Error::Unspecified(
+            "heavy hitters must be between 1 and 64 bits long",
+        ));
+    }
+
+    // Start from the empty prefix, which every report matches
+    let mut heavy_prefixes = BTreeMap::from([(0, 0)]);
+
+    for level in 1..=bits {
+        let candidates: Vec<u64> = heavy_prefixes
+            .keys()
+            .flat_map(|prefix| [*prefix, *prefix | 1 << (level - 1)])
+            .collect();
+        let aggregation_parameter = candidates
+            .iter()
+            .map(|prefix| IdpfInput::new(&prefix.to_le_bytes(), level))
+            .collect::<Result<BTreeSet<_>, _>>()?;
+
+        let counts = run_collect(
+            ppm_parameters,
+            hpke_config,
+            batch_interval,
+            vdaf.clone(),
+            &aggregation_parameter,
+            aggregation_parameter.len(),
+        )
+        .await?;
+
+        heavy_prefixes = BTreeMap::new();
+        for prefix in candidates {
+            let count = counts
+                .get(&IdpfInput::new(&prefix.to_le_bytes(), level)?)
+                .copied()
+                .unwrap_or(0);
+            if count >= threshold {
+                heavy_prefixes.insert(prefix, count);
+            }
+        }
+        info!(
+            level,
+            candidate_count = aggregation_parameter.len(),
+            heavy_prefix_count = heavy_prefixes.len(),
+            "counted prefixes"
+        );
+
+        if heavy_prefixes.is_empty() {
+            break;
+        }
+    }
 
-    Ok(vdaf.unshard(aggregation_parameter, [leader_share, helper_share])?)
+    Ok(heavy_prefixes)
 }
@@ -0,0 +1,356 @@
+//! Differential privacy.
+//!
+//! A task may require each aggregator to add noise to its aggregate share
+//! before releasing it to the collector, so that the aggregate the collector
+//! computes is differentially private. Noise is drawn independently for each
+//! element of the share from a discrete distribution over the integers, which
+//! is then mapped into the VDAF's field. Each aggregator adds enough noise on
+//! its own to provide the configured guarantee, so the guarantee holds even if
+//! the other aggregator adds none.
+//!
+//! The noise is calibrated to the sensitivity of the task's VDAF, i.e. by how
+//! much adding or removing a single report can change the aggregate. Only the
+//! aggregate is protected: the number of reports in a batch is released
+//! exactly.
+//!
+//! The samplers use floating point arithmetic, which makes them slightly
+//! deviate from the ideal distributions.
+
+use prio::{field::FieldElement, vdaf::AggregateShare};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// Largest permitted scale of the noise distribution. Samples from wider
+/// distributions could overflow the integers noise is drawn from.
+pub const MAX_NOISE_SCALE: f64 = (1u64 << 40) as f64;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("epsilon {0} out of range")]
+    InvalidEpsilon(f64),
+    #[error("delta {0} out of range")]
+    InvalidDelta(f64),
+    #[error("noise scale {0} exceeds maximum of {MAX_NOISE_SCALE}")]
+    ExcessiveNoise(f64),
+    #[error("noise {0} does not fit in field")]
+    NoiseOutOfRange(i64),
+}
+
+/// Differential privacy guarantee configured for a task
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum DifferentialPrivacy {
+    /// Pure epsilon-differential privacy, by adding discrete Laplace noise
+    DiscreteLaplace { epsilon: f64 },
+    /// (epsilon, delta)-differential privacy, by adding discrete Gaussian
+    /// noise. Epsilon must be below 1.
+    DiscreteGaussian { epsilon: f64, delta: f64 },
+}
+
+/// Distribution of the noise added to each element of an aggregate share
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Noise {
+    /// Discrete Laplace distribution, where the probability of `x` is
+    /// proportional to `exp(-|x| / scale)`
+    DiscreteLaplace { scale: f64 },
+    /// Discrete Gaussian distribution, where the probability of `x` is
+    /// proportional to `exp(-x^2 / (2 * sigma^2))`
+    DiscreteGaussian { sigma: f64 },
+}
+
+impl Noise {
+    /// Calibrate noise that provides the configured guarantee for a VDAF whose
+    /// aggregate changes by at most `sensitivity` when a report is added or
+    /// removed.
+    pub fn new(config: &DifferentialPrivacy, sensitivity: u64) -> Result<Self, Error> {
+        let noise = match *config {
+            DifferentialPrivacy::DiscreteLaplace { epsilon } => {
+                if !(epsilon.is_finite() && epsilon > 0.0) {
+                    return Err(Error::InvalidEpsilon(epsilon));
+                }
+                Self::DiscreteLaplace {
+                    scale: sensitivity as f64 / epsilon,
+                }
+            }
+            DifferentialPrivacy::DiscreteGaussian { epsilon, delta } => {
+                // The classic Gaussian mechanism's calibration only holds for
+                // epsilon below 1
+                if !(epsilon > 0.0 && epsilon < 1.0) {
+                    return Err(Error::InvalidEpsilon(epsilon));
+                }
+                if !(delta > 0.0 && delta < 1.0) {
+                    return Err(Error::InvalidDelta(delta));
+                }
+                Self::DiscreteGaussian {
+                    sigma: sensitivity as f64 * (2.0 * (1.25 / delta).ln()).sqrt() / epsilon,
+                }
+            }
+        };
+
+        let scale = match noise {
+            Self::DiscreteLaplace { scale } => scale,
+            Self::DiscreteGaussian { sigma } => sigma,
+        };
+        if scale > MAX_NOISE_SCALE {
+            return Err(Error::ExcessiveNoise(scale));
+        }
+
+        Ok(noise)
+    }
+
+    /// Draw a sample from the distribution.
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> i64 {
+        match *self {
+            Self::DiscreteLaplace { scale } => sample_discrete_laplace(rng, scale),
+            Self::DiscreteGaussian { sigma } => sample_discrete_gaussian(rng, sigma),
+        }
+    }
+}
+
+/// Sample from the geometric distribution where the probability of `k` is
+/// proportional to `exp(-k / scale)`, by inversion.
+fn sample_geometric<R: Rng + ?Sized>(rng: &mut R, scale: f64) -> i64 {
+    // Uniform over (0, 1], so that the logarithm is finite
+    let uniform = 1.0 - rng.gen::<f64>();
+    (-scale * uniform.ln()).floor() as i64
+}
+
+/// Sample from the discrete Laplace distribution as the difference of two
+/// geometric samples.
+fn sample_discrete_laplace<R: Rng + ?Sized>(rng: &mut R, scale: f64) -> i64 {
+    if scale == 0.0 {
+        return 0;
+    }
+    sample_geometric(rng, scale) - sample_geometric(rng, scale)
+}
+
+/// Sample from the discrete Gaussian distribution by rejection sampling from
+/// a discrete Laplace distribution, following Canonne, Kamath and Steinke,
+/// "The Discrete Gaussian for Differential Privacy" (2020), algorithm 3.
+fn sample_discrete_gaussian<R: Rng + ?Sized>(rng: &mut R, sigma: f64) -> i64 {
+    if sigma == 0.0 {
+        return 0;
+    }
+    let scale = sigma.floor() + 1.0;
+    let variance = sigma * sigma;
+    loop {
+        let candidate = sample_discrete_laplace(rng, scale);
+        let distance = (candidate.abs() as f64) - variance / scale;
+        if rng.gen::<f64>() < (-distance * distance / (2.0 * variance)).exp() {
+            return candidate;
+        }
+    }
+}
+
+/// Map a signed integer into the field.
+fn field_element<F: FieldElement>(value: i64) -> Result<F, Error> {
+    let magnitude = usize::try_from(value.unsigned_abs())
+        .ok()
+        .and_then(|magnitude| F::Integer::try_from(magnitude).ok())
+        .ok_or(Error::NoiseOutOfRange(value))?;
+    let magnitude = F::from(magnitude);
+
+    Ok(if value < 0 { -magnitude } else { magnitude })
+}
+
+/// Aggregate shares that noise can be added to
+pub trait NoisyAggregateShare {
+    /// Add an independent sample of the noise to each element of the share.
+    fn add_noise<R: Rng + ?Sized>(&mut self, noise: &Noise, rng: &mut R) -> Result<(), Error>;
+
+    /// Replace elements that noise has pushed below zero, i.e. that are closer
+    /// to the field modulus than to zero, with zero. Collectors apply this to
+    /// the aggregate, since VDAFs can't represent negative results.
+    fn clamp_negative(&mut self);
+}
+
+impl<F: FieldElement> NoisyAggregateShare for AggregateShare<F> {
+    fn add_noise<R: Rng + ?Sized>(&mut self, noise: &Noise, rng: &mut R) -> Result<(), Error> {
+        let noisy = self
+            .as_ref()
+            .iter()
+            .map(|element| Ok(*element + field_element::<F>(noise.sample(rng))?))
+            .collect::<Result<Vec<_>, Error>>()?;
+        *self = noisy.into();
+
+        Ok(())
+    }
+
+    fn clamp_negative(&mut self) {
+        let clamped: Vec<_> = self
+            .as_ref()
+            .iter()
+            .map(|element| {
+                if F::Integer::from(-*element) < F::Integer::from(*element) {
+                    F::zero()
+                } else {
+                    *element
+                }
+            })
+            .collect();
+        *self = clamped.into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use prio::field::{Field128, Field64};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    const SAMPLES: usize = 100_000;
+
+    /// Mean and variance of samples from the noise distribution
+    fn moments(noise: &Noise) -> (f64, f64, Vec<i64>) {
+        let mut rng = StdRng::seed_from_u64(0);
+        let samples: Vec<_> = (0..SAMPLES).map(|_| noise.sample(&mut rng)).collect();
+        let mean = samples.iter().sum::<i64>() as f64 / SAMPLES as f64;
+        let variance = samples
+            .iter()
+            .map(|sample| (*sample as f64 - mean).powi(2))
+            .sum::<f64>()
+            / SAMPLES as f64;
+
+        (mean, variance, samples)
+    }
+
+    #[test]
+    fn calibration() {
+        assert_eq!(
+            Noise::new(&DifferentialPrivacy::DiscreteLaplace { epsilon: 0.5 }, 3).unwrap(),
+            Noise::DiscreteLaplace { scale: 6.0 }
+        );
+        assert_matches!(
+            Noise::new(
+                &DifferentialPrivacy::DiscreteGaussian {
+                    epsilon: 0.5,
+                    delta: 1e-6
+                },
+                1
+            ),
+            Ok(Noise::DiscreteGaussian { sigma }) => {
+                assert!((sigma - 10.59).abs() < 0.01, "sigma {}", sigma);
+            }
+        );
+
+        for epsilon in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert_matches!(
+                Noise::new(&DifferentialPrivacy::DiscreteLaplace { epsilon }, 1),
+                Err(Error::InvalidEpsilon(_))
+            );
+        }
+        assert_matches!(
+            Noise::new(
+                &DifferentialPrivacy::DiscreteGaussian {
+                    epsilon: 1.0,
+                    delta: 1e-6
+                },
+                1
+            ),
+            Err(Error::InvalidEpsilon(_))
+        );
+        assert_matches!(
+            Noise::new(
+                &DifferentialPrivacy::DiscreteGaussian {
+                    epsilon: 0.5,
+                    delta: 0.0
+                },
+                1
+            ),
+            Err(Error::InvalidDelta(_))
+        );
+        assert_matches!(
+            Noise::new(
+                &DifferentialPrivacy::DiscreteLaplace { epsilon: 1.0 },
+                u64::MAX
+            ),
+            Err(Error::ExcessiveNoise(_))
+        );
+    }
+
+    #[test]
+    fn discrete_laplace_distribution() {
+        let scale = 10.0;
+        let (mean, variance, samples) = moments(&Noise::DiscreteLaplace { scale });
+
+        // The variance of the discrete Laplace distribution is
+        // 2q / (1 - q)^2 with q = exp(-1 / scale)
+        let q = (-1.0 / scale).exp();
+        let expected_variance = 2.0 * q / (1.0 - q).powi(2);
+        assert!(mean.abs() < 0.2, "mean {}", mean);
+        assert!(
+            (variance / expected_variance - 1.0).abs() < 0.05,
+            "variance {} expected {}",
+            variance,
+            expected_variance
+        );
+        // Exceeding 20 scales has probability around exp(-20)
+        assert!(samples.iter().all(|sample| sample.abs() < 200));
+    }
+
+    #[test]
+    fn discrete_gaussian_distribution() {
+        let sigma = 10.0;
+        let (mean, variance, samples) = moments(&Noise::DiscreteGaussian { sigma });
+
+        // The variance of the discrete Gaussian is just below sigma^2
+        assert!(mean.abs() < 0.2, "mean {}", mean);
+        assert!(
+            (variance / (sigma * sigma) - 1.0).abs() < 0.05,
+            "variance {}",
+            variance
+        );
+        assert!(samples.iter().all(|sample| sample.abs() < 7 * sigma as i64));
+    }
+
+    #[test]
+    fn no_noise_at_zero_scale() {
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(Noise::DiscreteLaplace { scale: 0.0 }.sample(&mut rng), 0);
+        assert_eq!(Noise::DiscreteGaussian { sigma: 0.0 }.sample(&mut rng), 0);
+    }
+
+    #[test]
+    fn add_noise_to_share() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let noise = Noise::DiscreteLaplace { scale: 5.0 };
+
+        // Negative noise wraps around the field modulus
+        let mut share = AggregateShare::from(vec![Field64::from(1000); 1000]);
+        share.add_noise(&noise, &mut rng).unwrap();
+        let values: Vec<u64> = share.as_ref().iter().map(|x| u64::from(*x)).collect();
+        assert!(values.iter().all(|value| (900..1100).contains(value)));
+        assert!(values.iter().any(|value| *value != 1000));
+
+        let mut share = AggregateShare::from(vec![Field128::from(0); 1000]);
+        share.add_noise(&noise, &mut rng).unwrap();
+        assert!(share
+            .as_ref()
+            .iter()
+            .any(|x| u128::from(*x) > u128::from(u64::MAX)));
+
+        assert_eq!(field_element::<Field64>(-1).unwrap(), -Field64::from(1));
+        assert_eq!(field_element::<Field128>(7).unwrap(), Field128::from(7));
+    }
+
+    #[test]
+    fn clamp_negative() {
+        let mut share = AggregateShare::from(vec![
+            Field128::from(0),
+            Field128::from(5),
+            -Field128::from(5),
+            -Field128::from(1),
+        ]);
+        share.clamp_negative();
+        assert_eq!(
+            share.as_ref(),
+            &[
+                Field128::from(0),
+                Field128::from(5),
+                Field128::from(0),
+                Field128::from(0)
+            ]
+        );
+    }
+}
@@ -15,6 +15,13 @@ pub(crate) enum ProblemDocumentType {
     HelperError,
     UnknownError,
     StaleReport,
+    InvalidHmac,
+    ReportTooEarly,
+    ReportTooLate,
+    ReportReplayed,
+    InvalidAggregationParameter,
+    UnknownAggregationParameter,
+    BatchOverlap,
 }
 
 impl From<ProblemDocumentType> for String {
@@ -29,6 +36,13 @@ impl From<ProblemDocumentType> for String {
             ProblemDocumentType::HelperError => "helperError",
             ProblemDocumentType::UnknownError => "unknownError",
             ProblemDocumentType::StaleReport => "staleReport",
+            ProblemDocumentType::InvalidHmac => "invalidHmac",
+            ProblemDocumentType::ReportTooEarly => "reportTooEarly",
+            ProblemDocumentType::ReportTooLate => "reportTooLate",
+            ProblemDocumentType::ReportReplayed => "reportReplayed",
+            ProblemDocumentType::InvalidAggregationParameter => "invalidAggregationParameter",
+            ProblemDocumentType::UnknownAggregationParameter => "unknownAggregationParameter",
+            ProblemDocumentType::BatchOverlap => "batchOverlap",
         };
 
         format!("urn:ietf:params:ppm:error:{}", problem_type)
@@ -0,0 +1,187 @@
+//! Liveness and readiness.
+//!
+//! Leader and helper answer `GET /healthz` as long as they are serving
+//! requests at all. `GET /readyz` checks whether they can do useful work: that
+//! their HPKE keys are usable, that the store of every task can be written to
+//! and, for the leader, that the helper of every task serves its HPKE config.
+//! Either way, the response is a JSON document listing the checks and why any
+//! of them failed, with status 200 if all checks passed and 503 otherwise.
+
+use crate::{hpke, parameters::TaskId};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::{error::Error as StdError, time::Duration};
+use warp::{filters::BoxedFilter, reply, Filter, Reply};
+
+/// How long the leader waits for the helper's HPKE config when checking
+/// readiness.
+pub(crate) const HELPER_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("no tasks configured")]
+    NoTasks,
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+    #[error(transparent)]
+    Hpke(#[from] hpke::Error),
+    #[error(transparent)]
+    Storage(#[from] crate::storage::Error),
+    #[error(transparent)]
+    Parameters(#[from] crate::parameters::Error),
+}
+
+/// Outcome of a single readiness check
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Check {
+    /// What was checked
+    pub name: String,
+    /// The task the check concerns, if it concerns a single task
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task_id: Option<String>,
+    /// Why the check failed, if it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    pub(crate) fn new(name: &str, task_id: Option<TaskId>, outcome: Result<(), Error>) -> Self {
+        Self {
+            name: name.to_string(),
+            task_id: task_id.map(|task_id| task_id.to_string()),
+            error: outcome.err().map(|error| describe(&error)),
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Describe an error along with the errors that caused it
+fn describe(error: &dyn StdError) -> String {
+    let mut description = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        description.push_str(": ");
+        description.push_str(&cause.to_string());
+        source = cause.source();
+    }
+
+    description
+}
+
+/// Body of a response to `GET /readyz`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Readiness {
+    /// Whether all checks passed
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Readiness {
+    /// Check the configuration shared by all tasks served by an aggregator,
+    /// and combine the outcome with the checks of the individual tasks.
+    pub(crate) fn new(
+        hpke_config: &hpke::Config,
+        task_count: usize,
+        task_checks: Vec<Check>,
+    ) -> Self {
+        let mut checks = vec![
+            Check::new(
+                "config",
+                None,
+                if task_count == 0 {
+                    Err(Error::NoTasks)
+                } else {
+                    Ok(())
+                },
+            ),
+            Check::new(
+                "hpke_config",
+                None,
+                hpke_config.check_keys().map_err(Error::from),
+            ),
+        ];
+        checks.extend(task_checks);
+
+        Self {
+            ready: checks.iter().all(Check::passed),
+            checks,
+        }
+    }
+}
+
+impl Reply for Readiness {
+    fn into_response(self) -> reply::Response {
+        let status = if self.ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        reply::with_status(reply::json(&self), status).into_response()
+    }
+}
+
+/// Construct a warp filter answering `GET /healthz`
+pub(crate) fn healthz_endpoint() -> BoxedFilter<(impl Reply,)> {
+    warp::get()
+        .and(warp::path("healthz"))
+        .map(|| reply::json(&serde_json::json!({ "status": "ok" })))
+        .with(warp::trace::named("healthz"))
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn readiness() {
+        let hpke_config = hpke::Config::new_recipient(
+            hpke::KeyEncapsulationMechanism::X25519HkdfSha256,
+            hpke::KeyDerivationFunction::HkdfSha256,
+            hpke::AuthenticatedEncryptionWithAssociatedData::ChaCha20Poly1305,
+        );
+        let task_id = TaskId::random();
+
+        let readiness = Readiness::new(
+            &hpke_config,
+            1,
+            vec![Check::new("storage", Some(task_id), Ok(()))],
+        );
+        assert!(readiness.ready);
+        assert_eq!(readiness.checks.len(), 3);
+
+        let readiness = Readiness::new(
+            &hpke_config,
+            1,
+            vec![Check::new(
+                "storage",
+                Some(task_id),
+                Err(Error::Storage(crate::storage::Error::File(
+                    io::Error::new(io::ErrorKind::PermissionDenied, "permission denied"),
+                    "store.json".into(),
+                ))),
+            )],
+        );
+        assert!(!readiness.ready);
+        assert_eq!(
+            readiness.checks[2],
+            Check {
+                name: "storage".to_string(),
+                task_id: Some(task_id.to_string()),
+                error: Some("file error: store.json: permission denied".to_string()),
+            }
+        );
+
+        let readiness = Readiness::new(&hpke_config, 0, vec![]);
+        assert!(!readiness.ready);
+        assert_eq!(
+            readiness.checks[0].error,
+            Some("no tasks configured".to_string())
+        );
+    }
+}
@@ -3,28 +3,40 @@
 use crate::{
     aggregate::{
         Aggregate, AggregateInitReq, AggregateMessage, AggregateReq, AggregateResp, Aggregator,
-        Transition, TransitionError, TransitionMessage,
+        ReportShare, Transition, TransitionError, TransitionMessage,
     },
+    aggregation_job::AggregationJobId,
+    differential_privacy::NoisyAggregateShare,
     error::{handle_rejection, IntoHttpApiProblem, ProblemDocumentType},
+    health::{self, Check, Readiness},
+    helper_state::{HelperState, HelperStateKey, PendingReport},
     hpke,
+    metrics::Metrics,
     parameters::{Parameters, TaskId},
+    retention::{should_purge, PurgeCounters, PurgedReports},
+    server::{self, ServerHandle, ShutdownSignal},
+    storage::{Mutation, ReportRecord, ReportRecordState, Store},
+    task::{ServeTasks, Task, TaskRegistry},
+    tls::{self, TlsConfig},
     with_shared_value, Nonce, Role,
 };
 use bytes::Bytes;
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result};
+use futures::{future::BoxFuture, FutureExt};
 use http::{Response, StatusCode};
 use prio::{
     codec::{Decode, Encode, ParameterizedDecode},
     vdaf::{self, PrepareTransition, VdafError},
 };
+use serde::{de::DeserializeOwned, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Debug,
+    future::Future,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
-use tokio::sync::Mutex;
-use tracing::{error, info, warn};
+use tracing::{info, warn};
 use warp::{Filter, Rejection};
 
 #[derive(Debug, thiserror::Error)]
@@ -49,6 +61,10 @@ pub enum Error {
     AggregateProtocol(String),
     #[error("Codec error")]
     Codec(#[from] prio::codec::CodecError),
+    #[error("storage error {0}")]
+    Storage(#[from] crate::storage::Error),
+    #[error("helper state error {0}")]
+    HelperState(#[from] crate::helper_state::Error),
 }
 
 impl IntoHttpApiProblem for Error {
@@ -61,63 +77,155 @@ impl IntoHttpApiProblem for Error {
             Self::Encryption(_) => Some(ProblemDocumentType::UnrecognizedMessage),
             Self::UnrecognizedTask(_) => Some(ProblemDocumentType::UnrecognizedTask),
             Self::UnknownHpkeConfig(_) => Some(ProblemDocumentType::OutdatedConfig),
+            Self::HelperState(_) => Some(ProblemDocumentType::UnrecognizedMessage),
             Self::Aggregation(e) => e.problem_document_type(),
             _ => None,
         }
     }
 }
 
-/// In-memory representation of a report stored by the leader
+/// In-memory representation of a report stored by the helper
 #[derive(Clone, Debug)]
 pub enum StoredReport<A: vdaf::Aggregator> {
-    Waiting { step: A::PrepareStep },
+    Waiting {
+        step: A::PrepareStep,
+        /// Encoded aggregation parameter the report is prepared under
+        aggregation_parameter: Vec<u8>,
+    },
     Accumulated,
 }
 
-/// Implements endpoints for helper.
+/// Implements endpoints for helper. All methods take `&self` so that
+/// aggregate requests for different jobs may be handled concurrently.
 #[derive(Debug)]
 pub struct Helper<A: vdaf::Aggregator + Debug> {
     parameters: Parameters,
     aggregator: Aggregator<A>,
-    stored_reports: HashMap<Nonce, StoredReport<A>>,
+    /// Only ever locked briefly, never while preparing a report.
+    stored_reports: Mutex<HashMap<Nonce, StoredReport<A>>>,
+    /// Durable copy of the stored reports.
+    store: Arc<dyn Store>,
+    /// If set, reports that are being prepared are kept in encrypted helper
+    /// state held by the leader rather than in `stored_reports`, which then
+    /// only holds accumulated reports.
+    helper_state_key: Option<HelperStateKey>,
+    purge_counters: PurgeCounters,
+    /// Metrics shared by all tasks the helper serves
+    metrics: Arc<Metrics>,
 }
 
-impl<A: vdaf::Aggregator + Debug> Helper<A> {
-    #[tracing::instrument(err, skip(hpke_config))]
+impl<A: vdaf::Aggregator + Debug> Helper<A>
+where
+    A::AggregateShare: Serialize + DeserializeOwned + NoisyAggregateShare,
+{
+    /// Construct a helper for the task, restoring any reports and accumulators
+    /// previously written to the task's store.
+    #[tracing::instrument(err, skip(task, hpke_config, metrics))]
     pub fn new(
-        parameters: &Parameters,
-        vdaf_aggregator: &A,
-        verify_parameter: &A::VerifyParam,
-        aggregation_parameter: &A::AggregationParam,
+        task: &Task<A>,
         hpke_config: &hpke::Config,
+        metrics: Arc<Metrics>,
     ) -> Result<Self, Error> {
-        let aggregator = Aggregator::new(
-            Role::Helper,
-            hpke_config,
-            vdaf_aggregator,
-            verify_parameter,
-            aggregation_parameter,
-            // TODO: lame that both structs own a copy of parameters
-            parameters,
-        );
+        // TODO: lame that both structs own a copy of parameters
+        let aggregator = Aggregator::new(Role::Helper, hpke_config, task, metrics.clone())?;
 
-        Ok(Self {
-            parameters: parameters.clone(),
+        let mut helper = Self {
+            parameters: task.parameters.clone(),
             aggregator,
-            stored_reports: HashMap::new(),
-        })
+            stored_reports: Mutex::new(HashMap::new()),
+            store: task.store.clone(),
+            helper_state_key: task.helper_state_key.clone(),
+            purge_counters: PurgeCounters::default(),
+            metrics,
+        };
+        helper.restore_reports()?;
+
+        Ok(helper)
+    }
+
+    /// Load reports from the store. Prepare steps can't be persisted, so the
+    /// store records the aggregation parameter each waiting report is being
+    /// prepared under and the leader's prepare messages for it, from which
+    /// its prepare step is recovered. Reports stored without that record are
+    /// prepared afresh under the task's eager aggregation parameter, if it has
+    /// one, and are dropped otherwise.
+    ///
+    /// The store also records the aggregation parameters each report of a task
+    /// without an eager aggregation parameter was accumulated under, so that
+    /// it isn't accumulated under them again.
+    fn restore_reports(&mut self) -> Result<(), Error> {
+        let mut stored_reports = HashMap::new();
+        let eager_aggregation_parameter = self
+            .aggregator
+            .eager_aggregation_parameter()
+            .map(Encode::get_encoded);
+
+        for record in self.store.reports()? {
+            for aggregation_parameter in &record.aggregation_parameters {
+                self.aggregator
+                    .record_nonce(record.nonce, aggregation_parameter)?;
+            }
+            let stored_report = match (record.state, &eager_aggregation_parameter) {
+                (ReportRecordState::Accumulated, Some(aggregation_parameter)) => {
+                    self.aggregator
+                        .record_nonce(record.nonce, aggregation_parameter)?;
+                    StoredReport::Accumulated
+                }
+                (ReportRecordState::Accumulated, None) => StoredReport::Accumulated,
+                // The helper doesn't hang on to reports that failed
+                (ReportRecordState::Failed, _) => continue,
+                (ReportRecordState::Waiting, _) => {
+                    let (aggregation_parameter, prepare_messages) =
+                        match (&record.preparation, &eager_aggregation_parameter) {
+                            (Some(preparation), _) => (
+                                preparation.aggregation_parameter.clone(),
+                                preparation.prepare_messages.as_slice(),
+                            ),
+                            (None, Some(aggregation_parameter)) => {
+                                (aggregation_parameter.clone(), [].as_slice())
+                            }
+                            (None, None) => {
+                                warn!(nonce = ?record.nonce, "dropping stored report");
+                                continue;
+                            }
+                        };
+                    match self.replay_prepare(
+                        &record.report_share()?,
+                        &aggregation_parameter,
+                        prepare_messages,
+                    ) {
+                        Ok(step) => StoredReport::Waiting {
+                            step,
+                            aggregation_parameter,
+                        },
+                        Err(error) => {
+                            warn!(nonce = ?record.nonce, ?error, "dropping stored report");
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            stored_reports.insert(record.nonce, stored_report);
+        }
+
+        info!(report_count = stored_reports.len(), "restored reports");
+        *self.stored_reports.get_mut().unwrap() = stored_reports;
+
+        Ok(())
     }
 
     #[tracing::instrument(skip(self, aggregate_message), err)]
     pub fn handle_aggregate(
-        &mut self,
+        &self,
         aggregate_message: &AggregateMessage,
     ) -> Result<AggregateMessage, Error> {
-        // TODO: verify HMAC
+        aggregate_message.verify(&self.parameters.aggregator_auth_key)?;
+        self.metrics.aggregate_round();
 
-        let inner_response = match aggregate_message.aggregate {
-            Aggregate::Initialize(ref req) => Aggregate::Response(self.handle_aggregate_init(req)?),
-            Aggregate::Request(ref req) => Aggregate::Response(self.handle_aggregate_req(req)?),
+        let response = match aggregate_message.aggregate {
+            Aggregate::Initialize(ref req) => self.handle_aggregate_init(req)?,
+            Aggregate::Request(ref req) => self.handle_aggregate_req(req)?,
             ref message => {
                 return Err(Error::AggregateProtocol(format!(
                     "unexpected aggregate message {:?}",
@@ -126,26 +234,58 @@ impl<A: vdaf::Aggregator + Debug> Helper<A> {
             }
         };
 
-        Ok(AggregateMessage {
-            aggregate: inner_response,
-            tag: [0u8; 32],
-        })
+        for transition in &response.transitions {
+            if let Transition::Failed { error } = transition.transition {
+                self.metrics.transition_error(error);
+            }
+        }
+        let inner_response = Aggregate::Response(response);
+
+        Ok(AggregateMessage::new(
+            inner_response,
+            &self.parameters.aggregator_auth_key,
+        ))
     }
 
     #[tracing::instrument(skip(self, request), err)]
-    fn handle_aggregate_init(
-        &mut self,
-        request: &AggregateInitReq,
-    ) -> Result<AggregateResp, Error> {
+    fn handle_aggregate_init(&self, request: &AggregateInitReq) -> Result<AggregateResp, Error> {
         info!(
             sub_request_count = request.report_shares.len(),
             "got aggregate request"
         );
 
+        // Each report gets exactly one transition in the response, so a
+        // request that contains a report twice is malformed
+        let mut request_nonces = HashSet::new();
+        if !request
+            .report_shares
+            .iter()
+            .all(|report_share| request_nonces.insert(report_share.nonce))
+        {
+            return Err(Error::AggregateProtocol(
+                "duplicate report in aggregate init request".to_string(),
+            ));
+        }
+
+        let aggregation_parameter = self
+            .aggregator
+            .validate_aggregation_parameter(&request.aggregation_parameter)?;
+
         let mut transitions = vec![];
+        let mut helper_state = HelperState {
+            aggregation_parameter: request.aggregation_parameter.clone(),
+            ..Default::default()
+        };
 
         for report_share in &request.report_shares {
-            if self.stored_reports.contains_key(&report_share.nonce) {
+            // A report that was accumulated under this aggregation parameter
+            // must not be aggregated under it twice, but one that is still
+            // waiting may be initialized again, which happens when the leader
+            // retries an aggregation job.
+            if self
+                .aggregator
+                .nonce_seen(report_share.nonce, &request.aggregation_parameter)
+            {
                 warn!(report_nonce = ?report_share.nonce, "duplicate report nonce");
                 transitions.push(TransitionMessage {
                     nonce: report_share.nonce,
@@ -153,6 +293,23 @@ impl<A: vdaf::Aggregator + Debug> Helper<A> {
                         error: TransitionError::ReportReplayed,
                     },
                 });
+                continue;
+            }
+            if let Some(StoredReport::Waiting { .. }) =
+                self.stored_reports.lock().unwrap().get(&report_share.nonce)
+            {
+                info!(report_nonce = ?report_share.nonce, "reinitializing report");
+            }
+
+            if let Err(time_error) = self.aggregator.check_report_time(report_share.nonce) {
+                warn!(?time_error, "rejecting report");
+                transitions.push(TransitionMessage {
+                    nonce: report_share.nonce,
+                    transition: Transition::Failed {
+                        error: time_error.into(),
+                    },
+                });
+                continue;
             }
 
             let (step, prepare_message) = match self.aggregator.prepare_message(
@@ -160,6 +317,7 @@ impl<A: vdaf::Aggregator + Debug> Helper<A> {
                 report_share.nonce,
                 &report_share.extensions,
                 &report_share.encrypted_input_share,
+                &aggregation_parameter,
             ) {
                 Ok(v) => v,
                 Err(prep_error) => {
@@ -181,35 +339,190 @@ impl<A: vdaf::Aggregator + Debug> Helper<A> {
                 },
             });
 
-            self.stored_reports
-                .insert(report_share.nonce, StoredReport::Waiting { step });
+            if self.helper_state_key.is_some() {
+                helper_state.reports.push(PendingReport {
+                    report_share: report_share.clone(),
+                    prepare_messages: vec![],
+                });
+            } else {
+                self.store.put_report(
+                    ReportRecord::new(report_share, None, ReportRecordState::Waiting)
+                        .with_preparation(request.aggregation_parameter.clone()),
+                )?;
+                self.stored_reports.lock().unwrap().insert(
+                    report_share.nonce,
+                    StoredReport::Waiting {
+                        step,
+                        aggregation_parameter: request.aggregation_parameter.clone(),
+                    },
+                );
+            }
         }
 
         self.aggregator.dump_accumulators();
+        // New aggregation jobs arrive regularly, so this is a good time to
+        // purge reports that have aged out.
+        self.purge_reports();
 
         Ok(AggregateResp {
-            helper_state: vec![],
+            helper_state: self.seal_helper_state(request.job_id, 1, &helper_state)?,
             transitions,
         })
     }
 
+    /// Encrypt helper state to hand to the leader, which hands it back in the
+    /// given round of the job. Without a helper state key, the helper keeps
+    /// its state itself and the leader gets nothing.
+    fn seal_helper_state(
+        &self,
+        job_id: AggregationJobId,
+        round: u16,
+        helper_state: &HelperState,
+    ) -> Result<Vec<u8>, Error> {
+        match &self.helper_state_key {
+            Some(key) => Ok(key.seal(&self.parameters.task_id, job_id, round, helper_state)?),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Recover the prepare step of a report held in helper state or in the
+    /// store by preparing its share afresh under the encoded aggregation
+    /// parameter and replaying the leader's prepare messages.
+    fn replay_prepare(
+        &self,
+        report_share: &ReportShare,
+        aggregation_parameter: &[u8],
+        prepare_messages: &[Vec<u8>],
+    ) -> Result<A::PrepareStep, Error> {
+        let (mut step, _) = self.aggregator.prepare_message(
+            self.parameters.task_id,
+            report_share.nonce,
+            &report_share.extensions,
+            &report_share.encrypted_input_share,
+            &A::AggregationParam::get_decoded(aggregation_parameter)?,
+        )?;
+
+        for message in prepare_messages {
+            let message = A::PrepareMessage::get_decoded_with_param(&step, message)?;
+            step = match self.aggregator.aggregator.prepare_step(step, Some(message)) {
+                PrepareTransition::Continue(next_round_step, _) => next_round_step,
+                _ => {
+                    return Err(Error::AggregateProtocol(
+                        "helper state does not replay".to_string(),
+                    ))
+                }
+            };
+        }
+
+        Ok(step)
+    }
+
     #[tracing::instrument(skip(self, request), err)]
-    fn handle_aggregate_req(&mut self, request: &AggregateReq) -> Result<AggregateResp, Error> {
+    fn handle_aggregate_req(&self, request: &AggregateReq) -> Result<AggregateResp, Error> {
         if request.task_id != self.parameters.task_id {
             return Err(Error::UnrecognizedTask(request.task_id));
         }
 
-        // We ignore helper state. I think realistically, we would use it to store a key that would
-        // identify one of many HashMap<Nonce, StoredReport>s so that we could service multiple
-        // aggregate protocols concurrently. Right now we take a lock on a single Helper that
-        // stores ALL reports.
+        let mut request_nonces = HashSet::new();
+        if !request
+            .transitions
+            .iter()
+            .all(|transition| request_nonces.insert(transition.nonce))
+        {
+            return Err(Error::AggregateProtocol(
+                "duplicate report in aggregate request".to_string(),
+            ));
+        }
+
+        // Without a helper state key, we ignore helper state and look reports
+        // up among those we store. With one, the reports of this job are in
+        // the helper state sent back to us by the leader.
+        let mut helper_state = match &self.helper_state_key {
+            Some(key) => key.open(
+                &self.parameters.task_id,
+                request.job_id,
+                request.round,
+                &request.helper_state,
+            )?,
+            None => HelperState::default(),
+        };
+        let mut pending_reports: HashMap<_, _> = std::mem::take(&mut helper_state.reports)
+            .into_iter()
+            .map(|pending_report| (pending_report.nonce(), pending_report))
+            .collect();
+        let mut next_helper_state = HelperState {
+            aggregation_parameter: helper_state.aggregation_parameter.clone(),
+            ..Default::default()
+        };
 
         let mut transitions = vec![];
 
         for leader_transition in &request.transitions {
-            let stored_report = match self.stored_reports.get_mut(&leader_transition.nonce) {
-                Some(v) => v,
-                None => {
+            let payload = match &leader_transition.transition {
+                Transition::Continued { payload } => payload,
+                Transition::Finished => {
+                    // Leader never sends helper finished
+                    warn!(?leader_transition.nonce, "leader unexpectedly finished");
+                    return Err(Error::AggregateProtocol(
+                        "leader unexpectedly finished".to_string(),
+                    ));
+                }
+                Transition::Failed { error } => {
+                    // Leader should never send helper failed
+                    warn!(leader_error = ?error, ?leader_transition.nonce, "leader unexpected failed");
+                    return Err(Error::AggregateProtocol(
+                        "leader unexpectedly failed".to_string(),
+                    ));
+                }
+            };
+            info!(?leader_transition.nonce, "leader continued");
+
+            let pending_report = pending_reports.remove(&leader_transition.nonce);
+            let stored_report = self
+                .stored_reports
+                .lock()
+                .unwrap()
+                .get(&leader_transition.nonce)
+                .cloned();
+            let (step, aggregation_parameter) = match (&pending_report, stored_report) {
+                // The leader may not replay helper state to get a report
+                // accumulated twice
+                (Some(_), _)
+                    if self.aggregator.nonce_seen(
+                        leader_transition.nonce,
+                        &helper_state.aggregation_parameter,
+                    ) =>
+                {
+                    warn!(?leader_transition.nonce, "report in helper state already accumulated");
+                    transitions.push(TransitionMessage {
+                        nonce: leader_transition.nonce,
+                        transition: Transition::Failed {
+                            error: TransitionError::ReportReplayed,
+                        },
+                    });
+                    continue;
+                }
+                (Some(pending_report), _) => (
+                    self.replay_prepare(
+                        &pending_report.report_share,
+                        &helper_state.aggregation_parameter,
+                        &pending_report.prepare_messages,
+                    )?,
+                    helper_state.aggregation_parameter.clone(),
+                ),
+                (
+                    None,
+                    Some(StoredReport::Waiting {
+                        step,
+                        aggregation_parameter,
+                    }),
+                ) => (step, aggregation_parameter),
+                (None, Some(StoredReport::Accumulated)) => {
+                    return Err(Error::AggregateProtocol(
+                        "leader unexpectedly continued".to_string(),
+                    ));
+                }
+                (None, None) => {
                     warn!(leader_transition_nonce = ?leader_transition.nonce, "unrecognized nonce in leader transition");
                     transitions.push(TransitionMessage {
                         nonce: leader_transition.nonce,
@@ -221,92 +534,142 @@ impl<A: vdaf::Aggregator + Debug> Helper<A> {
                 }
             };
 
-            match &leader_transition.transition {
-                Transition::Continued { payload } => {
-                    info!(?leader_transition.nonce, "leader continued");
-                    let step = if let StoredReport::Waiting { step } = stored_report {
-                        step
-                    } else {
-                        return Err(Error::AggregateProtocol(
-                            "leader unexpectedly continued".to_string(),
-                        ));
-                    };
-
-                    let preprocessed_prepare_message =
-                        A::PrepareMessage::get_decoded_with_param(step, payload)?;
-
-                    // Advance self to round n + 1
-                    let transition = match self
-                        .aggregator
-                        .aggregator
-                        .prepare_step(step.clone(), Some(preprocessed_prepare_message))
-                    {
-                        PrepareTransition::Continue(
-                            next_round_step,
-                            next_round_prepare_message,
-                        ) => {
-                            *stored_report = StoredReport::Waiting {
-                                step: next_round_step,
-                            };
-                            Transition::Continued {
-                                payload: next_round_prepare_message.get_encoded(),
-                            }
-                        }
-                        PrepareTransition::Finish(output_share) => {
-                            *stored_report = StoredReport::Accumulated;
-                            info!(?leader_transition.nonce, "accumulating report");
-                            self.aggregator
-                                .accumulate_report(leader_transition.nonce, output_share)?;
-                            Transition::Finished
+            let preprocessed_prepare_message =
+                A::PrepareMessage::get_decoded_with_param(&step, payload)?;
+
+            // Advance self to round n + 1
+            let transition = match self
+                .aggregator
+                .aggregator
+                .prepare_step(step, Some(preprocessed_prepare_message))
+            {
+                PrepareTransition::Continue(next_round_step, next_round_prepare_message) => {
+                    match pending_report {
+                        Some(mut pending_report) => {
+                            pending_report.prepare_messages.push(payload.clone());
+                            next_helper_state.reports.push(pending_report);
                         }
-                        PrepareTransition::Fail(error) => {
-                            warn!(
-                                time = ?leader_transition.nonce,
-                                ?error,
-                                "proof did not check out for report"
+                        None => {
+                            self.store.commit(vec![Mutation::AddPrepareMessage {
+                                nonce: leader_transition.nonce,
+                                prepare_message: payload.clone(),
+                            }])?;
+                            self.stored_reports.lock().unwrap().insert(
+                                leader_transition.nonce,
+                                StoredReport::Waiting {
+                                    step: next_round_step,
+                                    aggregation_parameter,
+                                },
                             );
-                            // Process other transitions
-                            continue;
                         }
-                    };
-
-                    transitions.push(TransitionMessage {
-                        nonce: leader_transition.nonce,
-                        transition,
-                    });
+                    }
+                    Transition::Continued {
+                        payload: next_round_prepare_message.get_encoded(),
+                    }
                 }
-                Transition::Finished => {
-                    // Leader never sends helper finished
-                    warn!(?leader_transition.nonce, "leader unexpectedly finished");
-                    return Err(Error::AggregateProtocol(
-                        "leader unexpectedly finished".to_string(),
-                    ));
+                PrepareTransition::Finish(output_share) => {
+                    let decoded_aggregation_parameter =
+                        A::AggregationParam::get_decoded(&aggregation_parameter)?;
+                    // Of several requests finishing the same report, which the
+                    // leader may send when retrying a job, only one may
+                    // accumulate it
+                    if let Err(replayed) = self
+                        .aggregator
+                        .record_nonce(leader_transition.nonce, &aggregation_parameter)
+                    {
+                        warn!(?replayed, "report already accumulated");
+                        transitions.push(TransitionMessage {
+                            nonce: leader_transition.nonce,
+                            transition: Transition::Failed {
+                                error: replayed.into(),
+                            },
+                        });
+                        continue;
+                    }
+                    let mut mutations = vec![match pending_report {
+                        // Reports in helper state were never stored, so record
+                        // them now to catch replays.
+                        Some(pending_report) => Mutation::PutReport(ReportRecord::new(
+                            &pending_report.report_share,
+                            None,
+                            ReportRecordState::Accumulated,
+                        )),
+                        None => Mutation::UpdateReportState {
+                            nonce: leader_transition.nonce,
+                            state: ReportRecordState::Accumulated,
+                        },
+                    }];
+                    if self.aggregator.eager_aggregation_parameter().is_none() {
+                        mutations.push(Mutation::AddAggregationParameter {
+                            nonce: leader_transition.nonce,
+                            aggregation_parameter: aggregation_parameter.clone(),
+                        });
+                    }
+                    info!(?leader_transition.nonce, "accumulating report");
+                    // The accumulator and the report's state are written
+                    // together, so a crash can't leave the report to be
+                    // accumulated again
+                    if let Err(error) = self.aggregator.accumulate_report(
+                        leader_transition.nonce,
+                        &decoded_aggregation_parameter,
+                        output_share,
+                        mutations,
+                    ) {
+                        self.aggregator
+                            .forget_nonce(leader_transition.nonce, &aggregation_parameter);
+                        return Err(error.into());
+                    }
+                    self.stored_reports
+                        .lock()
+                        .unwrap()
+                        .insert(leader_transition.nonce, StoredReport::Accumulated);
+                    Transition::Finished
                 }
-                Transition::Failed { error } => {
-                    // Leader should never send helper failed
-                    warn!(leader_error = ?error, ?leader_transition.nonce, "leader unexpected failed");
-                    return Err(Error::AggregateProtocol(
-                        "leader unexpectedly failed".to_string(),
-                    ));
+                PrepareTransition::Fail(error) => {
+                    warn!(
+                        time = ?leader_transition.nonce,
+                        ?error,
+                        "proof did not check out for report"
+                    );
+                    // Process other transitions
+                    continue;
                 }
-            }
+            };
+
+            transitions.push(TransitionMessage {
+                nonce: leader_transition.nonce,
+                transition,
+            });
         }
 
         info!("dumping accumulators");
         self.aggregator.dump_accumulators();
 
         Ok(AggregateResp {
-            helper_state: vec![],
+            helper_state: self.seal_helper_state(
+                request.job_id,
+                request.round.saturating_add(1),
+                &next_helper_state,
+            )?,
             transitions,
         })
     }
 
+    /// Release the helper's aggregate share for the requested batch interval,
+    /// committing the privacy budget it consumes before responding.
+    ///
+    /// The helper has no way to learn whether the leader went on to release
+    /// its own share. If the leader fails after this, for instance because it
+    /// can't record its own release, the leader gives its budget back but the
+    /// helper's stays spent, and the interval can't be collected again until
+    /// `max_batch_lifetime` allows another release.
     #[tracing::instrument(skip(self), err)]
     pub fn handle_aggregate_share(
-        &mut self,
+        &self,
         aggregate_message: &AggregateMessage,
     ) -> Result<AggregateMessage, Error> {
-        // TODO: verify HMAC
+        aggregate_message.verify(&self.parameters.aggregator_auth_key)?;
+        self.metrics.collect_request();
 
         let request = match aggregate_message.aggregate {
             Aggregate::ShareRequest(ref req) => req,
@@ -317,112 +680,449 @@ impl<A: vdaf::Aggregator + Debug> Helper<A> {
             }
         };
 
-        Ok(AggregateMessage {
-            aggregate: Aggregate::ShareResponse(
-                self.aggregator
-                    .extract_aggregate_share(request.task_id, request.batch_interval)?,
-            ),
-            tag: [0u8; 32],
-        })
+        let (aggregate_share, release) = self.aggregator.extract_aggregate_share(
+            request.task_id,
+            request.batch_interval,
+            &request.aggregation_parameter,
+        )?;
+        // The share is released in the response
+        self.aggregator.commit_release(release)?;
+        // Reports in the interval may no longer be needed
+        self.purge_reports();
+
+        Ok(AggregateMessage::new(
+            Aggregate::ShareResponse(aggregate_share),
+            &self.parameters.aggregator_auth_key,
+        ))
+    }
+
+    /// Purge accumulated reports that are no longer worth keeping, as
+    /// described in [`crate::retention`]. Failure to purge is logged but
+    /// otherwise ignored, since the reports will be purged on a later attempt.
+    fn purge_reports(&self) {
+        self.aggregator.prune_nonce_index();
+
+        let accumulated: Vec<_> = self
+            .stored_reports
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, report)| matches!(report, StoredReport::Accumulated))
+            .map(|(nonce, _)| *nonce)
+            .collect();
+
+        let now = self.aggregator.now();
+        let nonces: Vec<_> = accumulated
+            .into_iter()
+            .filter(|nonce| {
+                should_purge(
+                    &self.parameters,
+                    *nonce,
+                    self.aggregator.batch_closed(*nonce),
+                    now,
+                )
+            })
+            .collect();
+        if nonces.is_empty() {
+            return;
+        }
+
+        if let Err(error) = self.store.delete_reports(&nonces) {
+            warn!(?error, "failed to purge reports");
+            return;
+        }
+        let mut stored_reports = self.stored_reports.lock().unwrap();
+        for nonce in &nonces {
+            stored_reports.remove(nonce);
+        }
+
+        let purged = PurgedReports {
+            accumulated: nonces.len() as u64,
+            failed: 0,
+        };
+        info!(?purged, "purged reports");
+        self.purge_counters.record(purged);
+    }
+
+    /// Check that the task's store can be written to.
+    fn readiness_checks(&self) -> Vec<Check> {
+        vec![Check::new(
+            "storage",
+            Some(self.parameters.task_id),
+            self.store.check().map_err(health::Error::from),
+        )]
+    }
+
+    /// Total number of reports purged since the helper started.
+    pub fn purged_reports(&self) -> PurgedReports {
+        self.purge_counters.totals()
     }
 }
 
-pub async fn run_helper<A>(
-    ppm_parameters: &Parameters,
-    vdaf_aggregator: &A,
-    verify_parameter: &A::VerifyParam,
-    aggregation_parameter: &A::AggregationParam,
-    hpke_config: &hpke::Config,
-) -> Result<()>
+/// A [`Helper`] with its VDAF erased, so that one helper can serve tasks
+/// using different VDAFs.
+trait TaskHelper: Debug + Send + Sync {
+    fn parameters(&self) -> &Parameters;
+
+    fn handle_aggregate(
+        &self,
+        aggregate_message: &AggregateMessage,
+    ) -> Result<AggregateMessage, Error>;
+
+    fn handle_aggregate_share(
+        &self,
+        aggregate_message: &AggregateMessage,
+    ) -> Result<AggregateMessage, Error>;
+
+    fn readiness_checks(&self) -> Vec<Check>;
+
+    fn flush(&self) -> Result<(), Error>;
+}
+
+impl<A> TaskHelper for Helper<A>
 where
-    A: vdaf::Aggregator + 'static + Send + Sync,
+    A: vdaf::Aggregator + Debug + Send + Sync,
     A::VerifyParam: Send + Sync,
     A::AggregationParam: Send + Sync,
     A::PrepareStep: Send + Sync,
-    A::AggregateShare: Send + Sync,
+    A::AggregateShare: Send + Sync + Serialize + DeserializeOwned + NoisyAggregateShare,
 {
-    let port = ppm_parameters.aggregator_endpoints[Role::Helper.index()]
-        .port()
-        .unwrap_or(80);
-
-    let hpke_config_endpoint = hpke_config.warp_endpoint()?;
-
-    let helper_aggregator = Arc::new(Mutex::new(Helper::new(
-        ppm_parameters,
-        vdaf_aggregator,
-        verify_parameter,
-        aggregation_parameter,
-        hpke_config,
-    )?));
-
-    let aggregate = warp::post()
-        .and(warp::path("aggregate"))
-        .and(warp::body::bytes())
-        .and(with_shared_value(helper_aggregator.clone()))
-        .and_then(|body: Bytes, helper: Arc<Mutex<Helper<_>>>| async move {
-            let mut helper = helper.lock().await;
-            let aggregate_message = AggregateMessage::get_decoded(&body).map_err(|e| {
-                warp::reject::custom(e.problem_document(Some(&helper.parameters), "aggregate"))
-            })?;
-
-            let response = helper.handle_aggregate(&aggregate_message).map_err(|e| {
-                warp::reject::custom(e.problem_document(Some(&helper.parameters), "aggregate"))
-            })?;
-
-            let response = Response::builder()
-                .status(StatusCode::OK)
-                .body(response.get_encoded())
-                .map_err(|e| {
-                    warp::reject::custom(e.problem_document(Some(&helper.parameters), "aggregate"))
-                })?;
-
-            Ok(response) as Result<_, Rejection>
-        })
-        .with(warp::trace::named("aggregate"));
-
-    let aggregate_share = warp::post()
-        .and(warp::path("aggregate_share"))
-        .and(warp::body::bytes())
-        .and(with_shared_value(helper_aggregator.clone()))
-        .and_then(|body: Bytes, helper: Arc<Mutex<Helper<_>>>| async move {
-            let mut helper = helper.lock().await;
-            let aggregate_message = AggregateMessage::get_decoded(&body).map_err(|e| {
-                warp::reject::custom(
-                    e.problem_document(Some(&helper.parameters), "aggregate_share"),
-                )
-            })?;
+    fn parameters(&self) -> &Parameters {
+        &self.parameters
+    }
 
-            let response = helper
-                .handle_aggregate_share(&aggregate_message)
-                .map_err(|e| {
-                    warp::reject::custom(
-                        e.problem_document(Some(&helper.parameters), "aggregate_share"),
-                    )
-                })?;
-
-            let response = Response::builder()
-                .status(StatusCode::OK)
-                .body(response.get_encoded())
-                .map_err(|e| {
-                    warp::reject::custom(
-                        e.problem_document(Some(&helper.parameters), "aggregate_share"),
+    fn handle_aggregate(
+        &self,
+        aggregate_message: &AggregateMessage,
+    ) -> Result<AggregateMessage, Error> {
+        Helper::handle_aggregate(self, aggregate_message)
+    }
+
+    fn handle_aggregate_share(
+        &self,
+        aggregate_message: &AggregateMessage,
+    ) -> Result<AggregateMessage, Error> {
+        Helper::handle_aggregate_share(self, aggregate_message)
+    }
+
+    fn readiness_checks(&self) -> Vec<Check> {
+        Helper::readiness_checks(self)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Ok(self.store.flush()?)
+    }
+}
+
+/// Constructs the helper of a task once the metrics it shares with the helpers
+/// of other tasks exist.
+type HelperConstructor =
+    Box<dyn FnOnce(&hpke::Config, Arc<Metrics>) -> Result<Box<dyn TaskHelper>, Error> + Send>;
+
+/// Decode an aggregate message and look up the helper for the task it belongs
+/// to, or construct a rejection with a problem document.
+fn decode_and_route<'a>(
+    helpers: &'a TaskRegistry<Box<dyn TaskHelper>>,
+    body: &[u8],
+    endpoint: &'static str,
+) -> Result<(AggregateMessage, &'a dyn TaskHelper), Rejection> {
+    let aggregate_message = AggregateMessage::get_decoded(body)
+        .map_err(|e| warp::reject::custom(e.problem_document(None, endpoint)))?;
+
+    let task_id = aggregate_message.aggregate.task_id().ok_or_else(|| {
+        warp::reject::custom(
+            Error::AggregateProtocol(format!(
+                "unexpected aggregate message {:?}",
+                aggregate_message.aggregate
+            ))
+            .problem_document(None, endpoint),
+        )
+    })?;
+
+    let helper = helpers.get(&task_id).ok_or_else(|| {
+        warp::reject::custom(Error::UnrecognizedTask(task_id).problem_document(None, endpoint))
+    })?;
+
+    Ok((aggregate_message, helper.as_ref()))
+}
+
+/// Builds a helper serving a set of tasks. By default, the helper listens on
+/// `0.0.0.0` at the port of the helper endpoint of the first task. Tasks may
+/// use different VDAFs.
+pub struct HelperBuilder {
+    tasks: Vec<(Parameters, HelperConstructor)>,
+    hpke_config: hpke::Config,
+    address: Option<SocketAddr>,
+    tls: Option<TlsConfig>,
+    shutdown_signal: Option<BoxFuture<'static, ()>>,
+}
+
+impl HelperBuilder {
+    pub fn new(hpke_config: &hpke::Config) -> Self {
+        Self {
+            tasks: Vec::new(),
+            hpke_config: hpke_config.clone(),
+            address: None,
+            tls: None,
+            shutdown_signal: None,
+        }
+    }
+
+    /// Serve `tasks`, which need not use the same VDAF as tasks added
+    /// otherwise.
+    pub fn tasks<A>(self, tasks: Vec<Task<A>>) -> Self
+    where
+        A: vdaf::Aggregator + Debug + 'static + Send + Sync,
+        A::VerifyParam: Send + Sync,
+        A::AggregationParam: Send + Sync,
+        A::PrepareStep: Send + Sync,
+        A::AggregateShare: Send + Sync + Serialize + DeserializeOwned + NoisyAggregateShare,
+    {
+        tasks.into_iter().fold(self, Self::task)
+    }
+
+    /// Serve `task`, which need not use the same VDAF as the other tasks.
+    pub fn task<A>(mut self, task: Task<A>) -> Self
+    where
+        A: vdaf::Aggregator + Debug + 'static + Send + Sync,
+        A::VerifyParam: Send + Sync,
+        A::AggregationParam: Send + Sync,
+        A::PrepareStep: Send + Sync,
+        A::AggregateShare: Send + Sync + Serialize + DeserializeOwned + NoisyAggregateShare,
+    {
+        self.tasks.push((
+            task.parameters.clone(),
+            Box::new(move |hpke_config, metrics| {
+                Ok(Box::new(Helper::new(&task, hpke_config, metrics)?) as _)
+            }),
+        ));
+        self
+    }
+
+    /// Listen on `address`. Port 0 picks an unused port, which
+    /// [`ServerHandle::local_addr`] reports.
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Serve HTTPS with the certificate in `tls`. If `tls` pins the leader's
+    /// certificate, only clients presenting it may use the `aggregate` and
+    /// `aggregate_share` routes.
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Shut down once `signal` resolves.
+    pub fn shutdown_signal<F: Future<Output = ()> + Send + 'static>(mut self, signal: F) -> Self {
+        self.shutdown_signal = Some(signal.boxed());
+        self
+    }
+
+    /// Start serving in the background. Must be called from within a Tokio
+    /// runtime.
+    pub fn start(mut self) -> Result<ServerHandle> {
+        let (shutdown_sender, shutdown) = server::shutdown_signal(self.shutdown_signal.take());
+        let (local_addr, server) = self.bind(shutdown)?;
+
+        Ok(ServerHandle::new(
+            local_addr,
+            shutdown_sender,
+            tokio::spawn(server),
+        ))
+    }
+
+    /// Serve until the shutdown signal resolves.
+    pub async fn run(mut self) -> Result<()> {
+        let (_shutdown_sender, shutdown) = server::shutdown_signal(self.shutdown_signal.take());
+        let (_, server) = self.bind(shutdown)?;
+
+        server.await
+    }
+
+    /// Bind the helper's address, returning the address and a future that
+    /// serves requests until `shutdown` resolves and then flushes the stores
+    /// of all tasks.
+    #[tracing::instrument(skip(self, shutdown), err)]
+    fn bind(
+        self,
+        shutdown: ShutdownSignal,
+    ) -> Result<(SocketAddr, impl Future<Output = Result<()>>)> {
+        let tasks = self.tasks;
+        let hpke_config = &self.hpke_config;
+        let address = match self.address {
+            Some(address) => address,
+            None => {
+                let (parameters, _) = tasks
+                    .first()
+                    .ok_or_else(|| eyre!("helper must serve at least one task"))?;
+                let port = parameters.aggregator_endpoints[Role::Helper.index()]
+                    .port()
+                    .unwrap_or(80);
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port)
+            }
+        };
+
+        let hpke_config_endpoint = hpke_config.warp_endpoint()?;
+        let metrics = Arc::new(Metrics::new()?);
+
+        let task_count = tasks.len();
+        let helpers: Arc<TaskRegistry<Box<dyn TaskHelper>>> = Arc::new(TaskRegistry::new(
+            tasks
+                .into_iter()
+                .map(|(parameters, constructor)| {
+                    Ok((
+                        parameters.task_id,
+                        constructor(hpke_config, metrics.clone())?,
+                    ))
+                })
+                .collect::<Result<Vec<_>, Error>>()?,
+        )?);
+
+        let aggregate = warp::post()
+            .and(warp::path("aggregate"))
+            .and(tls::require_leader_certificate(
+                self.tls.as_ref(),
+                "aggregate",
+            ))
+            .and(warp::body::bytes())
+            .and(with_shared_value(helpers.clone()))
+            .and_then(
+                |body: Bytes, helpers: Arc<TaskRegistry<Box<dyn TaskHelper>>>| async move {
+                    let (aggregate_message, helper) =
+                        decode_and_route(&helpers, &body, "aggregate")?;
+
+                    let response = helper.handle_aggregate(&aggregate_message).map_err(|e| {
+                        warp::reject::custom(
+                            e.problem_document(Some(helper.parameters()), "aggregate"),
+                        )
+                    })?;
+
+                    let response = Response::builder()
+                        .status(StatusCode::OK)
+                        .body(response.get_encoded())
+                        .map_err(|e| {
+                            warp::reject::custom(
+                                e.problem_document(Some(helper.parameters()), "aggregate"),
+                            )
+                        })?;
+
+                    Ok(response) as Result<_, Rejection>
+                },
+            )
+            .with(warp::trace::named("aggregate"));
+
+        let aggregate_share = warp::post()
+            .and(warp::path("aggregate_share"))
+            .and(tls::require_leader_certificate(
+                self.tls.as_ref(),
+                "aggregate_share",
+            ))
+            .and(warp::body::bytes())
+            .and(with_shared_value(helpers.clone()))
+            .and_then(
+                |body: Bytes, helpers: Arc<TaskRegistry<Box<dyn TaskHelper>>>| async move {
+                    let (aggregate_message, helper) =
+                        decode_and_route(&helpers, &body, "aggregate_share")?;
+
+                    let response =
+                        helper
+                            .handle_aggregate_share(&aggregate_message)
+                            .map_err(|e| {
+                                warp::reject::custom(
+                                    e.problem_document(
+                                        Some(helper.parameters()),
+                                        "aggregate_share",
+                                    ),
+                                )
+                            })?;
+
+                    let response = Response::builder()
+                        .status(StatusCode::OK)
+                        .body(response.get_encoded())
+                        .map_err(|e| {
+                            warp::reject::custom(
+                                e.problem_document(Some(helper.parameters()), "aggregate_share"),
+                            )
+                        })?;
+
+                    Ok(response) as Result<_, Rejection>
+                },
+            )
+            .with(warp::trace::named("aggregate_share"));
+
+        let readyz = warp::get()
+            .and(warp::path("readyz"))
+            .and(with_shared_value(helpers.clone()))
+            .and(with_shared_value(hpke_config.clone()))
+            .map(
+                |helpers: Arc<TaskRegistry<Box<dyn TaskHelper>>>, hpke_config: hpke::Config| {
+                    Readiness::new(
+                        &hpke_config,
+                        helpers.iter().count(),
+                        helpers
+                            .iter()
+                            .flat_map(|helper| helper.readiness_checks())
+                            .collect(),
                     )
-                })?;
+                },
+            )
+            .with(warp::trace::named("readyz"));
 
-            Ok(response) as Result<_, Rejection>
-        })
-        .with(warp::trace::named("aggregate_share"));
+        let routes = hpke_config_endpoint
+            .or(health::healthz_endpoint())
+            .or(readyz)
+            .or(metrics.warp_endpoint())
+            .or(aggregate)
+            .or(aggregate_share)
+            .recover(handle_rejection)
+            .with(warp::trace::request());
 
-    let routes = hpke_config_endpoint
-        .or(aggregate)
-        .or(aggregate_share)
-        .recover(handle_rejection)
-        .with(warp::trace::request());
+        let (local_addr, server) = server::serve(routes, address, self.tls.as_ref(), shutdown)?;
+        info!(task_count, "helper serving on {}", local_addr);
 
-    info!("helper serving on 0.0.0.0:{}", port);
-    warp::serve(routes)
-        .run(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port))
-        .await;
+        let server = async move {
+            server.await;
 
-    unreachable!()
+            for helper in helpers.iter() {
+                helper.flush()?;
+            }
+            info!("helper stopped");
+
+            Ok(())
+        };
+
+        Ok((local_addr, server))
+    }
+}
+
+impl ServeTasks for HelperBuilder {
+    const ROLE: Role = Role::Helper;
+
+    fn task<A>(self, task: Task<A>) -> Self
+    where
+        A: vdaf::Aggregator + Debug + 'static + Send + Sync,
+        A::VerifyParam: Send + Sync,
+        A::AggregationParam: Send + Sync,
+        A::PrepareStep: Send + Sync,
+        A::AggregateShare: Send + Sync + Serialize + DeserializeOwned + NoisyAggregateShare,
+        A::PrepareMessage: Send + Sync,
+        A::OutputShare: Send + Sync,
+    {
+        HelperBuilder::task(self, task)
+    }
+}
+
+/// Serve the provided tasks until the returned future is dropped, as described
+/// in [`HelperBuilder`].
+pub async fn run_helper<A>(tasks: Vec<Task<A>>, hpke_config: &hpke::Config) -> Result<()>
+where
+    A: vdaf::Aggregator + Debug + 'static + Send + Sync,
+    A::VerifyParam: Send + Sync,
+    A::AggregationParam: Send + Sync,
+    A::PrepareStep: Send + Sync,
+    A::AggregateShare: Send + Sync + Serialize + DeserializeOwned + NoisyAggregateShare,
+{
+    HelperBuilder::new(hpke_config).tasks(tasks).run().await
 }
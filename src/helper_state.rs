@@ -0,0 +1,309 @@
+//! Encrypted helper state.
+//!
+//! Rather than keeping the prepare state of the reports in an aggregate job
+//! itself, a helper may hand it to the leader in `AggregateResp.helper_state`
+//! and get it back in the next `AggregateReq`. The state is encrypted and
+//! authenticated under a key known only to the helper, so that the leader can
+//! neither read nor forge it, and the helper replicas serving a task need not
+//! share anything but that key.
+//!
+//! VDAF prepare steps can't be encoded in general, so the state consists of
+//! the job's aggregation parameter, each report's share and the prepare
+//! messages the leader has sent for it so far. The helper recovers a report's
+//! prepare step by preparing the report share afresh under the aggregation
+//! parameter and replaying those messages.
+//!
+//! The state is bound to the task, the aggregation job and the round it is to
+//! be used in, so that the leader can't hand the helper state from another job
+//! or an earlier round of the same job. Nothing stops the leader from sending
+//! the same state twice, though. A report's nonce is only recorded as
+//! accumulated by the replica that accumulates it, so for helper replicas to
+//! catch a report being accumulated twice, and for their aggregate shares to
+//! cover every report, the accumulators and the nonce index must live in
+//! storage shared by all replicas. This prototype keeps both per process, so
+//! it must run a single helper replica per task.
+
+use crate::{
+    aggregate::ReportShare, aggregation_job::AggregationJobId, config_path, parameters::TaskId,
+    Nonce,
+};
+use aes_gcm::{
+    aead::{Aead, NewAead, Payload},
+    Aes128Gcm,
+};
+use prio::codec::{decode_u24_items, encode_u24_items, CodecError, Decode, Encode};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Debug, Formatter},
+    fs::File,
+    io::{Cursor, ErrorKind},
+    path::PathBuf,
+};
+
+/// Length of the AES-GCM nonce prepended to encrypted helper state
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("JSON parse error")]
+    JsonParse(#[from] serde_json::error::Error),
+    #[error("file error: {1}")]
+    File(#[source] std::io::Error, PathBuf),
+    #[error("Codec error")]
+    Codec(#[from] CodecError),
+    #[error("helper state key must be 16 bytes long")]
+    InvalidKey,
+    #[error("failed to encrypt helper state")]
+    Encryption,
+    #[error("helper state does not decrypt")]
+    Decryption,
+}
+
+/// AES-128-GCM key with which a helper protects the state it hands to the
+/// leader.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct HelperStateKey(
+    #[serde(
+        serialize_with = "crate::base64::serialize_bytes",
+        deserialize_with = "crate::base64::deserialize_bytes"
+    )]
+    Vec<u8>,
+);
+
+impl Debug for HelperStateKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // Don't leak the key into logs
+        f.write_str("HelperStateKey(..)")
+    }
+}
+
+impl HelperStateKey {
+    pub fn generate() -> Self {
+        Self(thread_rng().gen::<[u8; 16]>().to_vec())
+    }
+
+    /// Load the helper state key from `helper-state-key.json` in the default
+    /// configuration directory, if that file exists.
+    pub fn from_config_file() -> Result<Option<Self>, Error> {
+        let key_path = config_path().join("helper-state-key.json");
+
+        match File::open(&key_path) {
+            Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::File(e, key_path)),
+        }
+    }
+
+    fn cipher(&self) -> Result<Aes128Gcm, Error> {
+        Aes128Gcm::new_from_slice(&self.0).map_err(|_| Error::InvalidKey)
+    }
+
+    /// Encrypt `state` for the round of the task's aggregation job in which
+    /// it is to be opened again. These are bound into the ciphertext so that
+    /// state can't be moved between tasks, jobs or rounds.
+    pub(crate) fn seal(
+        &self,
+        task_id: &TaskId,
+        job_id: AggregationJobId,
+        round: u16,
+        state: &HelperState,
+    ) -> Result<Vec<u8>, Error> {
+        let nonce = thread_rng().gen::<[u8; NONCE_LEN]>();
+        let ciphertext = self
+            .cipher()?
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: &state.get_encoded(),
+                    aad: &associated_data(task_id, job_id, round),
+                },
+            )
+            .map_err(|_| Error::Encryption)?;
+
+        Ok([&nonce[..], &ciphertext].concat())
+    }
+
+    /// Decrypt and decode state previously sealed by [`Self::seal`] for the
+    /// round of the task's aggregation job.
+    pub(crate) fn open(
+        &self,
+        task_id: &TaskId,
+        job_id: AggregationJobId,
+        round: u16,
+        sealed: &[u8],
+    ) -> Result<HelperState, Error> {
+        if sealed.len() < NONCE_LEN {
+            return Err(Error::Decryption);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+        let plaintext = self
+            .cipher()?
+            .decrypt(
+                nonce.into(),
+                Payload {
+                    msg: ciphertext,
+                    aad: &associated_data(task_id, job_id, round),
+                },
+            )
+            .map_err(|_| Error::Decryption)?;
+
+        Ok(HelperState::get_decoded(&plaintext)?)
+    }
+}
+
+fn associated_data(task_id: &TaskId, job_id: AggregationJobId, round: u16) -> Vec<u8> {
+    let mut associated_data = task_id.as_bytes().to_vec();
+    job_id.encode(&mut associated_data);
+    round.encode(&mut associated_data);
+    associated_data
+}
+
+/// A report whose preparation by the helper is in progress
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PendingReport {
+    pub(crate) report_share: ReportShare,
+    /// Encoded prepare messages received from the leader so far, in the order
+    /// they were received
+    pub(crate) prepare_messages: Vec<Vec<u8>>,
+}
+
+impl PendingReport {
+    pub(crate) fn nonce(&self) -> Nonce {
+        self.report_share.nonce
+    }
+}
+
+impl Encode for PendingReport {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.report_share.encode(bytes);
+        (self.prepare_messages.len() as u16).encode(bytes);
+        for message in &self.prepare_messages {
+            encode_u24_items(bytes, &(), message);
+        }
+    }
+}
+
+impl Decode for PendingReport {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        let report_share = ReportShare::decode(bytes)?;
+        let message_count = u16::decode(bytes)?;
+        let prepare_messages = (0..message_count)
+            .map(|_| decode_u24_items(&(), bytes))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            report_share,
+            prepare_messages,
+        })
+    }
+}
+
+/// Helper state for an aggregate job, as carried in `helper_state`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct HelperState {
+    /// Encoded aggregation parameter the job's reports are prepared under
+    pub(crate) aggregation_parameter: Vec<u8>,
+    pub(crate) reports: Vec<PendingReport>,
+}
+
+impl Encode for HelperState {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        encode_u24_items(bytes, &(), &self.aggregation_parameter);
+        encode_u24_items(bytes, &(), &self.reports);
+    }
+}
+
+impl Decode for HelperState {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            aggregation_parameter: decode_u24_items(&(), bytes)?,
+            reports: decode_u24_items(&(), bytes)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hpke::{Ciphertext, ConfigId},
+        Time,
+    };
+    use assert_matches::assert_matches;
+
+    fn helper_state() -> HelperState {
+        HelperState {
+            aggregation_parameter: vec![1, 2],
+            reports: vec![PendingReport {
+                report_share: ReportShare {
+                    nonce: Nonce {
+                        time: Time(100),
+                        rand: 1,
+                    },
+                    extensions: vec![],
+                    encrypted_input_share: Ciphertext {
+                        config_id: ConfigId(1),
+                        encapsulated_context: vec![1, 2, 3],
+                        payload: vec![4, 5, 6],
+                    },
+                },
+                prepare_messages: vec![vec![7, 8], vec![9]],
+            }],
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let key = HelperStateKey::generate();
+        let task_id = TaskId::random();
+
+        let sealed = key
+            .seal(&task_id, AggregationJobId(3), 1, &helper_state())
+            .unwrap();
+        assert_eq!(
+            key.open(&task_id, AggregationJobId(3), 1, &sealed).unwrap(),
+            helper_state()
+        );
+    }
+
+    #[test]
+    fn reject_tampered_state() {
+        let key = HelperStateKey::generate();
+        let task_id = TaskId::random();
+        let job_id = AggregationJobId(3);
+        let sealed = key.seal(&task_id, job_id, 1, &helper_state()).unwrap();
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_matches!(
+            key.open(&task_id, job_id, 1, &tampered),
+            Err(Error::Decryption)
+        );
+
+        // State sealed for one task is useless for another
+        assert_matches!(
+            key.open(&TaskId::random(), job_id, 1, &sealed),
+            Err(Error::Decryption)
+        );
+
+        // Likewise for other jobs and other rounds of the same job
+        assert_matches!(
+            key.open(&task_id, AggregationJobId(4), 1, &sealed),
+            Err(Error::Decryption)
+        );
+        assert_matches!(
+            key.open(&task_id, job_id, 2, &sealed),
+            Err(Error::Decryption)
+        );
+
+        // Nor can the leader, which doesn't know the key, forge state
+        assert_matches!(
+            HelperStateKey::generate().open(&task_id, job_id, 1, &sealed),
+            Err(Error::Decryption)
+        );
+
+        assert_matches!(key.open(&task_id, job_id, 1, &[]), Err(Error::Decryption));
+    }
+}
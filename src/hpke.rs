@@ -350,6 +350,27 @@ impl Config {
             encapsulated_context,
         )
     }
+
+    /// Check that this config's keys are usable, by sealing a message to the
+    /// public key and opening it with the private key.
+    pub fn check_keys(&self) -> Result<(), Error> {
+        let task_id = TaskId::random();
+        let message = b"key check";
+
+        let ciphertext = self
+            .sender(&task_id, Label::InputShare, Role::Client, Role::Leader)?
+            .seal(message, &[])?;
+        self.recipient(
+            &task_id,
+            Label::InputShare,
+            Role::Client,
+            Role::Leader,
+            &ciphertext.encapsulated_context,
+        )?
+        .open(&ciphertext, &[])?;
+
+        Ok(())
+    }
 }
 
 impl Decode for Config {
@@ -627,4 +648,24 @@ mod tests {
 
         assert_eq!(plaintext, message);
     }
+
+    #[test]
+    fn check_keys() {
+        let new_config = || {
+            Config::new_recipient(
+                KeyEncapsulationMechanism::X25519HkdfSha256,
+                KeyDerivationFunction::HkdfSha256,
+                AuthenticatedEncryptionWithAssociatedData::ChaCha20Poly1305,
+            )
+        };
+        let mut config = new_config();
+        config.check_keys().unwrap();
+
+        // A private key that doesn't belong to the public key is no use
+        config.private_key = new_config().private_key;
+        config.check_keys().unwrap_err();
+
+        config.private_key = None;
+        config.check_keys().unwrap_err();
+    }
 }
@@ -4,30 +4,49 @@ use crate::{
         Aggregate, AggregateInitReq, AggregateMessage, AggregateReq, AggregateShareReq, Aggregator,
         ReportShare, Transition, TransitionMessage,
     },
-    collect::{CollectRequest, CollectResponse},
+    aggregation_job::{
+        plan_aggregation_jobs, AggregationDriverConfig, AggregationJob, AggregationJobId,
+        AggregationJobStep,
+    },
+    collect::{CollectJobId, CollectRequest, CollectResponse},
+    differential_privacy::NoisyAggregateShare,
     error::{handle_rejection, response_to_api_problem, IntoHttpApiProblem, ProblemDocumentType},
+    health::{self, Check, Readiness, HELPER_CHECK_TIMEOUT},
     hpke::{self, Ciphertext},
-    parameters::Parameters,
+    metrics::Metrics,
+    parameters::{Parameters, TaskId},
     report::{self, Report},
-    with_shared_value, Interval, Nonce, Role,
+    retention::{should_purge, PurgeCounters, PurgedReports},
+    server::{self, ServerHandle, ShutdownSignal},
+    storage::{Mutation, ReportRecord, ReportRecordState, Store},
+    task::{ServeTasks, Task, TaskRegistry},
+    tls::TlsConfig,
+    with_shared_value, Duration, Interval, Nonce, Role, Time,
 };
 use bytes::Bytes;
-use color_eyre::eyre::Result;
-use http::{Response, StatusCode};
+use color_eyre::eyre::{eyre, Result};
+use futures::{
+    future::{join_all, BoxFuture},
+    FutureExt,
+};
+use http::{header::LOCATION, Response, StatusCode};
 use http_api_problem::HttpApiProblem;
 use prio::{
     codec::{Decode, Encode, ParameterizedDecode},
     vdaf::{self, Aggregator as VdafAggregator, PrepareTransition, VdafError},
 };
 use reqwest::Client;
+use serde::{de::DeserializeOwned, Serialize};
 use std::{
     cmp::Ordering,
+    collections::{BTreeMap, HashSet},
     fmt::Debug,
+    future::Future,
+    io::Cursor,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
-use tokio::sync::Mutex;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, info, warn};
 use warp::{reply, Filter, Rejection};
 
 static LEADER_USER_AGENT: &str = concat!(
@@ -38,6 +57,10 @@ static LEADER_USER_AGENT: &str = concat!(
     "leader"
 );
 
+/// How long the leader keeps a collect job around for the collector to poll
+/// after the job finished or failed
+pub const COLLECT_JOB_TTL: Duration = Duration(60 * 60);
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("VDAF error {0}")]
@@ -53,11 +76,23 @@ pub enum Error {
     #[error("aggregate protocol error {0}")]
     AggregateProtocol(String),
     #[error("helper error {0}")]
-    HelperError(#[source] HttpApiProblem),
+    HelperError(#[source] Box<HttpApiProblem>),
     #[error("Aggregation error {0}")]
     Aggregation(#[from] crate::aggregate::Error),
     #[error("Codec error")]
     Codec(#[from] prio::codec::CodecError),
+    #[error("storage error {0}")]
+    Storage(#[from] crate::storage::Error),
+    #[error("helper response tag does not verify")]
+    HelperAuthentication,
+    #[error("unknown task ID")]
+    UnrecognizedTask(TaskId),
+    #[error("unknown collect job {0}")]
+    UnknownCollectJob(CollectJobId),
+    #[error("collect job failed {0}")]
+    CollectJobFailed(#[source] Box<HttpApiProblem>),
+    #[error("malformed collect request {0}")]
+    MalformedCollectRequest(#[source] prio::codec::CodecError),
 }
 
 impl IntoHttpApiProblem for Error {
@@ -65,23 +100,31 @@ impl IntoHttpApiProblem for Error {
         match self {
             Self::HelperError(_) => Some(ProblemDocumentType::HelperError),
             Self::HelperHttpRequest(_, _) => Some(ProblemDocumentType::HelperError),
+            Self::HelperAuthentication => Some(ProblemDocumentType::HelperError),
             Self::InvalidBatchInterval(_) => Some(ProblemDocumentType::InvalidBatchInterval),
+            Self::UnrecognizedTask(_) => Some(ProblemDocumentType::UnrecognizedTask),
+            Self::UnknownCollectJob(_) => Some(ProblemDocumentType::UnrecognizedMessage),
+            Self::MalformedCollectRequest(_) => Some(ProblemDocumentType::UnrecognizedMessage),
             Self::Aggregation(e) => e.problem_document_type(),
             _ => None,
         }
     }
 
     fn source_problem_document(&self) -> Option<&HttpApiProblem> {
-        if let Self::HelperError(problem_document) = self {
-            Some(problem_document)
-        } else {
-            None
+        match self {
+            Self::HelperError(problem_document) | Self::CollectJobFailed(problem_document) => {
+                Some(problem_document)
+            }
+            _ => None,
         }
     }
 }
 
 #[derive(Clone, Debug)]
 enum StoredReportState<A: vdaf::Aggregator> {
+    /// The report waits for a collect request to supply the aggregation
+    /// parameter to prepare it under.
+    Received,
     Waiting {
         state: A::PrepareStep,
         prepare_message: A::PrepareMessage,
@@ -90,6 +133,7 @@ enum StoredReportState<A: vdaf::Aggregator> {
         output_share: A::OutputShare,
     },
     Accumulated,
+    Failed,
 }
 
 /// In-memory representation of a report stored by the leader
@@ -97,8 +141,11 @@ enum StoredReportState<A: vdaf::Aggregator> {
 pub struct StoredReport<A: vdaf::Aggregator> {
     pub nonce: Nonce,
     state: StoredReportState<A>,
+    pub encrypted_leader_share: Ciphertext,
     pub encrypted_helper_share: Ciphertext,
     pub extensions: Vec<report::Extension>,
+    /// The aggregation job the report has been assigned to, if any
+    pub aggregation_job: Option<AggregationJobId>,
 }
 
 impl<A: vdaf::Aggregator> PartialEq for StoredReport<A> {
@@ -121,149 +168,580 @@ impl<A: vdaf::Aggregator> PartialOrd for StoredReport<A> {
     }
 }
 
-/// Implements endpoints the leader supports and tracks leader state.
+/// Progress of a collect job
+#[derive(Clone, Debug)]
+enum CollectJobState {
+    /// The leader is still obtaining the aggregate shares
+    Pending,
+    Finished(CollectResponse),
+    /// The problem document to hand to the collector
+    Failed(HttpApiProblem),
+}
+
+/// A collect job and the collect request it was created for
+#[derive(Clone, Debug)]
+struct CollectJob {
+    batch_interval: Interval,
+    /// Encoded aggregation parameter of the collect request
+    aggregation_parameter: Vec<u8>,
+    state: CollectJobState,
+    /// When the job finished or failed, if it has
+    finished_at: Option<Time>,
+}
+
+/// Reports received by the leader, the aggregation jobs they are in and the
+/// collect jobs. This is only ever locked briefly, and never across a request
+/// to the helper.
+#[derive(Debug)]
+struct LeaderState<A: vdaf::Aggregator> {
+    /// Reports received by the leader.
+    reports: BTreeMap<Nonce, StoredReport<A>>,
+    /// Aggregation jobs that have yet to finish and that aren't currently
+    /// being run.
+    aggregation_jobs: BTreeMap<AggregationJobId, AggregationJob>,
+    next_aggregation_job_id: u64,
+    /// Collect jobs, so that collectors may keep polling them. Jobs that
+    /// finished or failed are evicted after [`COLLECT_JOB_TTL`].
+    collect_jobs: BTreeMap<CollectJobId, CollectJob>,
+    next_collect_job_id: u64,
+}
+
+/// Implements endpoints the leader supports and tracks leader state. All
+/// methods take `&self` so that uploads, aggregation jobs and collect requests
+/// may be handled concurrently.
 #[derive(Debug)]
 pub struct Leader<A: VdafAggregator + Debug> {
     parameters: Parameters,
     aggregator: Aggregator<A>,
-    /// Reports received by the leader.
-    reports: Vec<StoredReport<A>>,
-    helper_state: Vec<u8>,
+    state: Mutex<LeaderState<A>>,
+    purge_counters: PurgeCounters,
+    /// Held while reports are aggregated under the aggregation parameter of a
+    /// collect request, so that concurrent collect requests don't aggregate
+    /// the same reports twice.
+    collect_lock: tokio::sync::Mutex<()>,
     http_client: Client,
+    /// Durable copy of the reports.
+    store: Arc<dyn Store>,
+    /// Metrics shared by all tasks the leader serves
+    metrics: Arc<Metrics>,
 }
 
-impl<A: VdafAggregator + Debug> Leader<A> {
+impl<A: VdafAggregator + Debug> Leader<A>
+where
+    A::AggregateShare: Serialize + DeserializeOwned + NoisyAggregateShare,
+{
+    /// Construct a leader for the task, restoring any reports and accumulators
+    /// previously written to the task's store. The leader talks to the helper
+    /// with `http_client`.
     pub fn new(
-        parameters: &Parameters,
-        vdaf_aggregator: &A,
-        verify_parameter: &A::VerifyParam,
-        aggregation_parameter: &A::AggregationParam,
+        task: &Task<A>,
         hpke_config: &hpke::Config,
+        http_client: Client,
+        metrics: Arc<Metrics>,
     ) -> Result<Self, Error> {
-        let aggregator = Aggregator::new(
-            Role::Leader,
-            // TODO make leader generic over Vdaf
-            hpke_config,
-            vdaf_aggregator,
-            verify_parameter,
-            aggregation_parameter,
-            parameters,
-        );
+        let aggregator = Aggregator::new(Role::Leader, hpke_config, task, metrics.clone())?;
 
-        Ok(Self {
-            parameters: parameters.clone(),
+        let mut leader = Self {
+            parameters: task.parameters.clone(),
             aggregator,
-            reports: vec![],
-            helper_state: vec![],
-            http_client: Client::builder().user_agent(LEADER_USER_AGENT).build()?,
-        })
+            state: Mutex::new(LeaderState {
+                reports: BTreeMap::new(),
+                aggregation_jobs: BTreeMap::new(),
+                next_aggregation_job_id: 0,
+                collect_jobs: BTreeMap::new(),
+                next_collect_job_id: 0,
+            }),
+            purge_counters: PurgeCounters::default(),
+            collect_lock: tokio::sync::Mutex::new(()),
+            http_client,
+            store: task.store.clone(),
+            metrics,
+        };
+        leader.restore_reports()?;
+
+        Ok(leader)
+    }
+
+    /// Load reports from the store. Prepare steps can't be persisted, so
+    /// reports that were waiting to be aggregated are prepared afresh from the
+    /// leader's input share. Aggregation jobs aren't persisted either, so such
+    /// reports get assigned to new jobs. Reports of a task without an eager
+    /// aggregation parameter wait for the next collect request instead, which
+    /// skips them if they were already aggregated under its parameter.
+    fn restore_reports(&mut self) -> Result<(), Error> {
+        let mut reports = BTreeMap::new();
+
+        for record in self.store.reports()? {
+            // Remember every stored report, including failed ones, so that
+            // none can be uploaded again
+            self.aggregator.record_nonce(record.nonce, &[])?;
+            for aggregation_parameter in &record.aggregation_parameters {
+                self.aggregator
+                    .record_nonce(record.nonce, aggregation_parameter)?;
+            }
+
+            let report_share = record.report_share()?;
+            let encrypted_helper_share = record.encrypted_helper_share()?.ok_or_else(|| {
+                Error::AggregateProtocol(format!(
+                    "stored report {} has no helper share",
+                    record.nonce
+                ))
+            })?;
+
+            let state = match record.state {
+                ReportRecordState::Accumulated => StoredReportState::Accumulated,
+                ReportRecordState::Failed => StoredReportState::Failed,
+                ReportRecordState::Waiting => match self.aggregator.eager_aggregation_parameter() {
+                    None => StoredReportState::Received,
+                    Some(aggregation_parameter) => match self.aggregator.prepare_message(
+                        self.parameters.task_id,
+                        report_share.nonce,
+                        &report_share.extensions,
+                        &report_share.encrypted_input_share,
+                        aggregation_parameter,
+                    ) {
+                        Ok((state, prepare_message)) => StoredReportState::Waiting {
+                            state,
+                            prepare_message,
+                        },
+                        Err(error) => {
+                            warn!(nonce = ?record.nonce, ?error, "dropping stored report");
+                            continue;
+                        }
+                    },
+                },
+            };
+
+            reports.insert(
+                report_share.nonce,
+                StoredReport {
+                    nonce: report_share.nonce,
+                    state,
+                    encrypted_leader_share: report_share.encrypted_input_share,
+                    encrypted_helper_share,
+                    extensions: report_share.extensions,
+                    aggregation_job: None,
+                },
+            );
+        }
+
+        info!(report_count = reports.len(), "restored reports");
+        self.state.get_mut().unwrap().reports = reports;
+
+        Ok(())
     }
 
     #[tracing::instrument(skip(self, report), err)]
-    pub async fn handle_upload(&mut self, report: &Report) -> Result<(), Error> {
+    pub async fn handle_upload(&self, report: &Report) -> Result<(), Error> {
         debug!(?report, "obtained report");
 
-        // TODO reject reports from the future
         // The leader is required to buffer reports while waiting to aggregate them. The
         // leader SHOULD NOT accept reports whose timestamps are too far in the future.
         // Implementors MAY provide for some small leeway, usually no more than a few
         // minutes, to account for clock skew.
+        self.aggregator.check_report_time(report.nonce)?;
+
+        let report_state = match self.aggregator.eager_aggregation_parameter() {
+            Some(aggregation_parameter) => {
+                let (state, prepare_message) = self.aggregator.prepare_message(
+                    report.task_id,
+                    report.nonce,
+                    &report.extensions,
+                    &report.encrypted_input_shares[Role::Leader.index()],
+                    aggregation_parameter,
+                )?;
+                StoredReportState::Waiting {
+                    state,
+                    prepare_message,
+                }
+            }
+            None => {
+                self.aggregator.check_input_share(
+                    report.task_id,
+                    report.nonce,
+                    &report.extensions,
+                    &report.encrypted_input_shares[Role::Leader.index()],
+                )?;
+                StoredReportState::Received
+            }
+        };
 
-        let (step, prepare_message) = self.aggregator.prepare_message(
-            report.task_id,
-            report.nonce,
-            &report.extensions,
-            &report.encrypted_input_shares[Role::Leader.index()],
-        )?;
+        // Only record the nonce of a report we can prepare and store, so that a
+        // client may retry an upload that failed. The nonce is recorded before
+        // storing the report so that a replay can't overwrite its record.
+        self.aggregator.record_nonce(report.nonce, &[])?;
 
-        self.reports.push(StoredReport {
-            nonce: report.nonce,
-            state: StoredReportState::Waiting {
-                state: step,
-                prepare_message,
+        if let Err(error) = self.store.put_report(ReportRecord::new(
+            &ReportShare {
+                nonce: report.nonce,
+                extensions: report.extensions.clone(),
+                encrypted_input_share: report.encrypted_input_shares[Role::Leader.index()].clone(),
             },
-            encrypted_helper_share: report.encrypted_input_shares[Role::Helper.index()].clone(),
-            extensions: report.extensions.clone(),
-        });
+            Some(&report.encrypted_input_shares[Role::Helper.index()]),
+            ReportRecordState::Waiting,
+        )) {
+            self.aggregator.forget_nonce(report.nonce, &[]);
+            return Err(error.into());
+        }
+
+        self.state.lock().unwrap().reports.insert(
+            report.nonce,
+            StoredReport {
+                nonce: report.nonce,
+                state: report_state,
+                encrypted_leader_share: report.encrypted_input_shares[Role::Leader.index()].clone(),
+                encrypted_helper_share: report.encrypted_input_shares[Role::Helper.index()].clone(),
+                extensions: report.extensions.clone(),
+                aggregation_job: None,
+            },
+        );
 
         Ok(())
     }
 
+    /// Assign reports that are waiting to be aggregated and aren't yet part
+    /// of an aggregation job to new jobs, then take all jobs that aren't
+    /// already running so that the caller may run them.
+    fn claim_aggregation_jobs(&self) -> Vec<AggregationJob> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        // Without an eager aggregation parameter, reports are only aggregated
+        // when collected
+        let aggregation_parameter = match self.aggregator.eager_aggregation_parameter() {
+            Some(aggregation_parameter) => aggregation_parameter.get_encoded(),
+            None => return vec![],
+        };
+
+        let unassigned = state
+            .reports
+            .values()
+            .filter(|report| {
+                report.aggregation_job.is_none()
+                    && matches!(report.state, StoredReportState::Waiting { .. })
+            })
+            .map(|report| report.nonce)
+            .collect();
+
+        for job in plan_aggregation_jobs(
+            unassigned,
+            self.parameters.max_aggregation_job_size,
+            &mut state.next_aggregation_job_id,
+            &aggregation_parameter,
+        ) {
+            info!(job = %job.id, report_count = job.nonces.len(), "created aggregation job");
+            for nonce in &job.nonces {
+                if let Some(report) = state.reports.get_mut(nonce) {
+                    report.aggregation_job = Some(job.id);
+                }
+            }
+            state.aggregation_jobs.insert(job.id, job);
+        }
+
+        std::mem::take(&mut state.aggregation_jobs)
+            .into_values()
+            .collect()
+    }
+
+    /// Put new reports into aggregation jobs and run every unfinished job to
+    /// completion, concurrently. A job that fails is restarted from the
+    /// beginning the next time this is called, and doesn't prevent other jobs
+    /// from running. The first error encountered is returned once all jobs
+    /// have run.
     #[tracing::instrument(err, skip(self))]
-    async fn send_aggregate_init_request(&mut self) -> Result<Option<AggregateMessage>, Error> {
-        let report_shares: Vec<ReportShare> = self
+    pub async fn run_aggregation_jobs(&self) -> Result<(), Error> {
+        let jobs = self.claim_aggregation_jobs();
+        let outcomes = join_all(jobs.into_iter().map(|job| self.run_aggregation_job(job))).await;
+
+        let mut result = Ok(());
+        for (mut job, outcome) in outcomes {
+            if let Err(error) = outcome {
+                let restarted = self.restart_aggregation_job(&mut job);
+                warn!(job = %job.id, retries = job.retries, ?error, "aggregation job failed, will retry");
+                self.state
+                    .lock()
+                    .unwrap()
+                    .aggregation_jobs
+                    .insert(job.id, job);
+                // Failing to restart a job is worse than the job failing, but
+                // the other jobs must still be put back before returning
+                let error = restarted.err().unwrap_or(error);
+                if result.is_ok() {
+                    result = Err(error);
+                }
+            }
+        }
+
+        self.aggregator.dump_accumulators();
+        self.purge_reports();
+
+        result
+    }
+
+    /// Purge reports that have been accumulated or have failed and that are no
+    /// longer worth keeping, as described in [`crate::retention`]. Reports
+    /// held for collect requests to aggregate count as accumulated. Failure to
+    /// purge is logged but otherwise ignored, since the reports will be purged
+    /// on a later attempt.
+    fn purge_reports(&self) {
+        self.aggregator.prune_nonce_index();
+
+        let candidates: Vec<_> = self
+            .state
+            .lock()
+            .unwrap()
             .reports
-            .iter()
-            .filter(|stored_report| !matches!(stored_report.state, StoredReportState::Accumulated))
-            .map(|stored_report| ReportShare {
-                nonce: stored_report.nonce,
-                extensions: stored_report.extensions.clone(),
-                encrypted_input_share: stored_report.encrypted_helper_share.clone(),
+            .values()
+            .filter_map(|report| match report.state {
+                StoredReportState::Accumulated => Some((report.nonce, true)),
+                StoredReportState::Failed => Some((report.nonce, false)),
+                StoredReportState::Received => Some((report.nonce, true)),
+                _ => None,
             })
             .collect();
 
-        let aggregate_init_request = AggregateMessage {
-            aggregate: Aggregate::Initialize(AggregateInitReq {
+        let now = self.aggregator.now();
+        let mut purged = PurgedReports::default();
+        let mut nonces = vec![];
+        for (nonce, accumulated) in candidates {
+            if should_purge(
+                &self.parameters,
+                nonce,
+                self.aggregator.batch_closed(nonce),
+                now,
+            ) {
+                if accumulated {
+                    purged.accumulated += 1;
+                } else {
+                    purged.failed += 1;
+                }
+                nonces.push(nonce);
+            }
+        }
+        if nonces.is_empty() {
+            return;
+        }
+
+        if let Err(error) = self.store.delete_reports(&nonces) {
+            warn!(?error, "failed to purge reports");
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        for nonce in &nonces {
+            state.reports.remove(nonce);
+        }
+
+        info!(?purged, "purged reports");
+        self.purge_counters.record(purged);
+    }
+
+    /// Check that the task's store can be written to and that its helper
+    /// serves its HPKE config.
+    async fn readiness_checks(&self) -> Vec<Check> {
+        let task_id = Some(self.parameters.task_id);
+        let helper = match tokio::time::timeout(
+            HELPER_CHECK_TIMEOUT,
+            self.parameters.hpke_config(Role::Helper, &self.http_client),
+        )
+        .await
+        {
+            Ok(hpke_config) => hpke_config.map(|_| ()).map_err(health::Error::from),
+            Err(_) => Err(health::Error::Timeout(HELPER_CHECK_TIMEOUT)),
+        };
+
+        vec![
+            Check::new(
+                "storage",
+                task_id,
+                self.store.check().map_err(health::Error::from),
+            ),
+            Check::new("helper", task_id, helper),
+        ]
+    }
+
+    /// Write any state the store holds back to durable storage.
+    fn flush(&self) -> Result<(), Error> {
+        self.aggregator.dump_accumulators();
+        Ok(self.store.flush()?)
+    }
+
+    /// Total number of reports purged since the leader started.
+    pub fn purged_reports(&self) -> PurgedReports {
+        self.purge_counters.totals()
+    }
+
+    /// Run an aggregation job until it finishes or fails, handing the job back
+    /// along with the outcome.
+    async fn run_aggregation_job(
+        &self,
+        mut job: AggregationJob,
+    ) -> (AggregationJob, Result<(), Error>) {
+        loop {
+            match self.step_aggregation_job(&mut job).await {
+                Ok(true) => continue,
+                Ok(false) => return (job, Ok(())),
+                Err(error) => return (job, Err(error)),
+            }
+        }
+    }
+
+    /// Send the next request of an aggregation job to the helper and handle
+    /// its response. Returns whether the job needs further steps.
+    #[tracing::instrument(err, skip(self, job), fields(job = %job.id))]
+    async fn step_aggregation_job(&self, job: &mut AggregationJob) -> Result<bool, Error> {
+        let aggregate = match &job.next_step {
+            AggregationJobStep::Initialize => {
+                let state = self.state.lock().unwrap();
+                Aggregate::Initialize(AggregateInitReq {
+                    task_id: self.parameters.task_id,
+                    job_id: job.id,
+                    aggregation_parameter: job.aggregation_parameter.clone(),
+                    report_shares: job
+                        .nonces
+                        .iter()
+                        .filter_map(|nonce| state.reports.get(nonce))
+                        .map(|stored_report| ReportShare {
+                            nonce: stored_report.nonce,
+                            extensions: stored_report.extensions.clone(),
+                            encrypted_input_share: stored_report.encrypted_helper_share.clone(),
+                        })
+                        .collect(),
+                })
+            }
+            AggregationJobStep::Continue(transitions) => Aggregate::Request(AggregateReq {
                 task_id: self.parameters.task_id,
-                aggregation_parameter: vec![],
-                report_shares,
+                job_id: job.id,
+                round: job.round,
+                helper_state: job.helper_state.clone(),
+                transitions: transitions.clone(),
             }),
-            // TODO: HMAC
-            tag: [0u8; 32],
         };
+        info!(round = job.round, "sending aggregate request to helper");
+        self.metrics.aggregate_round();
+        let _timer = self.metrics.helper_request_timer("aggregate");
 
         let http_response = self
             .http_client
             .post(self.parameters.aggregate_endpoint()?)
-            .body(aggregate_init_request.get_encoded())
+            .body(
+                AggregateMessage::new(aggregate, &self.parameters.aggregator_auth_key)
+                    .get_encoded(),
+            )
             .send()
             .await?;
         let http_response_status = http_response.status();
 
         if !http_response_status.is_success() {
             return match response_to_api_problem(http_response).await {
-                Ok(document) => Err(Error::HelperError(document)),
+                Ok(document) => Err(Error::HelperError(Box::new(document))),
                 Err(message) => Err(Error::HelperHttpRequest(http_response_status, message)),
             };
         }
 
-        let aggregate_response = AggregateMessage::get_decoded(&http_response.bytes().await?)?;
+        let aggregate_response = self.decode_helper_response(&http_response.bytes().await?)?;
 
-        self.aggregator.dump_accumulators();
+        self.handle_aggregate_resp(job, aggregate_response)
+    }
+
+    /// Restart an aggregation job after it failed. The leader's prepare state
+    /// for the job's reports is recomputed, since the failure may have left it
+    /// out of step with the helper's.
+    fn restart_aggregation_job(&self, job: &mut AggregationJob) -> Result<(), Error> {
+        job.restart();
+        let aggregation_parameter = A::AggregationParam::get_decoded(&job.aggregation_parameter)?;
+
+        let shares: Vec<_> = {
+            let state = self.state.lock().unwrap();
+            job.nonces
+                .iter()
+                .filter_map(|nonce| state.reports.get(nonce))
+                .map(|report| {
+                    (
+                        report.nonce,
+                        report.extensions.clone(),
+                        report.encrypted_leader_share.clone(),
+                    )
+                })
+                .collect()
+        };
 
-        self.handle_aggregate_resp(aggregate_response).await
+        let mut nonces = vec![];
+        let mut report_states = vec![];
+        for (nonce, extensions, encrypted_leader_share) in shares {
+            match self.aggregator.prepare_message(
+                self.parameters.task_id,
+                nonce,
+                &extensions,
+                &encrypted_leader_share,
+                &aggregation_parameter,
+            ) {
+                Ok((state, prepare_message)) => {
+                    report_states.push((
+                        nonce,
+                        StoredReportState::Waiting {
+                            state,
+                            prepare_message,
+                        },
+                    ));
+                    nonces.push(nonce);
+                }
+                Err(error) => {
+                    warn!(?nonce, ?error, "dropping report from restarted job");
+                    report_states.push((nonce, StoredReportState::Failed));
+                }
+            }
+        }
+        job.nonces = nonces;
+
+        self.update_report_states(report_states)
     }
 
-    #[tracing::instrument(err, skip(self, aggregate_req))]
-    async fn send_aggregate_request(
-        &mut self,
-        aggregate_req: &AggregateMessage,
-    ) -> Result<Option<AggregateMessage>, Error> {
-        let http_response = self
-            .http_client
-            .post(self.parameters.aggregate_endpoint()?)
-            .body(aggregate_req.get_encoded())
-            .send()
-            .await?;
-        let http_response_status = http_response.status();
+    /// Decode an aggregate message received from the helper and check its tag.
+    fn decode_helper_response(&self, body: &[u8]) -> Result<AggregateMessage, Error> {
+        let aggregate_message = AggregateMessage::get_decoded(body)?;
+        aggregate_message
+            .verify(&self.parameters.aggregator_auth_key)
+            .map_err(|_| Error::HelperAuthentication)?;
 
-        if !http_response_status.is_success() {
-            return match response_to_api_problem(http_response).await {
-                Ok(document) => Err(Error::HelperError(document)),
-                Err(message) => Err(Error::HelperHttpRequest(http_response_status, message)),
-            };
+        Ok(aggregate_message)
+    }
+
+    /// Set the prepare state of reports, recording failures in the store so
+    /// that failed reports aren't aggregated again. Reports aggregated for a
+    /// collect request stay available to later collect requests, so their
+    /// failures aren't recorded.
+    fn update_report_states(
+        &self,
+        report_states: Vec<(Nonce, StoredReportState<A>)>,
+    ) -> Result<(), Error> {
+        if self.aggregator.eager_aggregation_parameter().is_some() {
+            for (nonce, report_state) in &report_states {
+                if let StoredReportState::Failed = report_state {
+                    self.store
+                        .update_report_state(*nonce, ReportRecordState::Failed)?;
+                }
+            }
         }
 
-        let aggregate_response = AggregateMessage::get_decoded(&http_response.bytes().await?)?;
+        let mut state = self.state.lock().unwrap();
+        for (nonce, report_state) in report_states {
+            if let Some(report) = state.reports.get_mut(&nonce) {
+                report.state = report_state;
+            }
+        }
 
-        self.handle_aggregate_resp(aggregate_response).await
+        Ok(())
     }
 
-    #[tracing::instrument(skip(self, aggregate_response), err)]
-    async fn handle_aggregate_resp(
-        &mut self,
+    /// Handle the helper's response to the last request of an aggregation
+    /// job, advancing the job and its reports. Returns whether the job needs
+    /// further steps.
+    #[tracing::instrument(skip(self, job, aggregate_response), fields(job = %job.id), err)]
+    fn handle_aggregate_resp(
+        &self,
+        job: &mut AggregationJob,
         aggregate_response: AggregateMessage,
-    ) -> Result<Option<AggregateMessage>, Error> {
+    ) -> Result<bool, Error> {
         let aggregate_response = if let Aggregate::Response(resp) = aggregate_response.aggregate {
             resp
         } else {
@@ -272,28 +750,50 @@ impl<A: VdafAggregator + Debug> Leader<A> {
             ));
         };
 
-        if self.reports.len() != aggregate_response.transitions.len() {
-            return Err(Error::AggregateProtocol(format!(
-                "unexpected number of sub-responses in helper aggregate response. Got {} wanted {}",
-                aggregate_response.transitions.len(),
-                self.reports.len()
-            )));
-        }
-        self.helper_state = aggregate_response.helper_state;
-
-        let mut transitions = vec![];
+        let aggregation_parameter = A::AggregationParam::get_decoded(&job.aggregation_parameter)?;
 
-        for (leader_report, helper_transition) in
-            self.reports.iter_mut().zip(aggregate_response.transitions)
-        {
-            // Sub-responses from helper must appear in the same order as the
-            // sub-requests sent by leader
-            if leader_report.nonce != helper_transition.nonce {
+        // Sub-responses from helper must appear in the same order as the
+        // sub-requests sent by leader, though the helper may omit some.
+        let mut unanswered = job.nonces.iter();
+        for helper_transition in &aggregate_response.transitions {
+            if !unanswered.any(|nonce| *nonce == helper_transition.nonce) {
                 return Err(Error::AggregateProtocol(format!(
-                    "helper responses in wrong order. Wanted {}, got {}",
-                    leader_report.nonce, helper_transition.nonce,
+                    "helper responses in wrong order or for unknown report {}",
+                    helper_transition.nonce
                 )));
             }
+        }
+
+        // Work on a copy of the job's reports' prepare state, so that uploads
+        // needn't wait while we process the response.
+        let mut report_states: BTreeMap<Nonce, StoredReportState<A>> = {
+            let state = self.state.lock().unwrap();
+            job.nonces
+                .iter()
+                .filter_map(|nonce| {
+                    state
+                        .reports
+                        .get(nonce)
+                        .map(|report| (*nonce, report.state.clone()))
+                })
+                .collect()
+        };
+        let mut failed = vec![];
+
+        let mut transitions = vec![];
+        let mut answered = HashSet::new();
+
+        for helper_transition in aggregate_response.transitions {
+            answered.insert(helper_transition.nonce);
+            let report_state =
+                report_states
+                    .get_mut(&helper_transition.nonce)
+                    .ok_or_else(|| {
+                        Error::AggregateProtocol(format!(
+                            "no stored report for {}",
+                            helper_transition.nonce
+                        ))
+                    })?;
 
             match helper_transition.transition {
                 Transition::Continued { payload } => {
@@ -301,7 +801,7 @@ impl<A: VdafAggregator + Debug> Leader<A> {
                     let (state, leader_prepare_message) = if let StoredReportState::Waiting {
                         state,
                         prepare_message,
-                    } = &leader_report.state
+                    } = &report_state
                     {
                         (state, prepare_message)
                     } else {
@@ -328,29 +828,30 @@ impl<A: VdafAggregator + Debug> Leader<A> {
                             next_round_state,
                             next_round_prepare_message,
                         ) => {
-                            leader_report.state = StoredReportState::Waiting {
+                            *report_state = StoredReportState::Waiting {
                                 state: next_round_state,
                                 prepare_message: next_round_prepare_message,
                             };
                         }
                         PrepareTransition::Finish(output_share) => {
-                            leader_report.state = StoredReportState::Finished { output_share };
+                            *report_state = StoredReportState::Finished { output_share };
                         }
                         PrepareTransition::Fail(error) => {
                             warn!(
-                                time = ?leader_report.nonce,
+                                time = ?helper_transition.nonce,
                                 ?error,
                                 "proof did not check out for report"
                             );
                             // Process other transitions
+                            failed.push(helper_transition.nonce);
                             continue;
                         }
                     }
 
                     // Send round n prepare message to helper
-                    info!(?leader_report.nonce, "pushing continue transition to helper");
+                    info!(?helper_transition.nonce, "pushing continue transition to helper");
                     transitions.push(TransitionMessage {
-                        nonce: leader_report.nonce,
+                        nonce: helper_transition.nonce,
                         transition: Transition::Continued {
                             payload: prepare_message.get_encoded(),
                         },
@@ -358,71 +859,395 @@ impl<A: VdafAggregator + Debug> Leader<A> {
                 }
                 Transition::Finished => {
                     info!(?helper_transition.nonce, "helper finished");
-                    let output_share = if let StoredReportState::Finished { output_share } =
-                        &leader_report.state
-                    {
-                        output_share
-                    } else {
-                        return Err(Error::AggregateProtocol(
-                            "helper unexpectedly finished".to_string(),
-                        ));
-                    };
+                    let output_share =
+                        if let StoredReportState::Finished { output_share } = &report_state {
+                            output_share
+                        } else {
+                            return Err(Error::AggregateProtocol(
+                                "helper unexpectedly finished".to_string(),
+                            ));
+                        };
 
                     info!("accumulating report");
                     // Helper has confirmed they have accumulated the report. We do the same.
-                    self.aggregator
-                        .accumulate_report(leader_report.nonce, output_share.clone())?;
-
-                    leader_report.state = StoredReportState::Accumulated;
+                    let eager = self.aggregator.eager_aggregation_parameter().is_some();
+                    let mutation = if eager {
+                        Mutation::UpdateReportState {
+                            nonce: helper_transition.nonce,
+                            state: ReportRecordState::Accumulated,
+                        }
+                    } else {
+                        Mutation::AddAggregationParameter {
+                            nonce: helper_transition.nonce,
+                            aggregation_parameter: job.aggregation_parameter.clone(),
+                        }
+                    };
+                    self.aggregator.accumulate_report(
+                        helper_transition.nonce,
+                        &aggregation_parameter,
+                        output_share.clone(),
+                        vec![mutation],
+                    )?;
+
+                    // The report is accumulated for good, so it mustn't be
+                    // sent to the helper again should a later transition fail
+                    // and the job be restarted
+                    *report_state = StoredReportState::Accumulated;
+                    job.nonces.retain(|nonce| *nonce != helper_transition.nonce);
+                    if let Some(report) = self
+                        .state
+                        .lock()
+                        .unwrap()
+                        .reports
+                        .get_mut(&helper_transition.nonce)
+                    {
+                        report.state = StoredReportState::Accumulated;
+                    }
+                    if !eager {
+                        // Remember that the report has been aggregated under
+                        // this parameter, so that it isn't aggregated again
+                        self.aggregator
+                            .record_nonce(helper_transition.nonce, &job.aggregation_parameter)?;
+                    }
                 }
                 Transition::Failed { error } => {
-                    warn!(helper_error = ?error, nonce = ?leader_report.nonce, "helper rejected report");
-                    continue;
+                    warn!(helper_error = ?error, nonce = ?helper_transition.nonce, "helper rejected report");
+                    self.metrics.transition_error(error);
+                    failed.push(helper_transition.nonce);
                 }
             }
         }
 
-        info!("dumping accumulators");
-        self.aggregator.dump_accumulators();
+        // Reports the helper said nothing about won't make progress
+        for nonce in job.nonces.iter().filter(|nonce| !answered.contains(nonce)) {
+            warn!(?nonce, "helper dropped report");
+            failed.push(*nonce);
+        }
+        for nonce in failed {
+            report_states.insert(nonce, StoredReportState::Failed);
+        }
+        self.update_report_states(report_states.into_iter().collect())?;
+
+        job.round += 1;
+        job.helper_state = aggregate_response.helper_state;
+        job.nonces = transitions.iter().map(|t| t.nonce).collect();
 
-        if !transitions.is_empty() {
+        if job.is_finished() {
+            info!(rounds = job.round, "aggregation job finished");
+            Ok(false)
+        } else {
             info!(
                 length = transitions.len(),
                 "building aggregate request to helper"
             );
-            Ok(Some(AggregateMessage {
-                aggregate: Aggregate::Request(AggregateReq {
-                    task_id: self.parameters.task_id,
-                    helper_state: self.helper_state.clone(),
-                    transitions,
-                }),
-                // TODO: HMAC
-                tag: [0u8; 32],
-            }))
-        } else {
-            Ok(None)
+            job.next_step = AggregationJobStep::Continue(transitions);
+            Ok(true)
+        }
+    }
+
+    /// Prepare the reports in the batch interval that haven't yet been
+    /// aggregated under the collect request's aggregation parameter, in
+    /// conjunction with the helper. Only needed for tasks without an eager
+    /// aggregation parameter. The reports are held for later collect requests
+    /// afterwards, whether or not they could be aggregated.
+    #[tracing::instrument(skip(self, aggregation_parameter), err)]
+    async fn aggregate_for_collect(
+        &self,
+        batch_interval: Interval,
+        aggregation_parameter: &A::AggregationParam,
+    ) -> Result<(), Error> {
+        if !self.parameters.validate_batch_interval(batch_interval) {
+            return Err(Error::InvalidBatchInterval(batch_interval));
+        }
+
+        let _collect_guard = self.collect_lock.lock().await;
+        let encoded_aggregation_parameter = aggregation_parameter.get_encoded();
+
+        let shares: Vec<_> = {
+            let state = self.state.lock().unwrap();
+            state
+                .reports
+                .values()
+                .filter(|report| {
+                    matches!(report.state, StoredReportState::Received)
+                        && batch_interval.contains(report.nonce.time)
+                        && !self
+                            .aggregator
+                            .nonce_seen(report.nonce, &encoded_aggregation_parameter)
+                })
+                .map(|report| {
+                    (
+                        report.nonce,
+                        report.extensions.clone(),
+                        report.encrypted_leader_share.clone(),
+                    )
+                })
+                .collect()
+        };
+
+        let mut report_states = vec![];
+        for (nonce, extensions, encrypted_leader_share) in shares {
+            match self.aggregator.prepare_message(
+                self.parameters.task_id,
+                nonce,
+                &extensions,
+                &encrypted_leader_share,
+                aggregation_parameter,
+            ) {
+                Ok((state, prepare_message)) => report_states.push((
+                    nonce,
+                    StoredReportState::Waiting {
+                        state,
+                        prepare_message,
+                    },
+                )),
+                Err(error) => warn!(?nonce, ?error, "could not prepare report for collection"),
+            }
+        }
+        let nonces: Vec<_> = report_states.iter().map(|(nonce, _)| *nonce).collect();
+
+        // These jobs are run right here rather than by the aggregation driver
+        let jobs = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            let jobs = plan_aggregation_jobs(
+                nonces.clone(),
+                self.parameters.max_aggregation_job_size,
+                &mut state.next_aggregation_job_id,
+                &encoded_aggregation_parameter,
+            );
+            for job in &jobs {
+                info!(job = %job.id, report_count = job.nonces.len(), "created aggregation job for collect request");
+                for nonce in &job.nonces {
+                    if let Some(report) = state.reports.get_mut(nonce) {
+                        report.aggregation_job = Some(job.id);
+                    }
+                }
+            }
+            for (nonce, report_state) in report_states {
+                if let Some(report) = state.reports.get_mut(&nonce) {
+                    report.state = report_state;
+                }
+            }
+            jobs
+        };
+
+        let outcomes = join_all(jobs.into_iter().map(|job| self.run_aggregation_job(job))).await;
+
+        {
+            let mut state = self.state.lock().unwrap();
+            for nonce in &nonces {
+                if let Some(report) = state.reports.get_mut(nonce) {
+                    report.state = StoredReportState::Received;
+                    report.aggregation_job = None;
+                }
+            }
+        }
+
+        for (job, outcome) in outcomes {
+            if let Err(error) = outcome {
+                warn!(job = %job.id, ?error, "aggregation job for collect request failed");
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check a collect request and create a collect job for it, to be run with
+    /// [`Self::run_collect_job`]. If a job for the same batch interval and
+    /// aggregation parameter is under way or has finished, that job is
+    /// returned instead, so that collectors retrying a request don't consume
+    /// privacy budget again. The returned flag tells whether the job is new
+    /// and so must be run.
+    pub fn create_collect_job(
+        &self,
+        collect_request: &CollectRequest<A>,
+    ) -> Result<(CollectJobId, bool), Error> {
+        self.metrics.collect_request();
+        if !self
+            .parameters
+            .validate_batch_interval(collect_request.batch_interval)
+        {
+            return Err(Error::InvalidBatchInterval(collect_request.batch_interval));
+        }
+        let aggregation_parameter = collect_request.aggregation_parameter.get_encoded();
+        self.aggregator
+            .validate_aggregation_parameter(&aggregation_parameter)?;
+
+        let mut state = self.state.lock().unwrap();
+        self.evict_collect_jobs(&mut state);
+
+        // Failed jobs may be retried with a new job
+        if let Some((collect_job_id, _)) = state.collect_jobs.iter().find(|(_, job)| {
+            job.batch_interval == collect_request.batch_interval
+                && job.aggregation_parameter == aggregation_parameter
+                && !matches!(job.state, CollectJobState::Failed(_))
+        }) {
+            info!(%collect_job_id, batch_interval = ?collect_request.batch_interval, "found existing collect job");
+            return Ok((*collect_job_id, false));
+        }
+
+        let collect_job_id = CollectJobId(state.next_collect_job_id);
+        state.next_collect_job_id += 1;
+        state.collect_jobs.insert(
+            collect_job_id,
+            CollectJob {
+                batch_interval: collect_request.batch_interval,
+                aggregation_parameter,
+                state: CollectJobState::Pending,
+                finished_at: None,
+            },
+        );
+        info!(%collect_job_id, batch_interval = ?collect_request.batch_interval, "created collect job");
+
+        Ok((collect_job_id, true))
+    }
+
+    /// Forget collect jobs that finished or failed more than
+    /// [`COLLECT_JOB_TTL`] ago.
+    fn evict_collect_jobs(&self, state: &mut LeaderState<A>) {
+        let now = self.aggregator.now();
+        state.collect_jobs.retain(|collect_job_id, job| {
+            let keep = job.finished_at.map_or(true, |finished_at| {
+                finished_at.0.saturating_add(COLLECT_JOB_TTL.0) > now.0
+            });
+            if !keep {
+                debug!(%collect_job_id, "evicting collect job");
+            }
+            keep
+        });
+    }
+
+    /// Obtain the aggregate shares for the collect job, recording the outcome
+    /// for the collector to poll.
+    pub async fn run_collect_job(
+        &self,
+        collect_job_id: CollectJobId,
+        collect_request: &CollectRequest<A>,
+    ) {
+        let outcome = self.handle_collect(collect_request).await;
+        self.finish_collect_job(collect_job_id, outcome);
+    }
+
+    /// Record the outcome of a collect job for the collector to poll.
+    fn finish_collect_job(
+        &self,
+        collect_job_id: CollectJobId,
+        outcome: Result<CollectResponse, Error>,
+    ) {
+        let job_state = match outcome {
+            Ok(collect_response) => {
+                info!(%collect_job_id, "collect job finished");
+                CollectJobState::Finished(collect_response)
+            }
+            Err(error) => {
+                warn!(%collect_job_id, ?error, "collect job failed");
+                CollectJobState::Failed(error.problem_document(Some(&self.parameters), "collect"))
+            }
+        };
+
+        let finished_at = self.aggregator.now();
+        if let Some(job) = self
+            .state
+            .lock()
+            .unwrap()
+            .collect_jobs
+            .get_mut(&collect_job_id)
+        {
+            job.state = job_state;
+            job.finished_at = Some(finished_at);
+        }
+    }
+
+    /// The aggregate shares obtained by the collect job, or `None` if the job
+    /// hasn't finished yet. Polling a job any number of times doesn't consume
+    /// any privacy budget.
+    pub fn poll_collect_job(
+        &self,
+        collect_job_id: CollectJobId,
+    ) -> Result<Option<CollectResponse>, Error> {
+        let mut state = self.state.lock().unwrap();
+        self.evict_collect_jobs(&mut state);
+
+        match state
+            .collect_jobs
+            .get(&collect_job_id)
+            .map(|job| &job.state)
+        {
+            None => Err(Error::UnknownCollectJob(collect_job_id)),
+            Some(CollectJobState::Pending) => Ok(None),
+            Some(CollectJobState::Finished(collect_response)) => Ok(Some(collect_response.clone())),
+            Some(CollectJobState::Failed(problem_document)) => {
+                Err(Error::CollectJobFailed(Box::new(problem_document.clone())))
+            }
         }
     }
 
     #[tracing::instrument(skip(self, collect_request), err)]
-    pub async fn handle_collect(
-        &mut self,
+    async fn handle_collect(
+        &self,
         collect_request: &CollectRequest<A>,
     ) -> Result<CollectResponse, Error> {
+        if self.aggregator.eager_aggregation_parameter().is_none() {
+            self.aggregate_for_collect(
+                collect_request.batch_interval,
+                &collect_request.aggregation_parameter,
+            )
+            .await?;
+        }
+        let aggregation_parameter = collect_request.aggregation_parameter.get_encoded();
+
         // Extract own aggregate share. We do this before requesting the helper's aggregate share
         // because it also does request validation.
-        let leader_aggregate_share = self
-            .aggregator
-            .extract_aggregate_share(collect_request.task_id, collect_request.batch_interval)?;
+        let (leader_aggregate_share, release) = self.aggregator.extract_aggregate_share(
+            collect_request.task_id,
+            collect_request.batch_interval,
+            &aggregation_parameter,
+        )?;
+
+        // Our share is only released along with the helper's, so the privacy
+        // budget is only consumed if the helper comes through. The helper has
+        // already committed its budget once it responds, so it stays spent
+        // even if committing ours fails below (see
+        // `Helper::handle_aggregate_share`).
+        let helper_aggregate_share = match self
+            .helper_aggregate_share(collect_request.batch_interval, aggregation_parameter)
+            .await
+        {
+            Ok(helper_aggregate_share) => helper_aggregate_share,
+            Err(error) => {
+                self.aggregator.roll_back_release(release);
+                return Err(error);
+            }
+        };
+        self.aggregator.commit_release(release)?;
+
+        // Reports in the interval may no longer be needed
+        self.purge_reports();
+
+        // Ship encrypted aggregate shares to collector
+        Ok(CollectResponse {
+            encrypted_agg_shares: vec![leader_aggregate_share, helper_aggregate_share],
+        })
+    }
 
-        // Request aggregate share from the helper
-        let aggregate_message = AggregateMessage {
-            aggregate: Aggregate::ShareRequest(AggregateShareReq {
+    /// Request the helper's aggregate share over the batch interval under the
+    /// encoded aggregation parameter.
+    async fn helper_aggregate_share(
+        &self,
+        batch_interval: Interval,
+        aggregation_parameter: Vec<u8>,
+    ) -> Result<Ciphertext, Error> {
+        let aggregate_message = AggregateMessage::new(
+            Aggregate::ShareRequest(AggregateShareReq {
                 task_id: self.parameters.task_id,
-                batch_interval: collect_request.batch_interval,
+                batch_interval,
+                aggregation_parameter,
             }),
-            tag: [0u8; 32],
-        };
+            &self.parameters.aggregator_auth_key,
+        );
+        let _timer = self.metrics.helper_request_timer("aggregate_share");
 
         let http_response = self
             .http_client
@@ -434,146 +1259,570 @@ impl<A: VdafAggregator + Debug> Leader<A> {
 
         if !http_response_status.is_success() {
             return match response_to_api_problem(http_response).await {
-                Ok(document) => Err(Error::HelperError(document)),
+                Ok(document) => Err(Error::HelperError(Box::new(document))),
                 Err(message) => Err(Error::HelperHttpRequest(http_response_status, message)),
             };
         }
 
-        let aggregate_response = AggregateMessage::get_decoded(&http_response.bytes().await?)?;
+        let aggregate_response = self.decode_helper_response(&http_response.bytes().await?)?;
 
-        // Ship encrypted aggregate shares to collector
         match aggregate_response.aggregate {
-            Aggregate::ShareResponse(helper_ciphertext) => Ok(CollectResponse {
-                encrypted_agg_shares: vec![leader_aggregate_share, helper_ciphertext],
-            }),
-            message => {
-                return Err(Error::AggregateProtocol(format!(
-                    "helper unexpectedly did not provide share response: {message:?}"
-                )))
-            }
+            Aggregate::ShareResponse(helper_ciphertext) => Ok(helper_ciphertext),
+            message => Err(Error::AggregateProtocol(format!(
+                "helper unexpectedly did not provide share response: {message:?}"
+            ))),
         }
     }
 }
 
-#[tracing::instrument(
-    skip(
-        ppm_parameters,
-        vdaf_aggregator,
-        verify_parameter,
-        aggregation_parameter,
-        hpke_config
-    ),
-    err
-)]
-pub async fn run_leader<A>(
-    ppm_parameters: &Parameters,
-    vdaf_aggregator: &A,
-    verify_parameter: &A::VerifyParam,
-    aggregation_parameter: &A::AggregationParam,
-    hpke_config: &hpke::Config,
-) -> Result<()>
+/// A [`Leader`] with its VDAF erased, so that one leader can serve tasks
+/// using different VDAFs.
+trait TaskLeader: Debug + Send + Sync {
+    fn parameters(&self) -> &Parameters;
+
+    fn handle_upload<'a>(&'a self, report: &'a Report) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn run_aggregation_jobs(&self) -> BoxFuture<'_, Result<(), Error>>;
+
+    /// Decode an encoded collect request and create a collect job for it, as
+    /// [`Leader::create_collect_job`] does.
+    fn create_collect_job(&self, collect_request: &[u8]) -> Result<(CollectJobId, bool), Error>;
+
+    /// Run the collect job created for the encoded collect request.
+    fn run_collect_job<'a>(
+        &'a self,
+        collect_job_id: CollectJobId,
+        collect_request: &'a [u8],
+    ) -> BoxFuture<'a, ()>;
+
+    fn poll_collect_job(
+        &self,
+        collect_job_id: CollectJobId,
+    ) -> Result<Option<CollectResponse>, Error>;
+
+    fn readiness_checks(&self) -> BoxFuture<'_, Vec<Check>>;
+
+    fn flush(&self) -> Result<(), Error>;
+}
+
+impl<A> TaskLeader for Leader<A>
 where
-    A: vdaf::Aggregator + 'static + Send + Sync,
+    A: vdaf::Aggregator + Debug + 'static + Send + Sync,
     A::VerifyParam: Send + Sync,
     A::AggregationParam: Send + Sync,
     A::PrepareStep: Send + Sync,
-    A::AggregateShare: Send + Sync,
+    A::AggregateShare: Send + Sync + Serialize + DeserializeOwned + NoisyAggregateShare,
     A::PrepareMessage: Send + Sync,
     A::OutputShare: Send + Sync,
 {
-    let port = ppm_parameters.aggregator_endpoints[Role::Leader.index()]
-        .port()
-        .unwrap_or(80);
-    let hpke_config_endpoint = hpke_config.warp_endpoint()?;
-
-    let leader_aggregator = Arc::new(Mutex::new(Leader::new(
-        ppm_parameters,
-        vdaf_aggregator,
-        verify_parameter,
-        aggregation_parameter,
-        hpke_config,
-    )?));
-
-    let upload = warp::post()
-        .and(warp::path("upload"))
-        .and(warp::body::bytes())
-        .and(with_shared_value(leader_aggregator.clone()))
-        .and_then(|body: Bytes, leader: Arc<Mutex<Leader<_>>>| async move {
-            let mut leader = leader.lock().await;
-
-            let report = Report::get_decoded(&body).map_err(|e| {
-                warp::reject::custom(e.problem_document(Some(&leader.parameters), "upload"))
-            })?;
+    fn parameters(&self) -> &Parameters {
+        &self.parameters
+    }
 
-            leader.handle_upload(&report).await.map_err(|e| {
-                warp::reject::custom(e.problem_document(Some(&leader.parameters), "upload"))
-            })?;
+    fn handle_upload<'a>(&'a self, report: &'a Report) -> BoxFuture<'a, Result<(), Error>> {
+        Leader::handle_upload(self, report).boxed()
+    }
 
-            Ok(reply::with_status(warp::reply(), StatusCode::OK)) as Result<_, Rejection>
-        })
-        .with(warp::trace::named("upload"));
-
-    let aggregate = warp::post()
-        .and(warp::path("aggregate"))
-        .and(with_shared_value(leader_aggregator.clone()))
-        .and_then(|leader: Arc<Mutex<Leader<_>>>| async move {
-            let mut leader = leader.lock().await;
-
-            let mut next_aggregate_message =
-                leader.send_aggregate_init_request().await.map_err(|e| {
-                    warp::reject::custom(e.problem_document(Some(&leader.parameters), "aggregate"))
-                })?;
-
-            while let Some(message) = &next_aggregate_message {
-                next_aggregate_message =
-                    leader.send_aggregate_request(message).await.map_err(|e| {
+    fn run_aggregation_jobs(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Leader::run_aggregation_jobs(self).boxed()
+    }
+
+    fn create_collect_job(&self, collect_request: &[u8]) -> Result<(CollectJobId, bool), Error> {
+        let collect_request =
+            CollectRequest::get_decoded(collect_request).map_err(Error::MalformedCollectRequest)?;
+        Leader::create_collect_job(self, &collect_request)
+    }
+
+    fn run_collect_job<'a>(
+        &'a self,
+        collect_job_id: CollectJobId,
+        collect_request: &'a [u8],
+    ) -> BoxFuture<'a, ()> {
+        async move {
+            match CollectRequest::get_decoded(collect_request) {
+                Ok(collect_request) => {
+                    Leader::run_collect_job(self, collect_job_id, &collect_request).await
+                }
+                Err(error) => self
+                    .finish_collect_job(collect_job_id, Err(Error::MalformedCollectRequest(error))),
+            }
+        }
+        .boxed()
+    }
+
+    fn poll_collect_job(
+        &self,
+        collect_job_id: CollectJobId,
+    ) -> Result<Option<CollectResponse>, Error> {
+        Leader::poll_collect_job(self, collect_job_id)
+    }
+
+    fn readiness_checks(&self) -> BoxFuture<'_, Vec<Check>> {
+        Leader::readiness_checks(self).boxed()
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Leader::flush(self)
+    }
+}
+
+/// Constructs the leader of a task once the HTTP client and metrics it shares
+/// with the leaders of other tasks exist.
+type LeaderConstructor = Box<
+    dyn FnOnce(&hpke::Config, Client, Arc<Metrics>) -> Result<Box<dyn TaskLeader>, Error> + Send,
+>;
+
+/// Look up the leader for a task, or construct a rejection with a problem
+/// document if the task is not served by this leader.
+fn task_leader<'a>(
+    leaders: &'a TaskRegistry<Box<dyn TaskLeader>>,
+    task_id: &TaskId,
+    endpoint: &'static str,
+) -> Result<&'a dyn TaskLeader, Rejection> {
+    leaders.get(task_id).map(AsRef::as_ref).ok_or_else(|| {
+        warp::reject::custom(Error::UnrecognizedTask(*task_id).problem_document(None, endpoint))
+    })
+}
+
+/// Decode an uploaded report and hand it to the leader of its task, or
+/// construct a problem document if the report is rejected.
+async fn upload_report(
+    leaders: &TaskRegistry<Box<dyn TaskLeader>>,
+    body: &[u8],
+) -> Result<(), HttpApiProblem> {
+    let report = Report::get_decoded(body).map_err(|e| e.problem_document(None, "upload"))?;
+
+    let leader = leaders
+        .get(&report.task_id)
+        .ok_or_else(|| Error::UnrecognizedTask(report.task_id).problem_document(None, "upload"))?;
+
+    leader
+        .handle_upload(&report)
+        .await
+        .map_err(|e| e.problem_document(Some(leader.parameters()), "upload"))
+}
+
+/// Periodically run the aggregation jobs of every task, concurrently, backing
+/// off while the helper keeps failing.
+async fn drive_aggregation(
+    leaders: Arc<TaskRegistry<Box<dyn TaskLeader>>>,
+    config: AggregationDriverConfig,
+    shutdown: ShutdownSignal,
+) {
+    let mut consecutive_failures: u32 = 0;
+
+    loop {
+        // A round that has started is run to completion
+        tokio::select! {
+            _ = tokio::time::sleep(config.delay(consecutive_failures)) => {}
+            _ = shutdown.clone() => {
+                info!("stopping scheduled aggregation");
+                return;
+            }
+        }
+
+        let mut failed = false;
+        let outcomes = join_all(
+            leaders
+                .iter()
+                .map(|leader| async move { (leader, leader.run_aggregation_jobs().await) }),
+        )
+        .await;
+        for (leader, outcome) in outcomes {
+            if let Err(error) = outcome {
+                warn!(task_id = %leader.parameters().task_id, ?error, "scheduled aggregation failed");
+                failed = true;
+            }
+        }
+
+        consecutive_failures = if failed {
+            consecutive_failures.saturating_add(1)
+        } else {
+            0
+        };
+    }
+}
+
+/// Builds a leader serving a set of tasks. By default, the leader listens on
+/// `0.0.0.0` at the port of the leader endpoint of the first task, and only
+/// aggregates reports when asked to by a request to its `/aggregate` route.
+/// Tasks may use different VDAFs.
+pub struct LeaderBuilder {
+    tasks: Vec<(Parameters, LeaderConstructor)>,
+    hpke_config: hpke::Config,
+    aggregation_driver: Option<AggregationDriverConfig>,
+    address: Option<SocketAddr>,
+    tls: Option<TlsConfig>,
+    shutdown_signal: Option<BoxFuture<'static, ()>>,
+}
+
+impl LeaderBuilder {
+    pub fn new(hpke_config: &hpke::Config) -> Self {
+        Self {
+            tasks: Vec::new(),
+            hpke_config: hpke_config.clone(),
+            aggregation_driver: None,
+            address: None,
+            tls: None,
+            shutdown_signal: None,
+        }
+    }
+
+    /// Serve `tasks`, which need not use the same VDAF as tasks added
+    /// otherwise.
+    pub fn tasks<A>(self, tasks: Vec<Task<A>>) -> Self
+    where
+        A: vdaf::Aggregator + Debug + 'static + Send + Sync,
+        A::VerifyParam: Send + Sync,
+        A::AggregationParam: Send + Sync,
+        A::PrepareStep: Send + Sync,
+        A::AggregateShare: Send + Sync + Serialize + DeserializeOwned + NoisyAggregateShare,
+        A::PrepareMessage: Send + Sync,
+        A::OutputShare: Send + Sync,
+    {
+        tasks.into_iter().fold(self, Self::task)
+    }
+
+    /// Serve `task`, which need not use the same VDAF as the other tasks.
+    pub fn task<A>(mut self, task: Task<A>) -> Self
+    where
+        A: vdaf::Aggregator + Debug + 'static + Send + Sync,
+        A::VerifyParam: Send + Sync,
+        A::AggregationParam: Send + Sync,
+        A::PrepareStep: Send + Sync,
+        A::AggregateShare: Send + Sync + Serialize + DeserializeOwned + NoisyAggregateShare,
+        A::PrepareMessage: Send + Sync,
+        A::OutputShare: Send + Sync,
+    {
+        self.tasks.push((
+            task.parameters.clone(),
+            Box::new(move |hpke_config, http_client, metrics| {
+                Ok(Box::new(Leader::new(&task, hpke_config, http_client, metrics)?) as _)
+            }),
+        ));
+        self
+    }
+
+    /// Aggregate reports on the schedule set by `config`.
+    pub fn aggregation_driver(mut self, config: AggregationDriverConfig) -> Self {
+        self.aggregation_driver = Some(config);
+        self
+    }
+
+    /// Listen on `address`. Port 0 picks an unused port, which
+    /// [`ServerHandle::local_addr`] reports.
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Serve HTTPS with the certificate in `tls`. The leader also presents that
+    /// certificate to helpers asking for one, and trusts the certificates `tls`
+    /// trusts when connecting to them.
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Shut down once `signal` resolves.
+    pub fn shutdown_signal<F: Future<Output = ()> + Send + 'static>(mut self, signal: F) -> Self {
+        self.shutdown_signal = Some(signal.boxed());
+        self
+    }
+
+    /// Start serving in the background. Must be called from within a Tokio
+    /// runtime.
+    pub fn start(mut self) -> Result<ServerHandle> {
+        let (shutdown_sender, shutdown) = server::shutdown_signal(self.shutdown_signal.take());
+        let (local_addr, server) = self.bind(shutdown)?;
+
+        Ok(ServerHandle::new(
+            local_addr,
+            shutdown_sender,
+            tokio::spawn(server),
+        ))
+    }
+
+    /// Serve until the shutdown signal resolves.
+    pub async fn run(mut self) -> Result<()> {
+        let (_shutdown_sender, shutdown) = server::shutdown_signal(self.shutdown_signal.take());
+        let (_, server) = self.bind(shutdown)?;
+
+        server.await
+    }
+
+    /// Bind the leader's address, returning the address and a future that
+    /// serves requests until `shutdown` resolves. The future then waits for
+    /// scheduled aggregation and collect jobs under way, and flushes the
+    /// stores of all tasks.
+    #[tracing::instrument(skip(self, shutdown), err)]
+    fn bind(
+        self,
+        shutdown: ShutdownSignal,
+    ) -> Result<(SocketAddr, impl Future<Output = Result<()>>)> {
+        let tasks = self.tasks;
+        let hpke_config = &self.hpke_config;
+        let address = match self.address {
+            Some(address) => address,
+            None => {
+                let (parameters, _) = tasks
+                    .first()
+                    .ok_or_else(|| eyre!("leader must serve at least one task"))?;
+                let port = parameters.aggregator_endpoints[Role::Leader.index()]
+                    .port()
+                    .unwrap_or(80);
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port)
+            }
+        };
+        // Collect jobs hold this for reading while they run
+        let collect_jobs_running = Arc::new(tokio::sync::RwLock::new(()));
+
+        let hpke_config_endpoint = hpke_config.warp_endpoint()?;
+        let metrics = Arc::new(Metrics::new()?);
+
+        let mut http_client = Client::builder().user_agent(LEADER_USER_AGENT);
+        if let Some(tls) = &self.tls {
+            http_client = tls.configure_client(http_client)?;
+        }
+        let http_client = http_client.build()?;
+
+        let task_count = tasks.len();
+        let leaders: Arc<TaskRegistry<Box<dyn TaskLeader>>> = Arc::new(TaskRegistry::new(
+            tasks
+                .into_iter()
+                .map(|(parameters, constructor)| {
+                    Ok((
+                        parameters.task_id,
+                        constructor(hpke_config, http_client.clone(), metrics.clone())?,
+                    ))
+                })
+                .collect::<Result<Vec<_>, Error>>()?,
+        )?);
+
+        let upload = warp::post()
+            .and(warp::path("upload"))
+            .and(warp::body::bytes())
+            .and(with_shared_value(leaders.clone()))
+            .and(with_shared_value(metrics.clone()))
+            .and_then(
+                |body: Bytes,
+                 leaders: Arc<TaskRegistry<Box<dyn TaskLeader>>>,
+                 metrics: Arc<Metrics>| async move {
+                    let outcome = upload_report(&leaders, &body).await;
+                    metrics.upload(outcome.as_ref().err());
+                    outcome.map_err(warp::reject::custom)?;
+
+                    Ok(reply::with_status(warp::reply(), StatusCode::OK)) as Result<_, Rejection>
+                },
+            )
+            .with(warp::trace::named("upload"));
+
+        let aggregate = warp::post()
+            .and(warp::path("aggregate"))
+            .and(with_shared_value(leaders.clone()))
+            .and_then(
+                |leaders: Arc<TaskRegistry<Box<dyn TaskLeader>>>| async move {
+                    // Run the aggregation jobs of each task in turn
+                    for leader in leaders.iter() {
+                        leader.run_aggregation_jobs().await.map_err(|e| {
+                            warp::reject::custom(
+                                e.problem_document(Some(leader.parameters()), "aggregate"),
+                            )
+                        })?;
+                    }
+
+                    Ok(reply::with_status(warp::reply(), StatusCode::OK)) as Result<_, Rejection>
+                },
+            )
+            .with(warp::trace::named("aggregate"));
+
+        let collect = warp::post()
+            .and(warp::path("collect"))
+            .and(warp::body::bytes())
+            .and(with_shared_value(leaders.clone()))
+            .and(with_shared_value(collect_jobs_running.clone()))
+            .and_then(
+                |body: Bytes,
+                 leaders: Arc<TaskRegistry<Box<dyn TaskLeader>>>,
+                 collect_jobs_running: Arc<tokio::sync::RwLock<()>>| async move {
+                    // The collect request can only be decoded once its task,
+                    // and so its VDAF, is known
+                    let task_id = TaskId::decode(&mut Cursor::new(&body))
+                        .map_err(|e| warp::reject::custom(e.problem_document(None, "collect")))?;
+
+                    let leader = task_leader(&leaders, &task_id, "collect")?;
+
+                    let (collect_job_id, created) =
+                        leader.create_collect_job(&body).map_err(|e| {
+                            warp::reject::custom(
+                                e.problem_document(Some(leader.parameters()), "collect"),
+                            )
+                        })?;
+                    let collect_job_uri = leader
+                        .parameters()
+                        .collect_job_uri(collect_job_id)
+                        .map_err(|e| {
+                            warp::reject::custom(
+                                Error::from(e)
+                                    .problem_document(Some(leader.parameters()), "collect"),
+                            )
+                        })?;
+
+                    // Talking to the helper may take a while, so the job runs in
+                    // the background while the collector polls it
+                    if created {
+                        let job_leaders = leaders.clone();
+                        let running = collect_jobs_running.read_owned().await;
+                        tokio::spawn(async move {
+                            let _running = running;
+                            if let Some(leader) = job_leaders.get(&task_id) {
+                                leader.run_collect_job(collect_job_id, &body).await;
+                            }
+                        });
+                    }
+
+                    let response = Response::builder()
+                        .status(StatusCode::SEE_OTHER)
+                        .header(LOCATION, collect_job_uri.as_str())
+                        .body(vec![])
+                        .map_err(|e| {
+                            warp::reject::custom(
+                                e.problem_document(Some(leader.parameters()), "collect"),
+                            )
+                        })?;
+
+                    Ok(response) as Result<_, Rejection>
+                },
+            )
+            .with(warp::trace::named("collect"));
+
+        let collect_job = warp::get()
+            .and(warp::path!("collect_jobs" / TaskId / CollectJobId))
+            .and(with_shared_value(leaders.clone()))
+            .and_then(
+                |task_id: TaskId,
+                 collect_job_id: CollectJobId,
+                 leaders: Arc<TaskRegistry<Box<dyn TaskLeader>>>| async move {
+                    let leader = task_leader(&leaders, &task_id, "collect")?;
+
+                    let response = match leader.poll_collect_job(collect_job_id).map_err(|e| {
                         warp::reject::custom(
-                            e.problem_document(Some(&leader.parameters), "aggregate"),
+                            e.problem_document(Some(leader.parameters()), "collect"),
+                        )
+                    })? {
+                        Some(collect_response) => Response::builder()
+                            .status(StatusCode::OK)
+                            .body(collect_response.get_encoded()),
+                        None => Response::builder()
+                            .status(StatusCode::ACCEPTED)
+                            .body(vec![]),
+                    }
+                    .map_err(|e| {
+                        warp::reject::custom(
+                            e.problem_document(Some(leader.parameters()), "collect"),
                         )
                     })?;
-            }
 
-            Ok(reply::with_status(warp::reply(), StatusCode::OK)) as Result<_, Rejection>
-        })
-        .with(warp::trace::named("aggregate"));
+                    Ok(response) as Result<_, Rejection>
+                },
+            )
+            .with(warp::trace::named("collect_job"));
+
+        let readyz = warp::get()
+            .and(warp::path("readyz"))
+            .and(with_shared_value(leaders.clone()))
+            .and(with_shared_value(hpke_config.clone()))
+            .and_then(
+                |leaders: Arc<TaskRegistry<Box<dyn TaskLeader>>>, hpke_config: hpke::Config| async move {
+                    let task_checks =
+                        join_all(leaders.iter().map(|leader| leader.readiness_checks())).await;
+
+                    Ok(Readiness::new(
+                        &hpke_config,
+                        leaders.iter().count(),
+                        task_checks.into_iter().flatten().collect(),
+                    )) as Result<_, Rejection>
+                },
+            )
+            .with(warp::trace::named("readyz"));
+
+        let routes = hpke_config_endpoint
+            .or(health::healthz_endpoint())
+            .or(readyz)
+            .or(metrics.warp_endpoint())
+            .or(upload)
+            .or(aggregate)
+            .or(collect)
+            .or(collect_job)
+            .recover(handle_rejection)
+            .with(warp::trace::request());
+
+        let (local_addr, server) =
+            server::serve(routes, address, self.tls.as_ref(), shutdown.clone())?;
+        info!(task_count, "leader serving on {}", local_addr);
+
+        let aggregation_driver = self.aggregation_driver;
+        let server = async move {
+            match aggregation_driver {
+                Some(config) => {
+                    info!(?config, "scheduling aggregation");
+                    tokio::join!(server, drive_aggregation(leaders.clone(), config, shutdown));
+                }
+                None => server.await,
+            }
 
-    let collect = warp::post()
-        .and(warp::path("collect"))
-        .and(warp::body::bytes())
-        .and(with_shared_value(leader_aggregator.clone()))
-        .and_then(|body: Bytes, leader: Arc<Mutex<Leader<_>>>| async move {
-            let mut leader = leader.lock().await;
+            // Wait for collect jobs under way
+            drop(collect_jobs_running.write().await);
+            for leader in leaders.iter() {
+                leader.flush()?;
+            }
+            info!("leader stopped");
 
-            let collect_request = CollectRequest::get_decoded(&body).map_err(|e| {
-                warp::reject::custom(e.problem_document(Some(&leader.parameters), "collect"))
-            })?;
+            Ok(())
+        };
 
-            let response = leader.handle_collect(&collect_request).await.map_err(|e| {
-                warp::reject::custom(e.problem_document(Some(&leader.parameters), "collect"))
-            })?;
+        Ok((local_addr, server))
+    }
+}
 
-            let response = Response::builder()
-                .status(StatusCode::OK)
-                .body(response.get_encoded())
-                .map_err(|e| {
-                    warp::reject::custom(e.problem_document(Some(&leader.parameters), "collect"))
-                })?;
+impl ServeTasks for LeaderBuilder {
+    const ROLE: Role = Role::Leader;
+
+    fn task<A>(self, task: Task<A>) -> Self
+    where
+        A: vdaf::Aggregator + Debug + 'static + Send + Sync,
+        A::VerifyParam: Send + Sync,
+        A::AggregationParam: Send + Sync,
+        A::PrepareStep: Send + Sync,
+        A::AggregateShare: Send + Sync + Serialize + DeserializeOwned + NoisyAggregateShare,
+        A::PrepareMessage: Send + Sync,
+        A::OutputShare: Send + Sync,
+    {
+        LeaderBuilder::task(self, task)
+    }
+}
 
-            Ok(response) as Result<_, Rejection>
-        })
-        .with(warp::trace::named("collect"));
-
-    let routes = hpke_config_endpoint
-        .or(upload)
-        .or(aggregate)
-        .or(collect)
-        .recover(handle_rejection)
-        .with(warp::trace::request());
-
-    info!("leader serving on 0.0.0.0:{}", port);
-    warp::serve(routes)
-        .run(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port))
-        .await;
+/// Serve the provided tasks until the returned future is dropped, as described
+/// in [`LeaderBuilder`]. If `aggregation_driver` is provided, the leader
+/// aggregates reports on its own schedule.
+pub async fn run_leader<A>(
+    tasks: Vec<Task<A>>,
+    hpke_config: &hpke::Config,
+    aggregation_driver: Option<AggregationDriverConfig>,
+) -> Result<()>
+where
+    A: vdaf::Aggregator + Debug + 'static + Send + Sync,
+    A::VerifyParam: Send + Sync,
+    A::AggregationParam: Send + Sync,
+    A::PrepareStep: Send + Sync,
+    A::AggregateShare: Send + Sync + Serialize + DeserializeOwned + NoisyAggregateShare,
+    A::PrepareMessage: Send + Sync,
+    A::OutputShare: Send + Sync,
+{
+    let mut builder = LeaderBuilder::new(hpke_config).tasks(tasks);
+    if let Some(config) = aggregation_driver {
+        builder = builder.aggregation_driver(config);
+    }
 
-    unreachable!()
+    builder.run().await
 }
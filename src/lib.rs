@@ -1,17 +1,29 @@
 pub mod aggregate;
+pub mod aggregation_job;
 pub mod client;
+pub mod clock;
 pub mod collect;
+pub mod differential_privacy;
 mod error;
+pub mod health;
 pub mod helper;
+pub mod helper_state;
 pub mod hpke;
 pub mod leader;
+pub mod metrics;
 pub mod parameters;
+pub mod replay;
 pub mod report;
+pub mod retention;
+pub mod server;
+pub mod storage;
+pub mod task;
+pub mod tls;
 pub mod trace;
 
 use chrono::{DurationRound, TimeZone, Utc};
 use directories::ProjectDirs;
-use prio::codec::{CodecError, Decode, Encode};
+use prio::codec::{encode_u16_items, CodecError, Decode, Encode};
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 use std::{
@@ -23,7 +35,7 @@ use std::{
 use warp::Filter;
 
 /// Seconds elapsed since start of UNIX epoch
-#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct Time(pub u64);
 
 impl Time {
@@ -103,7 +115,7 @@ impl Decode for Duration {
 // Deriving [`PartialOrd`] yields a "lexicographic ordering based on the
 // top-to-bottom declaration order of the struct's members."
 // https://doc.rust-lang.org/std/cmp/trait.PartialOrd.html#derivable
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Nonce {
     /// Time at which the report was generated
     pub time: Time,
@@ -134,7 +146,7 @@ impl Decode for Nonce {
 }
 
 /// Interval of time.
-#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Interval {
     /// Start of the interval, included.
     pub start: Time,
@@ -143,9 +155,13 @@ pub struct Interval {
 }
 
 impl Interval {
-    /// Construct the HPKE AEAD associated data for the interval.
-    pub(crate) fn associated_data(&self) -> Vec<u8> {
-        [self.start.0.to_be_bytes(), self.duration.0.to_be_bytes()].concat()
+    /// Construct the HPKE AEAD associated data for an aggregate share over
+    /// the interval under the encoded aggregation parameter.
+    pub(crate) fn associated_data(&self, aggregation_parameter: &[u8]) -> Vec<u8> {
+        let mut associated_data =
+            [self.start.0.to_be_bytes(), self.duration.0.to_be_bytes()].concat();
+        encode_u16_items(&mut associated_data, &(), aggregation_parameter);
+        associated_data
     }
 
     /// Compute how many times an interval of length `duration` would fit in
@@ -153,6 +169,16 @@ impl Interval {
     pub(crate) fn intervals_in_interval(&self, duration: Duration) -> u64 {
         self.duration.0 / duration.0
     }
+
+    /// Whether the instant falls into this interval.
+    pub(crate) fn contains(&self, time: Time) -> bool {
+        time >= self.start && time < self.start.add(self.duration)
+    }
+
+    /// Whether any instant falls into both intervals.
+    pub(crate) fn overlaps(&self, other: &Interval) -> bool {
+        self.start < other.start.add(other.duration) && other.start < self.start.add(self.duration)
+    }
 }
 
 impl Display for Interval {
@@ -207,6 +233,12 @@ pub(crate) fn config_path() -> PathBuf {
     project_path.config_dir().to_path_buf()
 }
 
+/// Path relative to which persistent aggregator state may be stored.
+pub(crate) fn data_path() -> PathBuf {
+    let project_path = ProjectDirs::from("org", "isrg", "ppm-prototype").unwrap();
+    project_path.data_dir().to_path_buf()
+}
+
 /// Injects a clone of the provided value into the warp filter, making it
 /// available to the filter's map() or and_then() handler.
 pub fn with_shared_value<T: Clone + Sync + Send>(
@@ -220,7 +252,7 @@ mod base64 {
     use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
 
     pub fn serialize_bytes<V: AsRef<[u8]>, S: Serializer>(v: &V, s: S) -> Result<S::Ok, S::Error> {
-        String::serialize(&base64::encode(&v), s)
+        String::serialize(&base64::encode(v), s)
     }
 
     pub fn deserialize_bytes<'de, D: Deserializer<'de>, V: From<Vec<u8>>>(
@@ -258,7 +290,7 @@ mod base64 {
         s: S,
     ) -> Result<S::Ok, S::Error> {
         match v {
-            Some(v) => String::serialize(&base64::encode(&v), s),
+            Some(v) => String::serialize(&base64::encode(v), s),
             None => <Option<Vec<u8>>>::serialize(&None, s),
         }
     }
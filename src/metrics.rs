@@ -0,0 +1,254 @@
+//! Metrics.
+//!
+//! Leader and helper each keep a set of Prometheus metrics covering all the
+//! tasks they serve, exposed in the Prometheus text format on their `/metrics`
+//! route. Every server has its own registry, so that a leader and a helper
+//! running in the same process don't share metrics.
+
+use crate::{
+    aggregate::TransitionError,
+    error::{IntoHttpApiProblem, ProblemDocumentType},
+};
+use http::{header::CONTENT_TYPE, StatusCode};
+use http_api_problem::HttpApiProblem;
+use prometheus::{
+    histogram_opts, opts, Encoder, HistogramTimer, HistogramVec, IntCounter, IntCounterVec,
+    Registry, TextEncoder,
+};
+use std::sync::Arc;
+use warp::{filters::BoxedFilter, reply, Filter, Reply};
+
+/// Prefix of the URNs of PPM problem document types
+const PROBLEM_TYPE_PREFIX: &str = "urn:ietf:params:ppm:error:";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Prometheus error {0}")]
+    Prometheus(#[from] prometheus::Error),
+    #[error("metrics are not UTF-8 {0}")]
+    Encoding(#[from] std::string::FromUtf8Error),
+}
+
+impl IntoHttpApiProblem for Error {
+    fn problem_document_type(&self) -> Option<ProblemDocumentType> {
+        None
+    }
+}
+
+/// Metrics of a leader or helper.
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    registry: Registry,
+    /// Reports uploaded to the leader and accepted
+    uploads_accepted: IntCounter,
+    /// Reports uploaded to the leader and rejected, by the type of the problem
+    /// document the client got
+    uploads_rejected: IntCounterVec,
+    /// Reports whose preparation was started successfully
+    reports_prepared: IntCounter,
+    /// Reports that failed to prepare, by transition error. The helper counts
+    /// those it reports to the leader, the leader those reported by the helper.
+    transition_errors: IntCounterVec,
+    /// Input shares that could not be decrypted
+    hpke_decrypt_failures: IntCounter,
+    /// Aggregate requests, sent by the leader or handled by the helper
+    aggregate_rounds: IntCounter,
+    /// Collect requests received by the leader, or aggregate share requests
+    /// received by the helper
+    collect_requests: IntCounter,
+    /// Requests for aggregate shares refused because the batch's privacy
+    /// budget is used up
+    privacy_budget_rejections: IntCounter,
+    /// Time taken by the leader's requests to the helper, by helper endpoint
+    helper_request_duration: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, Error> {
+        let registry = Registry::new_custom(Some("ppm".to_string()), None)?;
+
+        let uploads_accepted = IntCounter::with_opts(opts!(
+            "uploads_accepted_total",
+            "Reports uploaded to the leader and accepted"
+        ))?;
+        let uploads_rejected = IntCounterVec::new(
+            opts!(
+                "uploads_rejected_total",
+                "Reports uploaded to the leader and rejected"
+            ),
+            &["problem_type"],
+        )?;
+        let reports_prepared = IntCounter::with_opts(opts!(
+            "reports_prepared_total",
+            "Reports whose preparation was started"
+        ))?;
+        let transition_errors = IntCounterVec::new(
+            opts!(
+                "transition_errors_total",
+                "Reports that failed to prepare, by transition error"
+            ),
+            &["error"],
+        )?;
+        let hpke_decrypt_failures = IntCounter::with_opts(opts!(
+            "hpke_decrypt_failures_total",
+            "Input shares that could not be decrypted"
+        ))?;
+        let aggregate_rounds = IntCounter::with_opts(opts!(
+            "aggregate_rounds_total",
+            "Aggregate requests sent to or handled by the helper"
+        ))?;
+        let collect_requests = IntCounter::with_opts(opts!(
+            "collect_requests_total",
+            "Collect or aggregate share requests received"
+        ))?;
+        let privacy_budget_rejections = IntCounter::with_opts(opts!(
+            "privacy_budget_rejections_total",
+            "Aggregate share requests refused for lack of privacy budget"
+        ))?;
+        let helper_request_duration = HistogramVec::new(
+            histogram_opts!(
+                "helper_request_duration_seconds",
+                "Duration of the leader's requests to the helper"
+            ),
+            &["endpoint"],
+        )?;
+
+        registry.register(Box::new(uploads_accepted.clone()))?;
+        registry.register(Box::new(uploads_rejected.clone()))?;
+        registry.register(Box::new(reports_prepared.clone()))?;
+        registry.register(Box::new(transition_errors.clone()))?;
+        registry.register(Box::new(hpke_decrypt_failures.clone()))?;
+        registry.register(Box::new(aggregate_rounds.clone()))?;
+        registry.register(Box::new(collect_requests.clone()))?;
+        registry.register(Box::new(privacy_budget_rejections.clone()))?;
+        registry.register(Box::new(helper_request_duration.clone()))?;
+
+        Ok(Self {
+            registry,
+            uploads_accepted,
+            uploads_rejected,
+            reports_prepared,
+            transition_errors,
+            hpke_decrypt_failures,
+            aggregate_rounds,
+            collect_requests,
+            privacy_budget_rejections,
+            helper_request_duration,
+        })
+    }
+
+    /// Count an upload, given the problem document it was rejected with, if
+    /// any. Rejections are labeled with the problem type without its URN
+    /// prefix.
+    pub(crate) fn upload(&self, problem_document: Option<&HttpApiProblem>) {
+        match problem_document {
+            None => self.uploads_accepted.inc(),
+            Some(problem_document) => {
+                let problem_type = problem_document
+                    .type_url
+                    .as_deref()
+                    .map(|type_url| type_url.trim_start_matches(PROBLEM_TYPE_PREFIX))
+                    .unwrap_or("unknown");
+                self.uploads_rejected
+                    .with_label_values(&[problem_type])
+                    .inc();
+            }
+        }
+    }
+
+    pub(crate) fn report_prepared(&self) {
+        self.reports_prepared.inc();
+    }
+
+    pub(crate) fn transition_error(&self, error: TransitionError) {
+        self.transition_errors
+            .with_label_values(&[&format!("{:?}", error)])
+            .inc();
+    }
+
+    pub(crate) fn hpke_decrypt_failure(&self) {
+        self.hpke_decrypt_failures.inc();
+    }
+
+    pub(crate) fn aggregate_round(&self) {
+        self.aggregate_rounds.inc();
+    }
+
+    pub(crate) fn collect_request(&self) {
+        self.collect_requests.inc();
+    }
+
+    pub(crate) fn privacy_budget_rejection(&self) {
+        self.privacy_budget_rejections.inc();
+    }
+
+    /// Start timing a request to the helper's endpoint. The duration is
+    /// recorded when the timer is dropped.
+    pub(crate) fn helper_request_timer(&self, endpoint: &str) -> HistogramTimer {
+        self.helper_request_duration
+            .with_label_values(&[endpoint])
+            .start_timer()
+    }
+
+    /// The current value of all metrics in the Prometheus text format
+    pub fn encode(&self) -> Result<String, Error> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        Ok(String::from_utf8(buffer)?)
+    }
+
+    /// Construct a warp filter serving the metrics on the `/metrics` route
+    pub(crate) fn warp_endpoint(self: &Arc<Self>) -> BoxedFilter<(impl Reply,)> {
+        let metrics = self.clone();
+        warp::get()
+            .and(warp::path("metrics"))
+            .and(warp::path::end())
+            .map(move || match metrics.encode() {
+                Ok(body) => reply::with_header(
+                    reply::with_status(body, StatusCode::OK),
+                    CONTENT_TYPE,
+                    TextEncoder::new().format_type(),
+                )
+                .into_response(),
+                Err(error) => {
+                    let problem_document = error.problem_document(None, "metrics");
+                    reply::with_status(
+                        reply::json(&problem_document),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    )
+                    .into_response()
+                }
+            })
+            .with(warp::trace::named("metrics"))
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_counters() {
+        let metrics = Metrics::new().unwrap();
+        metrics.upload(None);
+        metrics.upload(None);
+        metrics.upload(Some(
+            &HttpApiProblem::new(StatusCode::BAD_REQUEST)
+                .type_url(ProblemDocumentType::ReportReplayed),
+        ));
+        metrics.upload(Some(&HttpApiProblem::new(StatusCode::BAD_REQUEST)));
+        metrics.transition_error(TransitionError::HpkeDecryptError);
+        drop(metrics.helper_request_timer("aggregate"));
+
+        let encoded = metrics.encode().unwrap();
+        assert!(encoded.contains("ppm_uploads_accepted_total 2\n"));
+        assert!(encoded.contains("ppm_uploads_rejected_total{problem_type=\"reportReplayed\"} 1\n"));
+        assert!(encoded.contains("ppm_uploads_rejected_total{problem_type=\"unknown\"} 1\n"));
+        assert!(encoded.contains("ppm_transition_errors_total{error=\"HpkeDecryptError\"} 1\n"));
+        assert!(encoded
+            .contains("ppm_helper_request_duration_seconds_count{endpoint=\"aggregate\"} 1\n"));
+        assert!(encoded.contains("ppm_reports_prepared_total 0\n"));
+    }
+}
@@ -3,10 +3,22 @@
 //! Provides structures and functionality for dealing with a `struct PPMParam`
 //! and related types.
 
-use crate::{config_path, hpke, Duration, Interval, Role};
+use crate::{
+    aggregation_job::DEFAULT_MAX_AGGREGATION_JOB_SIZE,
+    collect::CollectJobId,
+    config_path,
+    differential_privacy::{self, DifferentialPrivacy, Noise},
+    hpke, Duration, Interval, Role,
+};
 use prio::{
     codec::{CodecError, Decode, Encode, ParameterizedDecode},
-    vdaf::Vdaf,
+    field::Field128,
+    vdaf::{
+        poplar1::{Poplar1, ToyIdpf},
+        prg::PrgAes128,
+        prio3::{Prio3Aes128Count, Prio3Aes128Histogram, Prio3Aes128Sum},
+        Vdaf, VdafError,
+    },
 };
 use rand::{thread_rng, Rng};
 use reqwest::Client;
@@ -15,8 +27,9 @@ use std::{
     convert::{AsRef, TryInto},
     fmt::Display,
     fs::File,
-    io::{Cursor, Read},
+    io::{Cursor, ErrorKind, Read},
     path::PathBuf,
+    str::FromStr,
 };
 use url::Url;
 
@@ -36,11 +49,19 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("Codec error")]
     Codec(#[from] prio::codec::CodecError),
+    #[error("VDAF error")]
+    Vdaf(#[from] VdafError),
+    #[error("unsupported VDAF {0:?}")]
+    UnsupportedVdaf(VdafLabel),
+    #[error("differential privacy error")]
+    DifferentialPrivacy(#[from] differential_privacy::Error),
+    #[error("report_retention requires a max_report_age no longer than it")]
+    ReportRetentionWithoutMaxReportAge,
 }
 
 /// The configuration parameters for a PPM task, corresponding to
 /// `struct Param` in §4.1 of RFCXXXX.
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Parameters {
     #[serde(
         serialize_with = "crate::base64::serialize_bytes",
@@ -55,6 +76,29 @@ pub struct Parameters {
     pub min_batch_size: u64,
     /// Minimum time elapsed between start and end of a batch
     pub min_batch_duration: Duration,
+    /// How far in the future a report's timestamp may be, to allow for clock
+    /// skew between clients and aggregators
+    #[serde(default = "default_tolerable_clock_skew")]
+    pub tolerable_clock_skew: Duration,
+    /// Reports with timestamps further in the past than this are rejected. If
+    /// unset, reports of any age are accepted.
+    #[serde(default)]
+    pub max_report_age: Option<Duration>,
+    /// How long aggregators keep reports that have been accumulated or have
+    /// failed, counted from the end of the report's batch interval. Such
+    /// reports are purged as soon as their batch interval is collected
+    /// regardless. If unset, reports in uncollected intervals are kept. If
+    /// set, `max_report_age` must be set and no longer than this, so that
+    /// purged reports can't be replayed.
+    #[serde(default)]
+    pub report_retention: Option<Duration>,
+    /// Maximum number of reports the leader puts into one aggregation job
+    #[serde(default = "default_max_aggregation_job_size")]
+    pub max_aggregation_job_size: usize,
+    /// Differential privacy guarantee for the aggregates released to the
+    /// collector. If unset, aggregators release exact aggregate shares.
+    #[serde(default)]
+    pub differential_privacy: Option<DifferentialPrivacy>,
     /// HMAC-SHA256 key used to authenticate messages exchanged between
     /// aggregators
     #[serde(
@@ -74,6 +118,15 @@ pub struct Parameters {
     pub vdaf_verification_parameter: Vec<Vec<u8>>,
 }
 
+/// Default clock skew tolerance of a few minutes
+fn default_tolerable_clock_skew() -> Duration {
+    Duration(300)
+}
+
+fn default_max_aggregation_job_size() -> usize {
+    DEFAULT_MAX_AGGREGATION_JOB_SIZE
+}
+
 impl Parameters {
     pub fn from_config_file() -> Result<Self, Error> {
         let ppm_parameters_path = config_path().join("parameters.json");
@@ -83,6 +136,19 @@ impl Parameters {
         )
     }
 
+    /// Load the parameters of all tasks an aggregator should serve. These are
+    /// read from a JSON list in `tasks.json` if that file exists, and from the
+    /// single task in `parameters.json` otherwise.
+    pub fn tasks_from_config_file() -> Result<Vec<Self>, Error> {
+        let tasks_path = config_path().join("tasks.json");
+
+        match File::open(&tasks_path) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![Self::from_config_file()?]),
+            Err(e) => Err(Error::File(e, tasks_path)),
+        }
+    }
+
     /// Read in a JSON encoded Param from the provided `std::io::Read` and
     /// construct an instance of `Parameters`.
     ///
@@ -117,6 +183,11 @@ impl Parameters {
         Ok(hpke::Config::decode(&mut Cursor::new(body_bytes.as_ref()))?)
     }
 
+    /// The route on which the aggregator exposes its metrics
+    pub fn metrics_endpoint(&self, role: Role) -> Result<Url, Error> {
+        Ok(self.aggregator_endpoint(role).join("metrics")?)
+    }
+
     pub fn upload_endpoint(&self) -> Result<Url, Error> {
         Ok(self.aggregator_endpoint(Role::Leader).join("upload")?)
     }
@@ -129,6 +200,13 @@ impl Parameters {
         Ok(self.aggregator_endpoint(Role::Helper).join("aggregate")?)
     }
 
+    /// The URI a collector polls for the results of the leader's collect job
+    pub fn collect_job_uri(&self, collect_job_id: CollectJobId) -> Result<Url, Error> {
+        Ok(self
+            .aggregator_endpoint(Role::Leader)
+            .join(&format!("collect_jobs/{}/{}", self.task_id, collect_job_id))?)
+    }
+
     pub fn leader_aggregate_endpoint(&self) -> Result<Url, Error> {
         Ok(self.aggregator_endpoint(Role::Leader).join("aggregate")?)
     }
@@ -139,6 +217,28 @@ impl Parameters {
             .join("aggregate_share")?)
     }
 
+    /// Noise aggregators add to their aggregate shares, if the task requires
+    /// differential privacy.
+    pub fn noise(&self) -> Result<Option<Noise>, Error> {
+        Ok(self
+            .differential_privacy
+            .map(|config| Noise::new(&config, self.vdaf.sensitivity()))
+            .transpose()?)
+    }
+
+    /// Check that the parameters are consistent enough for an aggregator to
+    /// serve the task.
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(report_retention) = self.report_retention {
+            match self.max_report_age {
+                Some(max_report_age) if max_report_age.0 <= report_retention.0 => {}
+                _ => return Err(Error::ReportRetentionWithoutMaxReportAge),
+            }
+        }
+
+        Ok(())
+    }
+
     /// Returns true if the batch interval is aligned with and greater than the
     /// minimum batch duration
     pub(crate) fn validate_batch_interval(&self, batch_interval: Interval) -> bool {
@@ -147,6 +247,33 @@ impl Parameters {
             && batch_interval.duration.0 % self.min_batch_duration.0 == 0
     }
 
+    /// Instantiate the VDAF named by the `vdaf` parameter, for as many
+    /// aggregators as there are aggregator endpoints
+    pub fn vdaf_instance(&self) -> Result<VdafInstance, Error> {
+        let num_aggregators = self.aggregator_endpoints.len() as u8;
+
+        Ok(match &self.vdaf {
+            VdafLabel::Prio3Count64 => {
+                VdafInstance::Prio3Count64(Prio3Aes128Count::new(num_aggregators)?)
+            }
+            VdafLabel::Prio3Sum64 { bits } => {
+                VdafInstance::Prio3Sum64(Prio3Aes128Sum::new(num_aggregators, *bits)?)
+            }
+            VdafLabel::Prio3Histogram64 { buckets } => {
+                VdafInstance::Prio3Histogram64(Prio3Aes128Histogram::new(num_aggregators, buckets)?)
+            }
+            VdafLabel::Hits { bits } => {
+                if num_aggregators != 2 || *bits == 0 || *bits > MAX_HITS_BITS {
+                    return Err(Error::UnsupportedVdaf(self.vdaf.clone()));
+                }
+                VdafInstance::Hits {
+                    vdaf: Poplar1::new(*bits),
+                    bits: *bits,
+                }
+            }
+        })
+    }
+
     /// Decode the VDAF verification parameter for the provided Role
     pub fn decode_vdaf_verification_parameter<V>(
         &self,
@@ -165,7 +292,7 @@ impl Parameters {
 }
 
 /// Randomly generated byte sequence uniquely identifying a PPM task.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TaskId([u8; 32]);
 
 impl TaskId {
@@ -184,6 +311,16 @@ impl Display for TaskId {
     }
 }
 
+impl FromStr for TaskId {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut decoded = [0u8; 32];
+        hex::decode_to_slice(s, &mut decoded)?;
+        Ok(Self(decoded))
+    }
+}
+
 impl AsRef<[u8]> for TaskId {
     fn as_ref(&self) -> &[u8] {
         &self.0
@@ -215,9 +352,51 @@ impl Decode for TaskId {
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
 pub enum VdafLabel {
     Prio3Count64,
-    Prio3Sum64 { bits: u32 },
-    Prio3Histogram64 { buckets: Vec<u64> },
-    Hits,
+    Prio3Sum64 {
+        bits: u32,
+    },
+    Prio3Histogram64 {
+        buckets: Vec<u64>,
+    },
+    /// Heavy hitters among `bits`-bit strings, found by collecting the same
+    /// batch interval under successively longer candidate prefixes
+    Hits {
+        bits: usize,
+    },
+}
+
+impl VdafLabel {
+    /// By how much adding or removing a single report can change any element
+    /// of an aggregate, which also bounds the change in L1 and L2 norm.
+    pub fn sensitivity(&self) -> u64 {
+        match self {
+            Self::Prio3Sum64 { bits } => 1u64.checked_shl(*bits).map_or(u64::MAX, |max| max - 1),
+            // A count, one bucket of a histogram or the count of the one
+            // candidate prefix a string matches changes by one
+            Self::Prio3Count64 | Self::Prio3Histogram64 { .. } | Self::Hits { .. } => 1,
+        }
+    }
+}
+
+/// Poplar1 as instantiated for [`VdafLabel::Hits`]. The IDPF is a toy that
+/// limits inputs to [`MAX_HITS_BITS`] bits.
+pub type Poplar1Aes128 = Poplar1<ToyIdpf<Field128>, PrgAes128, 16>;
+
+/// Longest input supported by [`Poplar1Aes128`]
+pub const MAX_HITS_BITS: usize = 16;
+
+/// A VDAF instantiated from a [`VdafLabel`]. Code that is generic over the
+/// VDAF matches on this to get at the concrete instance.
+#[derive(Clone, Debug)]
+pub enum VdafInstance {
+    Prio3Count64(Prio3Aes128Count),
+    Prio3Sum64(Prio3Aes128Sum),
+    Prio3Histogram64(Prio3Aes128Histogram),
+    Hits {
+        vdaf: Poplar1Aes128,
+        /// Length of the measurements, which Poplar1 doesn't expose
+        bits: usize,
+    },
 }
 
 #[cfg(test)]
@@ -225,6 +404,7 @@ mod tests {
     use std::convert::TryInto;
 
     use super::*;
+    use assert_matches::assert_matches;
 
     #[test]
     fn parameters_json_parse() {
@@ -254,6 +434,11 @@ mod tests {
             max_batch_lifetime: 1,
             min_batch_size: 100,
             min_batch_duration: Duration(100000),
+            tolerable_clock_skew: Duration(60),
+            max_report_age: Some(Duration(86400)),
+            report_retention: Some(Duration(604800)),
+            max_aggregation_job_size: 50,
+            differential_privacy: Some(DifferentialPrivacy::DiscreteLaplace { epsilon: 0.5 }),
             aggregator_auth_key: vec![
                 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9,
                 10, 11, 12, 13, 14, 15,
@@ -287,6 +472,15 @@ mod tests {
     "max_batch_lifetime": 1,
     "min_batch_size": 100,
     "min_batch_duration": 100000,
+    "tolerable_clock_skew": 60,
+    "max_report_age": 86400,
+    "report_retention": 604800,
+    "max_aggregation_job_size": 50,
+    "differential_privacy": {
+        "DiscreteLaplace": {
+            "epsilon": 0.5
+        }
+    },
     "vdaf": {
         "Prio3Sum64": {
             "bits": 64
@@ -307,4 +501,101 @@ mod tests {
         assert_eq!(params, params_again);
         assert_eq!(params_from_json, params);
     }
+
+    #[test]
+    fn report_time_bounds_default() {
+        let params =
+            Parameters::from_json_reader(&include_bytes!("../sample-config/parameters.json")[..])
+                .unwrap();
+
+        assert_eq!(params.tolerable_clock_skew, Duration(300));
+        assert_eq!(params.max_report_age, None);
+        assert_eq!(
+            params.max_aggregation_job_size,
+            DEFAULT_MAX_AGGREGATION_JOB_SIZE
+        );
+    }
+
+    #[test]
+    fn report_retention_requires_max_report_age() {
+        let mut params =
+            Parameters::from_json_reader(&include_bytes!("../sample-config/parameters.json")[..])
+                .unwrap();
+        params.validate().unwrap();
+
+        params.report_retention = Some(Duration(3600));
+        assert_matches!(
+            params.validate(),
+            Err(Error::ReportRetentionWithoutMaxReportAge)
+        );
+
+        params.max_report_age = Some(Duration(7200));
+        assert_matches!(
+            params.validate(),
+            Err(Error::ReportRetentionWithoutMaxReportAge)
+        );
+
+        params.max_report_age = Some(Duration(3600));
+        params.validate().unwrap();
+    }
+
+    #[test]
+    fn instantiate_vdaf() {
+        let mut params =
+            Parameters::from_json_reader(&include_bytes!("../sample-config/parameters.json")[..])
+                .unwrap();
+
+        assert_matches!(params.vdaf_instance(), Ok(VdafInstance::Prio3Sum64(vdaf)) => {
+            assert_eq!(vdaf.output_len(), 1);
+        });
+
+        params.vdaf = VdafLabel::Prio3Count64;
+        assert_matches!(params.vdaf_instance(), Ok(VdafInstance::Prio3Count64(_)));
+
+        params.vdaf = VdafLabel::Prio3Histogram64 {
+            buckets: vec![10, 20, 30],
+        };
+        assert_matches!(params.vdaf_instance(), Ok(VdafInstance::Prio3Histogram64(vdaf)) => {
+            assert_eq!(vdaf.output_len(), 4);
+        });
+
+        params.vdaf = VdafLabel::Prio3Sum64 { bits: 65 };
+        assert_matches!(params.vdaf_instance(), Err(Error::Vdaf(_)));
+
+        params.vdaf = VdafLabel::Hits { bits: 8 };
+        assert_matches!(
+            params.vdaf_instance(),
+            Ok(VdafInstance::Hits { bits: 8, .. })
+        );
+
+        params
+            .aggregator_endpoints
+            .push(params.aggregator_endpoints[1].clone());
+        assert_matches!(
+            params.vdaf_instance(),
+            Err(Error::UnsupportedVdaf(VdafLabel::Hits { bits: 8 }))
+        );
+        params.aggregator_endpoints.pop();
+
+        params.vdaf = VdafLabel::Hits { bits: 17 };
+        assert_matches!(params.vdaf_instance(), Err(Error::UnsupportedVdaf(_)));
+    }
+
+    #[test]
+    fn collect_job_uri() {
+        let params =
+            Parameters::from_json_reader(&include_bytes!("../sample-config/parameters.json")[..])
+                .unwrap();
+
+        let uri = params.collect_job_uri(CollectJobId(7)).unwrap();
+        assert_eq!(
+            uri.as_str(),
+            format!("http://localhost:8080/collect_jobs/{}/7", params.task_id)
+        );
+
+        // The task ID in the URI identifies the task
+        let task_id = uri.path_segments().unwrap().nth(1).unwrap();
+        assert_eq!(task_id.parse::<TaskId>().unwrap(), params.task_id);
+        assert!("00".parse::<TaskId>().is_err());
+    }
 }
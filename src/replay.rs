@@ -0,0 +1,174 @@
+//! Replay protection.
+//!
+//! Aggregators must not aggregate a report twice under the same aggregation
+//! parameter. Each keeps a [`NonceIndex`] of the nonces of reports it has
+//! taken on: the leader records reports as they are uploaded and the helper
+//! records them as they are accumulated, along with the aggregation parameter
+//! they were accumulated under. The index is organized by batch interval, so
+//! that the nonces of an interval can be forgotten once the interval can no
+//! longer accept reports, because it has been collected or because its
+//! reports would be too old.
+
+use crate::{Duration, Interval, Nonce, Time};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Mutex,
+};
+
+/// Nonces of the reports in one batch interval, by encoded aggregation
+/// parameter
+type IntervalNonces = HashMap<Vec<u8>, HashSet<Nonce>>;
+
+/// Nonces of the reports an aggregator has seen, by batch interval and
+/// encoded aggregation parameter.
+#[derive(Debug)]
+pub(crate) struct NonceIndex {
+    min_batch_duration: Duration,
+    /// Keyed by the start of the batch interval
+    nonces: Mutex<BTreeMap<Time, IntervalNonces>>,
+}
+
+impl NonceIndex {
+    pub(crate) fn new(min_batch_duration: Duration) -> Self {
+        Self {
+            min_batch_duration,
+            nonces: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record the nonce under the aggregation parameter. Returns false if it
+    /// had already been recorded under that parameter, in which case the
+    /// report is a replay. Of several concurrent callers recording the same
+    /// nonce, exactly one gets true.
+    pub(crate) fn insert(&self, nonce: Nonce, aggregation_parameter: &[u8]) -> bool {
+        let mut nonces = self.nonces.lock().unwrap();
+        let interval_nonces = nonces
+            .entry(nonce.time.interval_start(self.min_batch_duration))
+            .or_default();
+
+        match interval_nonces.get_mut(aggregation_parameter) {
+            Some(parameter_nonces) => parameter_nonces.insert(nonce),
+            None => {
+                interval_nonces.insert(aggregation_parameter.to_vec(), HashSet::from([nonce]));
+                true
+            }
+        }
+    }
+
+    /// Forget that the nonce was recorded under the aggregation parameter, so
+    /// that the report may be taken on again.
+    pub(crate) fn remove(&self, nonce: Nonce, aggregation_parameter: &[u8]) {
+        if let Some(parameter_nonces) = self
+            .nonces
+            .lock()
+            .unwrap()
+            .get_mut(&nonce.time.interval_start(self.min_batch_duration))
+            .and_then(|interval_nonces| interval_nonces.get_mut(aggregation_parameter))
+        {
+            parameter_nonces.remove(&nonce);
+        }
+    }
+
+    /// Whether the nonce has been recorded under the aggregation parameter.
+    pub(crate) fn contains(&self, nonce: Nonce, aggregation_parameter: &[u8]) -> bool {
+        let nonces = self.nonces.lock().unwrap();
+        let parameter_nonces = nonces
+            .get(&nonce.time.interval_start(self.min_batch_duration))
+            .and_then(|interval_nonces| interval_nonces.get(aggregation_parameter));
+
+        matches!(parameter_nonces, Some(parameter_nonces) if parameter_nonces.contains(&nonce))
+    }
+
+    /// Forget the nonces in batch intervals for which `closed` returns true.
+    /// Returns the number of nonces forgotten.
+    pub(crate) fn forget_closed(&self, closed: impl Fn(Interval) -> bool) -> usize {
+        let mut forgotten = 0;
+        self.nonces
+            .lock()
+            .unwrap()
+            .retain(|start, interval_nonces| {
+                let interval = Interval {
+                    start: *start,
+                    duration: self.min_batch_duration,
+                };
+                if closed(interval) {
+                    forgotten += interval_nonces.values().map(HashSet::len).sum::<usize>();
+                    false
+                } else {
+                    true
+                }
+            });
+
+        forgotten
+    }
+
+    /// Number of nonces in the index.
+    pub(crate) fn len(&self) -> usize {
+        self.nonces
+            .lock()
+            .unwrap()
+            .values()
+            .flat_map(HashMap::values)
+            .map(HashSet::len)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nonce(time: u64, rand: u64) -> Nonce {
+        Nonce {
+            time: Time(time),
+            rand,
+        }
+    }
+
+    #[test]
+    fn reject_duplicates() {
+        let index = NonceIndex::new(Duration(100));
+
+        assert!(index.insert(nonce(1000, 1), &[]));
+        assert!(index.insert(nonce(1000, 2), &[]));
+        assert!(!index.insert(nonce(1000, 1), &[]));
+        assert!(index.contains(nonce(1000, 2), &[]));
+        assert!(!index.contains(nonce(1001, 2), &[]));
+        assert_eq!(index.len(), 2);
+
+        // A removed nonce may be inserted again
+        index.remove(nonce(1000, 1), &[]);
+        assert!(!index.contains(nonce(1000, 1), &[]));
+        assert!(index.insert(nonce(1000, 1), &[]));
+    }
+
+    #[test]
+    fn distinguish_aggregation_parameters() {
+        let index = NonceIndex::new(Duration(100));
+
+        // A report may be taken on once under each aggregation parameter
+        assert!(index.insert(nonce(1000, 1), &[0]));
+        assert!(index.insert(nonce(1000, 1), &[1]));
+        assert!(!index.insert(nonce(1000, 1), &[1]));
+        assert!(index.contains(nonce(1000, 1), &[0]));
+        assert!(!index.contains(nonce(1000, 1), &[2]));
+        assert_eq!(index.len(), 2);
+    }
+
+    #[test]
+    fn forget_closed_intervals() {
+        let index = NonceIndex::new(Duration(100));
+        for time in [1000, 1050, 1100, 1200] {
+            index.insert(nonce(time, 0), &[]);
+        }
+        index.insert(nonce(1000, 0), &[1]);
+
+        let forgotten = index.forget_closed(|interval| interval.start < Time(1100));
+        assert_eq!(forgotten, 3);
+        assert_eq!(index.len(), 2);
+
+        // Nonces in forgotten intervals may be inserted again
+        assert!(index.insert(nonce(1000, 0), &[]));
+        assert!(!index.insert(nonce(1200, 0), &[]));
+    }
+}
@@ -0,0 +1,143 @@
+//! Report retention.
+//!
+//! Once a report has been accumulated, or has failed to prepare, aggregators
+//! have no further use for its encrypted shares or prepare state. They purge
+//! such a report when its batch interval has been collected, after which the
+//! report could not be aggregated again anyway, or when the task's
+//! `report_retention` window has passed since the end of its batch interval.
+//!
+//! Aggregators rebuild their replay protection from the reports they store
+//! when they restart, so a purged report in an uncollected interval could be
+//! replayed afterwards if it were still recent enough to be accepted. Tasks
+//! must therefore set a `max_report_age` no longer than their
+//! `report_retention`, and reports are never purged before they're too old to
+//! be uploaded again.
+//!
+//! Tasks without an eager aggregation parameter aggregate reports anew for
+//! each collect request. Their reports are held until the privacy budget of
+//! their batch interval has been used up rather than until it is first
+//! collected.
+
+use crate::{parameters::Parameters, Nonce, Time};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of reports purged by an aggregator, by the state they were in.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PurgedReports {
+    pub accumulated: u64,
+    pub failed: u64,
+}
+
+impl PurgedReports {
+    pub fn total(&self) -> u64 {
+        self.accumulated + self.failed
+    }
+}
+
+/// Running totals of the reports an aggregator has purged.
+#[derive(Debug, Default)]
+pub(crate) struct PurgeCounters {
+    accumulated: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl PurgeCounters {
+    pub(crate) fn record(&self, purged: PurgedReports) {
+        self.accumulated
+            .fetch_add(purged.accumulated, Ordering::Relaxed);
+        self.failed.fetch_add(purged.failed, Ordering::Relaxed);
+    }
+
+    pub(crate) fn totals(&self) -> PurgedReports {
+        PurgedReports {
+            accumulated: self.accumulated.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Whether a report that has been accumulated or has failed may be purged,
+/// given whether its batch interval is done with and the current time.
+pub(crate) fn should_purge(
+    parameters: &Parameters,
+    nonce: Nonce,
+    batch_closed: bool,
+    now: Time,
+) -> bool {
+    if batch_closed {
+        return true;
+    }
+
+    match (parameters.report_retention, parameters.max_report_age) {
+        (Some(retention), Some(max_report_age)) => {
+            let batch_interval = nonce.time.batch_interval(parameters.min_batch_duration);
+            let retain_until = batch_interval
+                .start
+                .0
+                .saturating_add(batch_interval.duration.0)
+                .saturating_add(retention.0.max(max_report_age.0));
+            now.0 >= retain_until
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Duration;
+    use std::io::Cursor;
+
+    #[test]
+    fn purge_after_collection_or_retention() {
+        let mut parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+            "../sample-config/parameters.json"
+        )))
+        .unwrap();
+        parameters.min_batch_duration = Duration(100);
+        let nonce = Nonce {
+            time: Time(1050),
+            rand: 0,
+        };
+
+        // Without a retention window, only collection allows purging
+        assert!(should_purge(&parameters, nonce, true, Time(1050)));
+        assert!(!should_purge(&parameters, nonce, false, Time(u64::MAX)));
+
+        // The window starts at the end of the report's batch interval
+        parameters.report_retention = Some(Duration(60));
+        parameters.max_report_age = Some(Duration(30));
+        assert!(!should_purge(&parameters, nonce, false, Time(1159)));
+        assert!(should_purge(&parameters, nonce, false, Time(1160)));
+
+        // Reports that could still be uploaded again are kept, however long
+        // the window
+        parameters.max_report_age = Some(Duration(120));
+        assert!(!should_purge(&parameters, nonce, false, Time(1219)));
+        assert!(should_purge(&parameters, nonce, false, Time(1220)));
+        parameters.max_report_age = None;
+        assert!(!should_purge(&parameters, nonce, false, Time(u64::MAX)));
+    }
+
+    #[test]
+    fn counters_accumulate() {
+        let counters = PurgeCounters::default();
+        counters.record(PurgedReports {
+            accumulated: 3,
+            failed: 1,
+        });
+        counters.record(PurgedReports {
+            accumulated: 2,
+            failed: 0,
+        });
+
+        assert_eq!(
+            counters.totals(),
+            PurgedReports {
+                accumulated: 5,
+                failed: 1
+            }
+        );
+        assert_eq!(counters.totals().total(), 6);
+    }
+}
@@ -0,0 +1,142 @@
+//! Running aggregators.
+//!
+//! [`crate::leader::LeaderBuilder`] and [`crate::helper::HelperBuilder`] start
+//! a leader or helper in the background and return a [`ServerHandle`], which
+//! tells where the server listens and stops it. A server shuts down gracefully:
+//! it stops accepting connections, lets requests that are under way finish,
+//! waits for its background work, and flushes the stores of its tasks.
+
+use crate::tls::{self, TlsConfig};
+use color_eyre::eyre::Result;
+use futures::{
+    future::{self, BoxFuture, Shared},
+    FutureExt,
+};
+use std::{convert::Infallible, future::Future, net::SocketAddr};
+use tokio::{sync::oneshot, task::JoinHandle};
+use tracing::{info, warn};
+use warp::{Filter, Reply};
+
+/// Future that resolves once a server is to shut down. It may be cloned so
+/// that all parts of the server can wait for it.
+pub(crate) type ShutdownSignal = Shared<BoxFuture<'static, ()>>;
+
+/// Construct the shutdown signal of a server, which fires when the returned
+/// sender is used or dropped, or when `signal` resolves.
+pub(crate) fn shutdown_signal(
+    signal: Option<BoxFuture<'static, ()>>,
+) -> (oneshot::Sender<()>, ShutdownSignal) {
+    let (sender, receiver) = oneshot::channel();
+    let signal = signal.unwrap_or_else(|| future::pending().boxed());
+
+    let shutdown = async move {
+        tokio::select! {
+            _ = receiver => {}
+            _ = signal => {}
+        }
+        info!("shutting down");
+    }
+    .boxed()
+    .shared();
+
+    (sender, shutdown)
+}
+
+/// Serve `routes` on `address` until `shutdown` resolves, over HTTPS if `tls`
+/// is provided. Returns the bound address and a future that serves requests.
+pub(crate) fn serve<F, R>(
+    routes: F,
+    address: SocketAddr,
+    tls: Option<&TlsConfig>,
+    shutdown: ShutdownSignal,
+) -> Result<(SocketAddr, BoxFuture<'static, ()>)>
+where
+    F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    match tls {
+        Some(tls) => Ok(tls::serve(routes, address, tls, shutdown)?),
+        None => {
+            let (local_addr, server) =
+                warp::serve(routes).try_bind_with_graceful_shutdown(address, shutdown)?;
+            Ok((local_addr, server.boxed()))
+        }
+    }
+}
+
+/// A running leader or helper. Dropping the handle shuts the server down.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    server: JoinHandle<Result<()>>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(
+        local_addr: SocketAddr,
+        shutdown: oneshot::Sender<()>,
+        server: JoinHandle<Result<()>>,
+    ) -> Self {
+        Self {
+            local_addr,
+            shutdown,
+            server,
+        }
+    }
+
+    /// The address the server listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Shut the server down and wait until it has stopped.
+    pub async fn shutdown(self) -> Result<()> {
+        // The server may already be shutting down, in which case nobody is
+        // listening anymore
+        let _ = self.shutdown.send(());
+
+        self.server.await?
+    }
+
+    /// Wait for the server to stop, which it only does once the shutdown
+    /// signal it was built with resolves. Like dropping the handle, dropping
+    /// the future returned here shuts the server down.
+    pub fn stopped(self) -> impl Future<Output = Result<()>> {
+        let Self {
+            shutdown, server, ..
+        } = self;
+        async move {
+            let result = server.await?;
+            drop(shutdown);
+            result
+        }
+    }
+}
+
+/// Resolves once the process is asked to terminate, by SIGTERM or SIGINT.
+pub async fn termination_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => info!("received SIGTERM"),
+                    _ = tokio::signal::ctrl_c() => info!("received SIGINT"),
+                }
+                return;
+            }
+            Err(error) => warn!(?error, "can't handle SIGTERM"),
+        }
+    }
+
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => info!("received SIGINT"),
+        Err(error) => {
+            warn!(?error, "can't handle SIGINT");
+            future::pending::<()>().await;
+        }
+    }
+}
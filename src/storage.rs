@@ -0,0 +1,1058 @@
+//! Persistent storage for aggregator state.
+//!
+//! Aggregators keep the reports they have received, their accumulators and the
+//! batch intervals that have been collected in a [`Store`], so that this state
+//! (and with it the privacy budget consumed by collectors) survives a restart.
+
+use crate::{
+    aggregate::ReportShare, data_path, hpke::Ciphertext, parameters::TaskId, Interval, Nonce, Role,
+};
+use prio::codec::{CodecError, Decode, Encode};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::warn;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("JSON error")]
+    Json(#[from] serde_json::Error),
+    #[error("file error: {1}")]
+    File(#[source] std::io::Error, PathBuf),
+    #[error("Codec error")]
+    Codec(#[from] CodecError),
+    #[error("unknown report {0}")]
+    UnknownReport(Nonce),
+    #[error("no store for role {0:?}")]
+    InvalidRole(Role),
+}
+
+/// Where a stored report is in the aggregation process.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ReportRecordState {
+    /// The report has not yet been prepared together with the other aggregator.
+    Waiting,
+    /// The report's output share has been accumulated.
+    Accumulated,
+    /// Preparation of the report failed, so it will never be accumulated.
+    Failed,
+}
+
+/// A report as persisted by an aggregator.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ReportRecord {
+    pub nonce: Nonce,
+    pub state: ReportRecordState,
+    /// Encoded `ReportShare` containing this aggregator's encrypted input share.
+    #[serde(
+        serialize_with = "crate::base64::serialize_bytes",
+        deserialize_with = "crate::base64::deserialize_bytes"
+    )]
+    report_share: Vec<u8>,
+    /// Encoded encrypted input share of the helper. Only stored by the leader,
+    /// which forwards it to the helper during aggregation.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "crate::base64::serialize_bytes_option",
+        deserialize_with = "crate::base64::deserialize_bytes_option"
+    )]
+    encrypted_helper_share: Option<Vec<u8>>,
+    /// Encoded aggregation parameters the report has been aggregated under, if
+    /// the task has no eager aggregation parameter
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        serialize_with = "crate::base64::serialize_bytes_vec",
+        deserialize_with = "crate::base64::deserialize_bytes_vec"
+    )]
+    pub aggregation_parameters: Vec<Vec<u8>>,
+    /// How far preparation of a waiting report has got. Only stored by a
+    /// helper that keeps its own prepare state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preparation: Option<Preparation>,
+}
+
+/// The progress of a report being prepared, from which a helper recovers the
+/// report's prepare step by preparing its share afresh under the aggregation
+/// parameter and replaying the leader's prepare messages, like it does for
+/// reports in [`crate::helper_state::HelperState`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Preparation {
+    /// Encoded aggregation parameter the report is prepared under
+    #[serde(
+        serialize_with = "crate::base64::serialize_bytes",
+        deserialize_with = "crate::base64::deserialize_bytes"
+    )]
+    pub aggregation_parameter: Vec<u8>,
+    /// Encoded prepare messages the leader has sent for the report so far
+    #[serde(
+        default,
+        serialize_with = "crate::base64::serialize_bytes_vec",
+        deserialize_with = "crate::base64::deserialize_bytes_vec"
+    )]
+    pub prepare_messages: Vec<Vec<u8>>,
+}
+
+impl ReportRecord {
+    pub fn new(
+        report_share: &ReportShare,
+        encrypted_helper_share: Option<&Ciphertext>,
+        state: ReportRecordState,
+    ) -> Self {
+        Self {
+            nonce: report_share.nonce,
+            state,
+            report_share: report_share.get_encoded(),
+            encrypted_helper_share: encrypted_helper_share.map(Encode::get_encoded),
+            aggregation_parameters: vec![],
+            preparation: None,
+        }
+    }
+
+    /// Record that the report is about to be prepared under the encoded
+    /// aggregation parameter.
+    pub fn with_preparation(mut self, aggregation_parameter: Vec<u8>) -> Self {
+        self.preparation = Some(Preparation {
+            aggregation_parameter,
+            prepare_messages: vec![],
+        });
+        self
+    }
+
+    /// The report share containing this aggregator's encrypted input share.
+    pub fn report_share(&self) -> Result<ReportShare, Error> {
+        Ok(ReportShare::get_decoded(&self.report_share)?)
+    }
+
+    /// The helper's encrypted input share, if this record was stored by the
+    /// leader.
+    pub fn encrypted_helper_share(&self) -> Result<Option<Ciphertext>, Error> {
+        self.encrypted_helper_share
+            .as_ref()
+            .map(|share| Ciphertext::get_decoded(share))
+            .transpose()
+            .map_err(Error::from)
+    }
+}
+
+/// An accumulator as persisted by an aggregator. The accumulated aggregate
+/// share is stored in its serde representation since VDAF aggregate shares can
+/// only be decoded from their TLS encoding if their length is known.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccumulatorRecord {
+    pub interval: Interval,
+    /// Encoded aggregation parameter the accumulated reports were prepared
+    /// under
+    #[serde(
+        default,
+        serialize_with = "crate::base64::serialize_bytes",
+        deserialize_with = "crate::base64::deserialize_bytes"
+    )]
+    pub aggregation_parameter: Vec<u8>,
+    pub accumulated: serde_json::Value,
+    pub contributions: u64,
+    /// Checksum over the nonces of the accumulated reports
+    #[serde(default, with = "hex")]
+    pub checksum: [u8; 32],
+    pub consumed_privacy_budget: u64,
+}
+
+/// A change to the state held in a [`Store`]. The mutations passed to one call
+/// of [`Store::commit`] take effect together or not at all.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Mutation {
+    /// Insert or replace the record for a report. The aggregation parameters
+    /// recorded for a replaced report are kept.
+    PutReport(ReportRecord),
+    /// Update the state of a previously stored report. Its preparation is
+    /// dropped unless it is still waiting.
+    UpdateReportState {
+        nonce: Nonce,
+        state: ReportRecordState,
+    },
+    /// Record that a previously stored report has been aggregated under the
+    /// encoded aggregation parameter.
+    AddAggregationParameter {
+        nonce: Nonce,
+        aggregation_parameter: Vec<u8>,
+    },
+    /// Append a prepare message to the preparation of a previously stored
+    /// report.
+    AddPrepareMessage {
+        nonce: Nonce,
+        prepare_message: Vec<u8>,
+    },
+    /// Delete the records of the reports. Unknown nonces are ignored.
+    DeleteReports(Vec<Nonce>),
+    /// Insert or replace the accumulator for `accumulator.interval` and
+    /// `accumulator.aggregation_parameter`.
+    PutAccumulator(AccumulatorRecord),
+    /// Record that a batch interval has been collected.
+    PutCollectedBatchInterval(Interval),
+}
+
+/// Storage backend for aggregator state. Implementations use interior
+/// mutability so that a store may be shared between the components of an
+/// aggregator.
+pub trait Store: Debug + Send + Sync {
+    /// Apply the mutations atomically: if an error is returned, none of them
+    /// has taken effect.
+    fn commit(&self, mutations: Vec<Mutation>) -> Result<(), Error>;
+
+    /// All stored reports, ordered by nonce.
+    fn reports(&self) -> Result<Vec<ReportRecord>, Error>;
+
+    /// All stored accumulators.
+    fn accumulators(&self) -> Result<Vec<AccumulatorRecord>, Error>;
+
+    /// All batch intervals that have been collected.
+    fn collected_batch_intervals(&self) -> Result<Vec<Interval>, Error>;
+
+    /// Insert or replace the record for a report. The aggregation parameters
+    /// recorded for a replaced report are kept.
+    fn put_report(&self, report: ReportRecord) -> Result<(), Error> {
+        self.commit(vec![Mutation::PutReport(report)])
+    }
+
+    /// Update the state of a previously stored report.
+    fn update_report_state(&self, nonce: Nonce, state: ReportRecordState) -> Result<(), Error> {
+        self.commit(vec![Mutation::UpdateReportState { nonce, state }])
+    }
+
+    /// Delete the records of the reports. Unknown nonces are ignored.
+    fn delete_reports(&self, nonces: &[Nonce]) -> Result<(), Error> {
+        self.commit(vec![Mutation::DeleteReports(nonces.to_vec())])
+    }
+
+    /// Insert or replace the accumulator for `accumulator.interval` and
+    /// `accumulator.aggregation_parameter`.
+    fn put_accumulator(&self, accumulator: AccumulatorRecord) -> Result<(), Error> {
+        self.commit(vec![Mutation::PutAccumulator(accumulator)])
+    }
+
+    /// Record that a batch interval has been collected.
+    fn put_collected_batch_interval(&self, interval: Interval) -> Result<(), Error> {
+        self.commit(vec![Mutation::PutCollectedBatchInterval(interval)])
+    }
+
+    /// Check that the store can currently be written to. Used to decide
+    /// whether the aggregator is ready to serve requests.
+    fn check(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Make sure that everything written to the store so far survives a crash.
+    /// Called when the aggregator shuts down.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Contents {
+    reports: BTreeMap<Nonce, ReportRecord>,
+    /// Keyed by interval and encoded aggregation parameter
+    accumulators: HashMap<(Interval, Vec<u8>), AccumulatorRecord>,
+    collected_batch_intervals: HashSet<Interval>,
+}
+
+/// Representation of [`Contents`] written to disk by [`FileStore`]. JSON
+/// objects may only have string keys, so maps are flattened into lists.
+#[derive(Default, Deserialize, Serialize)]
+struct SerializedContents {
+    reports: Vec<ReportRecord>,
+    accumulators: Vec<AccumulatorRecord>,
+    collected_batch_intervals: Vec<Interval>,
+}
+
+impl From<SerializedContents> for Contents {
+    fn from(serialized: SerializedContents) -> Self {
+        let mut contents = Self::default();
+        contents.apply(
+            serialized
+                .reports
+                .into_iter()
+                .map(Mutation::PutReport)
+                .chain(
+                    serialized
+                        .accumulators
+                        .into_iter()
+                        .map(Mutation::PutAccumulator),
+                )
+                .chain(
+                    serialized
+                        .collected_batch_intervals
+                        .into_iter()
+                        .map(Mutation::PutCollectedBatchInterval),
+                ),
+        );
+        contents
+    }
+}
+
+impl From<&Contents> for SerializedContents {
+    fn from(contents: &Contents) -> Self {
+        Self {
+            reports: contents.reports.values().cloned().collect(),
+            accumulators: contents.accumulators.values().cloned().collect(),
+            collected_batch_intervals: contents.collected_batch_intervals.iter().copied().collect(),
+        }
+    }
+}
+
+impl Contents {
+    /// Check that the mutations can be applied, so that [`Self::apply`] either
+    /// applies all of them or isn't called at all.
+    fn check(&self, mutations: &[Mutation]) -> Result<(), Error> {
+        let mut put_reports = HashSet::new();
+        for mutation in mutations {
+            match mutation {
+                Mutation::PutReport(report) => {
+                    put_reports.insert(report.nonce);
+                }
+                Mutation::UpdateReportState { nonce, .. }
+                | Mutation::AddAggregationParameter { nonce, .. }
+                | Mutation::AddPrepareMessage { nonce, .. }
+                    if !self.reports.contains_key(nonce) && !put_reports.contains(nonce) =>
+                {
+                    return Err(Error::UnknownReport(*nonce));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, mutations: impl IntoIterator<Item = Mutation>) {
+        for mutation in mutations {
+            match mutation {
+                Mutation::PutReport(mut report) => {
+                    if let Some(previous) = self.reports.get(&report.nonce) {
+                        merge_aggregation_parameters(
+                            &mut report.aggregation_parameters,
+                            &previous.aggregation_parameters,
+                        );
+                    }
+                    self.reports.insert(report.nonce, report);
+                }
+                Mutation::UpdateReportState { nonce, state } => {
+                    if let Some(report) = self.reports.get_mut(&nonce) {
+                        report.state = state;
+                        if state != ReportRecordState::Waiting {
+                            report.preparation = None;
+                        }
+                    }
+                }
+                Mutation::AddPrepareMessage {
+                    nonce,
+                    prepare_message,
+                } => {
+                    if let Some(preparation) = self
+                        .reports
+                        .get_mut(&nonce)
+                        .and_then(|report| report.preparation.as_mut())
+                    {
+                        preparation.prepare_messages.push(prepare_message);
+                    }
+                }
+                Mutation::AddAggregationParameter {
+                    nonce,
+                    aggregation_parameter,
+                } => {
+                    if let Some(report) = self.reports.get_mut(&nonce) {
+                        merge_aggregation_parameters(
+                            &mut report.aggregation_parameters,
+                            &[aggregation_parameter],
+                        );
+                    }
+                }
+                Mutation::DeleteReports(nonces) => {
+                    for nonce in nonces {
+                        self.reports.remove(&nonce);
+                    }
+                }
+                Mutation::PutAccumulator(accumulator) => {
+                    self.accumulators.insert(
+                        (
+                            accumulator.interval,
+                            accumulator.aggregation_parameter.clone(),
+                        ),
+                        accumulator,
+                    );
+                }
+                Mutation::PutCollectedBatchInterval(interval) => {
+                    self.collected_batch_intervals.insert(interval);
+                }
+            }
+        }
+    }
+
+    /// Number of records held, which bounds the size of a snapshot.
+    fn len(&self) -> usize {
+        self.reports.len() + self.accumulators.len() + self.collected_batch_intervals.len()
+    }
+
+    fn reports(&self) -> Vec<ReportRecord> {
+        self.reports.values().cloned().collect()
+    }
+
+    fn accumulators(&self) -> Vec<AccumulatorRecord> {
+        self.accumulators.values().cloned().collect()
+    }
+
+    fn collected_batch_intervals(&self) -> Vec<Interval> {
+        self.collected_batch_intervals.iter().copied().collect()
+    }
+}
+
+fn merge_aggregation_parameters(into: &mut Vec<Vec<u8>>, from: &[Vec<u8>]) {
+    for aggregation_parameter in from {
+        if !into.contains(aggregation_parameter) {
+            into.push(aggregation_parameter.clone());
+        }
+    }
+}
+
+/// A store that keeps all state in memory, and so loses it when the process
+/// exits.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    contents: Mutex<Contents>,
+}
+
+impl Store for MemoryStore {
+    fn commit(&self, mutations: Vec<Mutation>) -> Result<(), Error> {
+        let mut contents = self.contents.lock().unwrap();
+        contents.check(&mutations)?;
+        contents.apply(mutations);
+        Ok(())
+    }
+
+    fn reports(&self) -> Result<Vec<ReportRecord>, Error> {
+        Ok(self.contents.lock().unwrap().reports())
+    }
+
+    fn accumulators(&self) -> Result<Vec<AccumulatorRecord>, Error> {
+        Ok(self.contents.lock().unwrap().accumulators())
+    }
+
+    fn collected_batch_intervals(&self) -> Result<Vec<Interval>, Error> {
+        Ok(self.contents.lock().unwrap().collected_batch_intervals())
+    }
+}
+
+/// An entry in the log written by [`FileStore`], one per line
+#[derive(Deserialize, Serialize)]
+enum LogEntry {
+    /// The contents of the store when the log was started. Only ever the
+    /// first entry of a log.
+    Snapshot(SerializedContents),
+    /// Mutations committed together
+    Commit(Vec<Mutation>),
+}
+
+/// Minimum number of commits a [`FileStore`] appends to its log before it
+/// considers compacting it
+const MIN_LOG_COMMITS: usize = 1024;
+
+/// State of a [`FileStore`], guarded by one lock so that commits reach the
+/// log in the order they are applied in memory.
+#[derive(Debug)]
+struct Log {
+    contents: Contents,
+    /// The log file, opened for appending
+    file: File,
+    /// Number of commits appended since the snapshot that starts the log
+    commits: usize,
+    /// Set if an append failed, possibly leaving a partial entry at the end
+    /// of the log. The log is then rewritten before anything else is appended.
+    damaged: bool,
+}
+
+/// A store that keeps its state in memory and durably appends every commit to
+/// a log file, one JSON object per line. Once the log has grown to more
+/// commits than the store holds records, it is replaced with a snapshot of the
+/// store's contents, so that writes take amortized constant time.
+///
+/// Commits are synced to disk before they are applied in memory, and the log
+/// is replaced atomically, so a crash loses no commit that succeeded.
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    log: Mutex<Log>,
+}
+
+impl FileStore {
+    /// Open the store backed by the file at `path`, loading any state
+    /// previously written there.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut contents = Contents::default();
+
+        match File::open(&path) {
+            Ok(file) => {
+                let mut lines = BufReader::new(file).lines().peekable();
+                while let Some(line) = lines.next() {
+                    let line = line.map_err(|e| Error::File(e, path.clone()))?;
+                    match serde_json::from_str(&line) {
+                        Ok(LogEntry::Snapshot(snapshot)) => contents = snapshot.into(),
+                        Ok(LogEntry::Commit(mutations)) => contents.apply(mutations),
+                        // A crash while appending leaves a partial last entry,
+                        // whose commit never succeeded
+                        Err(e) if lines.peek().is_none() => {
+                            warn!(?path, error = ?e, "ignoring partial entry at end of log");
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::File(e, path)),
+        }
+
+        // Start a fresh log, which also does away with any partial entry
+        let file = Self::write_snapshot(&path, &contents)?;
+
+        Ok(Self {
+            path,
+            log: Mutex::new(Log {
+                contents,
+                file,
+                commits: 0,
+                damaged: false,
+            }),
+        })
+    }
+
+    /// Open the store for the provided role and task in the default data
+    /// directory.
+    pub fn from_data_dir(role: Role, task_id: &TaskId) -> Result<Self, Error> {
+        let role_name = match role {
+            Role::Leader => "leader",
+            Role::Helper => "helper",
+            r => return Err(Error::InvalidRole(r)),
+        };
+
+        let data_dir = data_path();
+        fs::create_dir_all(&data_dir).map_err(|e| Error::File(e, data_dir.clone()))?;
+
+        Self::open(data_dir.join(format!("{}-{}.jsonl", role_name, task_id)))
+    }
+
+    /// Path of a file next to the log, named after it
+    fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+        let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(suffix);
+        path.with_file_name(file_name)
+    }
+
+    /// Atomically replace the log at `path` with one holding a snapshot of
+    /// `contents`, returning the new log opened for appending. The snapshot is
+    /// synced to disk before it replaces the log, and the rename is synced
+    /// after.
+    fn write_snapshot(path: &Path, contents: &Contents) -> Result<File, Error> {
+        let temp_path = Self::sibling_path(path, ".tmp");
+        let mut entry = serde_json::to_vec(&LogEntry::Snapshot(contents.into()))?;
+        entry.push(b'\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&temp_path)
+            .map_err(|e| Error::File(e, temp_path.clone()))?;
+        file.write_all(&entry)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| Error::File(e, temp_path.clone()))?;
+        drop(file);
+
+        fs::rename(&temp_path, path).map_err(|e| Error::File(e, path.to_path_buf()))?;
+        Self::sync_directory(path)?;
+
+        OpenOptions::new()
+            .append(true)
+            .open(path)
+            .map_err(|e| Error::File(e, path.to_path_buf()))
+    }
+
+    /// Sync the directory containing `path`, so that a rename to `path`
+    /// survives a crash.
+    #[cfg(unix)]
+    fn sync_directory(path: &Path) -> Result<(), Error> {
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(directory)
+            .and_then(|directory| directory.sync_all())
+            .map_err(|e| Error::File(e, directory.to_path_buf()))
+    }
+
+    /// Directories can't be synced on other platforms.
+    #[cfg(not(unix))]
+    fn sync_directory(_path: &Path) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Append the commit to the log and sync it to disk.
+    fn append(&self, log: &mut Log, mutations: &[Mutation]) -> Result<(), Error> {
+        #[derive(Serialize)]
+        enum LogEntryRef<'a> {
+            Commit(&'a [Mutation]),
+        }
+
+        if log.damaged {
+            log.file = Self::write_snapshot(&self.path, &log.contents)?;
+            log.commits = 0;
+            log.damaged = false;
+        }
+
+        let mut entry = serde_json::to_vec(&LogEntryRef::Commit(mutations))?;
+        entry.push(b'\n');
+        if let Err(e) = log
+            .file
+            .write_all(&entry)
+            .and_then(|_| log.file.sync_data())
+        {
+            log.damaged = true;
+            return Err(Error::File(e, self.path.clone()));
+        }
+        log.commits += 1;
+
+        Ok(())
+    }
+}
+
+impl Store for FileStore {
+    fn commit(&self, mutations: Vec<Mutation>) -> Result<(), Error> {
+        let mut log = self.log.lock().unwrap();
+        log.contents.check(&mutations)?;
+        self.append(&mut log, &mutations)?;
+        log.contents.apply(mutations);
+
+        // The commit is durable already, so failing to compact the log is no
+        // reason to fail it
+        if log.commits >= MIN_LOG_COMMITS.max(log.contents.len()) {
+            match Self::write_snapshot(&self.path, &log.contents) {
+                Ok(file) => {
+                    log.file = file;
+                    log.commits = 0;
+                }
+                Err(error) => warn!(?error, "failed to compact store log"),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn reports(&self) -> Result<Vec<ReportRecord>, Error> {
+        Ok(self.log.lock().unwrap().contents.reports())
+    }
+
+    fn accumulators(&self) -> Result<Vec<AccumulatorRecord>, Error> {
+        Ok(self.log.lock().unwrap().contents.accumulators())
+    }
+
+    fn collected_batch_intervals(&self) -> Result<Vec<Interval>, Error> {
+        Ok(self
+            .log
+            .lock()
+            .unwrap()
+            .contents
+            .collected_batch_intervals())
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        // Create and remove a file next to the log, as compacting it does. The
+        // lock keeps concurrent checks from tripping over each other.
+        let _log = self.log.lock().unwrap();
+        let probe_path = Self::sibling_path(&self.path, ".probe");
+        File::create(&probe_path).map_err(|e| Error::File(e, probe_path.clone()))?;
+        fs::remove_file(&probe_path).map_err(|e| Error::File(e, probe_path))
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        // Every commit is synced as it is appended, so this only syncs the
+        // file's metadata
+        let log = self.log.lock().unwrap();
+        log.file
+            .sync_all()
+            .map_err(|e| Error::File(e, self.path.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hpke::ConfigId, Duration, Time};
+    use assert_matches::assert_matches;
+
+    fn report_record(time: u64, state: ReportRecordState) -> ReportRecord {
+        ReportRecord::new(
+            &ReportShare {
+                nonce: Nonce {
+                    time: Time(time),
+                    rand: 0,
+                },
+                extensions: vec![],
+                encrypted_input_share: Ciphertext {
+                    config_id: ConfigId(1),
+                    encapsulated_context: vec![1, 2, 3],
+                    payload: vec![4, 5, 6],
+                },
+            },
+            Some(&Ciphertext {
+                config_id: ConfigId(0),
+                encapsulated_context: vec![7, 8, 9],
+                payload: vec![10, 11, 12],
+            }),
+            state,
+        )
+    }
+
+    #[test]
+    fn file_store_survives_reopen() {
+        let path = std::env::temp_dir().join(format!(
+            "ppm-prototype-store-{}.json",
+            rand::random::<u64>()
+        ));
+        let interval = Interval {
+            start: Time(100),
+            duration: Duration(100),
+        };
+        let accumulator = AccumulatorRecord {
+            interval,
+            aggregation_parameter: vec![],
+            accumulated: serde_json::json!(["1", "2"]),
+            contributions: 2,
+            checksum: [7; 32],
+            consumed_privacy_budget: 1,
+        };
+        // Accumulators of the same interval under different aggregation
+        // parameters are kept apart
+        let other_accumulator = AccumulatorRecord {
+            aggregation_parameter: vec![1, 2],
+            accumulated: serde_json::json!(["3"]),
+            ..accumulator.clone()
+        };
+
+        {
+            let store = FileStore::open(&path).unwrap();
+            store
+                .put_report(report_record(100, ReportRecordState::Waiting))
+                .unwrap();
+            store
+                .put_report(report_record(101, ReportRecordState::Waiting))
+                .unwrap();
+            store
+                .update_report_state(
+                    Nonce {
+                        time: Time(101),
+                        rand: 0,
+                    },
+                    ReportRecordState::Accumulated,
+                )
+                .unwrap();
+            store.put_accumulator(accumulator.clone()).unwrap();
+            store.put_accumulator(other_accumulator.clone()).unwrap();
+            store.put_collected_batch_interval(interval).unwrap();
+        }
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(
+            store.reports().unwrap(),
+            vec![
+                report_record(100, ReportRecordState::Waiting),
+                report_record(101, ReportRecordState::Accumulated)
+            ]
+        );
+        let reopened_report = &store.reports().unwrap()[0];
+        assert_eq!(
+            reopened_report
+                .report_share()
+                .unwrap()
+                .encrypted_input_share,
+            report_record(100, ReportRecordState::Waiting)
+                .report_share()
+                .unwrap()
+                .encrypted_input_share
+        );
+        assert!(reopened_report.encrypted_helper_share().unwrap().is_some());
+        let mut accumulators = store.accumulators().unwrap();
+        accumulators.sort_by(|a, b| a.aggregation_parameter.cmp(&b.aggregation_parameter));
+        assert_eq!(accumulators, vec![accumulator, other_accumulator]);
+        assert_eq!(store.collected_batch_intervals().unwrap(), vec![interval]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn deleted_reports_stay_deleted() {
+        let path = std::env::temp_dir().join(format!(
+            "ppm-prototype-store-{}.json",
+            rand::random::<u64>()
+        ));
+
+        {
+            let store = FileStore::open(&path).unwrap();
+            for time in 100..103 {
+                store
+                    .put_report(report_record(time, ReportRecordState::Accumulated))
+                    .unwrap();
+            }
+            store
+                .delete_reports(&[
+                    Nonce {
+                        time: Time(100),
+                        rand: 0,
+                    },
+                    Nonce {
+                        time: Time(102),
+                        rand: 0,
+                    },
+                    // Unknown reports are ignored
+                    Nonce {
+                        time: Time(200),
+                        rand: 0,
+                    },
+                ])
+                .unwrap();
+        }
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(
+            store.reports().unwrap(),
+            vec![report_record(101, ReportRecordState::Accumulated)]
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn report_progress_survives_reopen() {
+        let path = std::env::temp_dir().join(format!(
+            "ppm-prototype-store-{}.json",
+            rand::random::<u64>()
+        ));
+        let nonce = Nonce {
+            time: Time(100),
+            rand: 0,
+        };
+
+        {
+            let store = FileStore::open(&path).unwrap();
+            store
+                .put_report(
+                    report_record(100, ReportRecordState::Waiting).with_preparation(vec![1]),
+                )
+                .unwrap();
+            store
+                .commit(vec![
+                    Mutation::AddPrepareMessage {
+                        nonce,
+                        prepare_message: vec![2, 3],
+                    },
+                    Mutation::AddPrepareMessage {
+                        nonce,
+                        prepare_message: vec![4],
+                    },
+                ])
+                .unwrap();
+        }
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(
+            store.reports().unwrap()[0].preparation,
+            Some(Preparation {
+                aggregation_parameter: vec![1],
+                prepare_messages: vec![vec![2, 3], vec![4]],
+            })
+        );
+
+        // Accumulating the report ends its preparation
+        store
+            .commit(vec![
+                Mutation::UpdateReportState {
+                    nonce,
+                    state: ReportRecordState::Accumulated,
+                },
+                Mutation::AddAggregationParameter {
+                    nonce,
+                    aggregation_parameter: vec![1],
+                },
+            ])
+            .unwrap();
+        assert_eq!(store.reports().unwrap()[0].preparation, None);
+        // Preparing it anew under another parameter keeps the parameters it
+        // was accumulated under
+        store
+            .put_report(report_record(100, ReportRecordState::Waiting).with_preparation(vec![5]))
+            .unwrap();
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        let report = &store.reports().unwrap()[0];
+        assert_eq!(report.aggregation_parameters, vec![vec![1]]);
+        assert_eq!(
+            report.preparation,
+            Some(Preparation {
+                aggregation_parameter: vec![5],
+                prepare_messages: vec![],
+            })
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn commits_are_atomic() {
+        let path = std::env::temp_dir().join(format!(
+            "ppm-prototype-store-{}.jsonl",
+            rand::random::<u64>()
+        ));
+        let interval = Interval {
+            start: Time(100),
+            duration: Duration(100),
+        };
+
+        {
+            let store = FileStore::open(&path).unwrap();
+            // Updating an unknown report fails the whole commit
+            assert_matches!(
+                store.commit(vec![
+                    Mutation::PutCollectedBatchInterval(interval),
+                    Mutation::UpdateReportState {
+                        nonce: Nonce {
+                            time: Time(100),
+                            rand: 0,
+                        },
+                        state: ReportRecordState::Accumulated,
+                    },
+                ]),
+                Err(Error::UnknownReport(_))
+            );
+            assert!(store.collected_batch_intervals().unwrap().is_empty());
+
+            // A report may be updated in the commit that puts it
+            store
+                .commit(vec![
+                    Mutation::PutReport(report_record(100, ReportRecordState::Waiting)),
+                    Mutation::UpdateReportState {
+                        nonce: Nonce {
+                            time: Time(100),
+                            rand: 0,
+                        },
+                        state: ReportRecordState::Accumulated,
+                    },
+                    Mutation::PutCollectedBatchInterval(interval),
+                ])
+                .unwrap();
+        }
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(
+            store.reports().unwrap(),
+            vec![report_record(100, ReportRecordState::Accumulated)]
+        );
+        assert_eq!(store.collected_batch_intervals().unwrap(), vec![interval]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ignore_partial_log_entry() {
+        let path = std::env::temp_dir().join(format!(
+            "ppm-prototype-store-{}.jsonl",
+            rand::random::<u64>()
+        ));
+
+        {
+            let store = FileStore::open(&path).unwrap();
+            store
+                .put_report(report_record(100, ReportRecordState::Waiting))
+                .unwrap();
+        }
+
+        // Simulate a crash in the middle of appending a commit
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"Commit":[{"PutRep"#).unwrap();
+        drop(file);
+
+        {
+            let store = FileStore::open(&path).unwrap();
+            assert_eq!(
+                store.reports().unwrap(),
+                vec![report_record(100, ReportRecordState::Waiting)]
+            );
+            // Commits made after reopening aren't lost behind the partial entry
+            store
+                .put_report(report_record(101, ReportRecordState::Waiting))
+                .unwrap();
+        }
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.reports().unwrap().len(), 2);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compact_log() {
+        let path = std::env::temp_dir().join(format!(
+            "ppm-prototype-store-{}.jsonl",
+            rand::random::<u64>()
+        ));
+
+        {
+            let store = FileStore::open(&path).unwrap();
+            for _ in 0..MIN_LOG_COMMITS + 10 {
+                store
+                    .put_report(report_record(100, ReportRecordState::Waiting))
+                    .unwrap();
+            }
+        }
+
+        // The log was replaced with a snapshot once it held more commits than
+        // the store holds records
+        let log_entries = BufReader::new(File::open(&path).unwrap()).lines().count();
+        assert!(log_entries <= 11, "{} log entries", log_entries);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(
+            store.reports().unwrap(),
+            vec![report_record(100, ReportRecordState::Waiting)]
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_store_check() {
+        let directory =
+            std::env::temp_dir().join(format!("ppm-prototype-check-{}", rand::random::<u64>()));
+        fs::create_dir(&directory).unwrap();
+        let store = FileStore::open(directory.join("store.json")).unwrap();
+        store.check().unwrap();
+        assert!(!directory.join("store.json.probe").exists());
+
+        // Once its directory is gone, the store can't be written to
+        fs::remove_dir_all(&directory).unwrap();
+        assert_matches!(store.check(), Err(Error::File(_, _)));
+    }
+
+    #[test]
+    fn data_dir_store_for_other_roles() {
+        assert_matches!(
+            FileStore::from_data_dir(Role::Collector, &TaskId::random()),
+            Err(Error::InvalidRole(Role::Collector))
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! Support for aggregators serving many PPM tasks at once
+
+use crate::{
+    clock::Clock,
+    differential_privacy::NoisyAggregateShare,
+    helper_state::HelperStateKey,
+    parameters::{self, Parameters, TaskId, VdafInstance},
+    storage::Store,
+    Role,
+};
+use prio::{
+    codec::{Encode, ParameterizedDecode},
+    vdaf,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("task {0} is configured more than once")]
+    DuplicateTask(TaskId),
+}
+
+/// Everything an aggregator needs to know to serve a single PPM task.
+#[derive(Clone, Debug)]
+pub struct Task<A: vdaf::Aggregator> {
+    pub parameters: Parameters,
+    /// VDAF instance used for the task
+    pub vdaf: A,
+    /// This aggregator's VDAF verification parameter for the task
+    pub verify_parameter: A::VerifyParam,
+    /// Aggregation parameter reports are prepared under as soon as they
+    /// arrive. If `None`, reports are held until a collect request supplies the
+    /// aggregation parameter, and may be aggregated under several parameters.
+    pub aggregation_parameter: Option<A::AggregationParam>,
+    /// Where the aggregator keeps state for the task
+    pub store: Arc<dyn Store>,
+    /// Source of the current time
+    pub clock: Arc<dyn Clock>,
+    /// If set, the helper hands the prepare state of aggregate jobs to the
+    /// leader, encrypted under this key, instead of keeping it. Ignored by the
+    /// leader.
+    pub helper_state_key: Option<HelperStateKey>,
+}
+
+/// The parts of a [`Task`] that don't follow from its parameters.
+#[derive(Clone, Debug)]
+pub struct TaskOptions {
+    /// Where the aggregator keeps state for the task
+    pub store: Arc<dyn Store>,
+    /// Source of the current time
+    pub clock: Arc<dyn Clock>,
+    /// See [`Task::helper_state_key`]
+    pub helper_state_key: Option<HelperStateKey>,
+}
+
+/// Builds an aggregator serving tasks that may use different VDAFs.
+/// Implemented by [`crate::leader::LeaderBuilder`] and
+/// [`crate::helper::HelperBuilder`].
+pub trait ServeTasks: Sized {
+    /// Role of the aggregator being built
+    const ROLE: Role;
+
+    /// Serve `task`.
+    fn task<A>(self, task: Task<A>) -> Self
+    where
+        A: vdaf::Aggregator + Debug + 'static + Send + Sync,
+        A::VerifyParam: Send + Sync,
+        A::AggregationParam: Send + Sync,
+        A::PrepareStep: Send + Sync,
+        A::AggregateShare: Send + Sync + Serialize + DeserializeOwned + NoisyAggregateShare,
+        A::PrepareMessage: Send + Sync,
+        A::OutputShare: Send + Sync;
+
+    /// Serve the task described by `parameters`, with the VDAF they name.
+    /// Reports of `Hits` tasks are aggregated under the aggregation parameter
+    /// of each collect request, those of other tasks as they arrive.
+    fn task_from_parameters(
+        self,
+        parameters: Parameters,
+        options: TaskOptions,
+    ) -> Result<Self, parameters::Error> {
+        Ok(match parameters.vdaf_instance()? {
+            VdafInstance::Prio3Count64(vdaf) => {
+                self.task(Task::new(Self::ROLE, parameters, vdaf, Some(()), options)?)
+            }
+            VdafInstance::Prio3Sum64(vdaf) => {
+                self.task(Task::new(Self::ROLE, parameters, vdaf, Some(()), options)?)
+            }
+            VdafInstance::Prio3Histogram64(vdaf) => {
+                self.task(Task::new(Self::ROLE, parameters, vdaf, Some(()), options)?)
+            }
+            VdafInstance::Hits { vdaf, .. } => {
+                self.task(Task::new(Self::ROLE, parameters, vdaf, None, options)?)
+            }
+        })
+    }
+}
+
+impl<A: vdaf::Aggregator> Task<A>
+where
+    A::VerifyParam: Encode + ParameterizedDecode<A>,
+{
+    /// Construct the task an aggregator in `role` serves with `vdaf`, taking
+    /// its verification parameter from `parameters`.
+    fn new(
+        role: Role,
+        parameters: Parameters,
+        vdaf: A,
+        aggregation_parameter: Option<A::AggregationParam>,
+        options: TaskOptions,
+    ) -> Result<Self, parameters::Error> {
+        parameters.validate()?;
+        let verify_parameter = parameters.decode_vdaf_verification_parameter(role, &vdaf)?;
+
+        Ok(Self {
+            parameters,
+            vdaf,
+            verify_parameter,
+            aggregation_parameter,
+            store: options.store,
+            clock: options.clock,
+            helper_state_key: options.helper_state_key,
+        })
+    }
+}
+
+/// Per-task state of an aggregator, keyed by task ID so that incoming
+/// messages can be dispatched to the task they belong to.
+#[derive(Debug)]
+pub(crate) struct TaskRegistry<T> {
+    tasks: HashMap<TaskId, T>,
+}
+
+impl<T> TaskRegistry<T> {
+    /// Construct a registry of the provided tasks, failing if several of them
+    /// have the same ID.
+    pub(crate) fn new<I: IntoIterator<Item = (TaskId, T)>>(tasks: I) -> Result<Self, Error> {
+        let mut registry = HashMap::new();
+        for (task_id, task) in tasks {
+            if registry.insert(task_id, task).is_some() {
+                return Err(Error::DuplicateTask(task_id));
+            }
+        }
+
+        Ok(Self { tasks: registry })
+    }
+
+    /// Look up the state for a task, returning `None` if this aggregator does
+    /// not serve the task.
+    pub(crate) fn get(&self, task_id: &TaskId) -> Option<&T> {
+        self.tasks.get(task_id)
+    }
+
+    /// Iterate over the state of all tasks.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &T> {
+        self.tasks.values()
+    }
+}
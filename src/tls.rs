@@ -0,0 +1,444 @@
+//! TLS.
+//!
+//! Leader and helper serve HTTPS when given a certificate chain and private
+//! key. A helper may additionally pin the leader's certificate: it then asks
+//! clients for a certificate during the TLS handshake, and only serves its
+//! `aggregate` and `aggregate_share` routes to those presenting the pinned
+//! one. Its other routes stay open to anyone, since clients fetch the helper's
+//! HPKE config without a certificate of their own. The leader presents its
+//! certificate to helpers that ask for one, and trusts the configured
+//! certificates in addition to the usual web roots when connecting to them.
+//!
+//! warp's TLS server doesn't tell routes which certificate the client
+//! presented, so TLS connections are accepted here and handed to hyper, which
+//! runs the warp routes with the client certificate in the extensions of each
+//! request.
+
+use crate::{config_path, server::ShutdownSignal, Role};
+use futures::{
+    future::{self, BoxFuture},
+    stream, FutureExt, StreamExt,
+};
+use http::StatusCode;
+use http_api_problem::HttpApiProblem;
+use rustls_pemfile::Item;
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::Infallible,
+    fmt::{self, Debug, Formatter},
+    fs::{self, File},
+    io::{self, ErrorKind, Read},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{
+    rustls::{
+        server::AllowAnyAnonymousOrAuthenticatedClient, Certificate, PrivateKey, RootCertStore,
+        ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tracing::{debug, warn};
+use warp::{
+    filters::BoxedFilter,
+    hyper::{
+        server::{accept, Server},
+        service::{make_service_fn, service_fn, Service},
+        Body, Request,
+    },
+    Filter, Reply,
+};
+
+/// How long a client may take to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of TLS handshakes under way at once
+const MAX_CONCURRENT_HANDSHAKES: usize = 64;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("JSON parse error")]
+    JsonParse(#[from] serde_json::error::Error),
+    #[error("file error: {1}")]
+    File(#[source] io::Error, PathBuf),
+    #[error("PEM parse error")]
+    Pem(#[source] io::Error),
+    #[error("no certificate found")]
+    NoCertificate,
+    #[error("no private key found")]
+    NoPrivateKey,
+    #[error("invalid certificate")]
+    InvalidCertificate,
+    #[error("no TLS config for role {0:?}")]
+    InvalidRole(Role),
+    #[error("TLS error {0}")]
+    Rustls(#[from] tokio_rustls::rustls::Error),
+    #[error("HTTP client error {0}")]
+    HttpClient(#[from] reqwest::Error),
+}
+
+/// Paths of the PEM files making up the TLS config of a leader or helper, as
+/// found in `leader-tls.json` or `helper-tls.json`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TlsConfigFile {
+    /// The certificate to serve, followed by any intermediate certificates
+    pub certificate_chain: PathBuf,
+    /// The certificate's private key, in PKCS#8 or PKCS#1 format
+    pub private_key: PathBuf,
+    /// Certificates the leader trusts when connecting to helpers
+    #[serde(default)]
+    pub trusted_certificates: Option<PathBuf>,
+    /// The leader's certificate. If set, the helper requires it on the routes
+    /// serving the leader.
+    #[serde(default)]
+    pub leader_certificate: Option<PathBuf>,
+}
+
+impl TlsConfigFile {
+    pub fn from_json_reader<R: Read>(reader: R) -> Result<Self, Error> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Read the PEM files, resolving relative paths against `directory`.
+    pub fn load(&self, directory: &Path) -> Result<TlsConfig, Error> {
+        let read = |path: &Path| {
+            let path = directory.join(path);
+            fs::read(&path).map_err(|e| Error::File(e, path))
+        };
+
+        let mut config =
+            TlsConfig::from_pem(&read(&self.certificate_chain)?, &read(&self.private_key)?)?;
+        if let Some(path) = &self.trusted_certificates {
+            config = config.trust_certificates(&read(path)?)?;
+        }
+        if let Some(path) = &self.leader_certificate {
+            config = config.pin_leader_certificate(&read(path)?)?;
+        }
+
+        Ok(config)
+    }
+}
+
+/// TLS config of a leader or helper
+#[derive(Clone)]
+pub struct TlsConfig {
+    certificate_chain: Vec<Certificate>,
+    private_key: PrivateKey,
+    /// The certificate chain and private key in PEM, which is how reqwest
+    /// takes a client identity
+    identity_pem: Vec<u8>,
+    trusted_certificates: Vec<Certificate>,
+    leader_certificate: Option<Certificate>,
+}
+
+impl Debug for TlsConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // Don't leak the private key into logs
+        f.debug_struct("TlsConfig")
+            .field("certificate_chain", &self.certificate_chain.len())
+            .field("trusted_certificates", &self.trusted_certificates.len())
+            .field("leader_certificate", &self.leader_certificate.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsConfig {
+    /// Load the TLS config from `leader-tls.json` or `helper-tls.json` in the
+    /// default configuration directory, if that file exists. Relative paths in
+    /// the file are relative to the configuration directory.
+    pub fn from_config_file(role: Role) -> Result<Option<Self>, Error> {
+        let role_name = match role {
+            Role::Leader => "leader",
+            Role::Helper => "helper",
+            r => return Err(Error::InvalidRole(r)),
+        };
+        let directory = config_path();
+        let config_path = directory.join(format!("{}-tls.json", role_name));
+
+        match File::open(&config_path) {
+            Ok(file) => Ok(Some(
+                TlsConfigFile::from_json_reader(file)?.load(&directory)?,
+            )),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::File(e, config_path)),
+        }
+    }
+
+    /// Construct a config serving the certificate chain with the private key,
+    /// both in PEM.
+    pub fn from_pem(certificate_chain: &[u8], private_key: &[u8]) -> Result<Self, Error> {
+        let identity_pem = [certificate_chain, b"\n", private_key].concat();
+        let certificate_chain = certificates(certificate_chain)?;
+        let private_key = read_pem(private_key)?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::PKCS8Key(key) | Item::RSAKey(key) => Some(PrivateKey(key)),
+                _ => None,
+            })
+            .ok_or(Error::NoPrivateKey)?;
+
+        let config = Self {
+            certificate_chain,
+            private_key,
+            identity_pem,
+            trusted_certificates: vec![],
+            leader_certificate: None,
+        };
+        // Make sure that rustls can sign with the key
+        config.server_config()?;
+
+        Ok(config)
+    }
+
+    /// Trust the certificates in `pem` when connecting to helpers.
+    pub fn trust_certificates(mut self, pem: &[u8]) -> Result<Self, Error> {
+        self.trusted_certificates.extend(certificates(pem)?);
+        Ok(self)
+    }
+
+    /// Serve the routes for the leader only to clients presenting the
+    /// certificate in `pem`.
+    pub fn pin_leader_certificate(mut self, pem: &[u8]) -> Result<Self, Error> {
+        let certificate = certificates(pem)?.swap_remove(0);
+        self.leader_certificate = Some(certificate);
+        // Make sure the certificate can serve as a trust anchor
+        self.server_config()?;
+
+        Ok(self)
+    }
+
+    /// Construct the rustls config to serve with. If the leader's certificate
+    /// is pinned, clients are asked for a certificate, which must be the
+    /// leader's if they present one. Whether they need to present one at all is
+    /// up to the route.
+    fn server_config(&self) -> Result<ServerConfig, Error> {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match &self.leader_certificate {
+            Some(certificate) => {
+                let mut roots = RootCertStore::empty();
+                roots
+                    .add(certificate)
+                    .map_err(|_| Error::InvalidCertificate)?;
+                builder
+                    .with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config =
+            builder.with_single_cert(self.certificate_chain.clone(), self.private_key.clone())?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(config)
+    }
+
+    /// Configure an HTTP client to trust the configured certificates and to
+    /// present this config's certificate to servers that ask for one.
+    pub(crate) fn configure_client(
+        &self,
+        mut builder: reqwest::ClientBuilder,
+    ) -> Result<reqwest::ClientBuilder, Error> {
+        for certificate in &self.trusted_certificates {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_der(&certificate.0)?);
+        }
+
+        Ok(builder.identity(reqwest::Identity::from_pem(&self.identity_pem)?))
+    }
+}
+
+fn read_pem(pem: &[u8]) -> Result<Vec<Item>, Error> {
+    rustls_pemfile::read_all(&mut &pem[..]).map_err(Error::Pem)
+}
+
+/// The certificates in `pem`, of which there must be at least one
+fn certificates(pem: &[u8]) -> Result<Vec<Certificate>, Error> {
+    let certificates: Vec<_> = read_pem(pem)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(certificate) => Some(Certificate(certificate)),
+            _ => None,
+        })
+        .collect();
+    if certificates.is_empty() {
+        return Err(Error::NoCertificate);
+    }
+
+    Ok(certificates)
+}
+
+/// Certificate a client presented during the TLS handshake, added to the
+/// extensions of every request on the connection
+#[derive(Clone, Debug)]
+struct ClientCertificate(Certificate);
+
+/// Construct a warp filter rejecting requests from clients that did not
+/// present the leader's certificate, if `tls` pins one.
+pub(crate) fn require_leader_certificate(
+    tls: Option<&TlsConfig>,
+    endpoint: &'static str,
+) -> BoxedFilter<()> {
+    let leader_certificate = tls.and_then(|tls| tls.leader_certificate.clone());
+
+    warp::ext::optional::<ClientCertificate>()
+        .and_then(move |client_certificate: Option<ClientCertificate>| {
+            let authorized = match &leader_certificate {
+                Some(leader_certificate) => {
+                    client_certificate.map(|certificate| certificate.0).as_ref()
+                        == Some(leader_certificate)
+                }
+                None => true,
+            };
+
+            future::ready(if authorized {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(
+                    HttpApiProblem::new(StatusCode::FORBIDDEN)
+                        .detail("leader certificate required")
+                        .instance(endpoint),
+                ))
+            })
+        })
+        .untuple_one()
+        .boxed()
+}
+
+/// Serve `routes` over HTTPS on `address` until `shutdown` resolves. Returns
+/// the bound address and a future that serves requests.
+pub(crate) fn serve<F, R>(
+    routes: F,
+    address: SocketAddr,
+    tls: &TlsConfig,
+    shutdown: ShutdownSignal,
+) -> Result<(SocketAddr, BoxFuture<'static, ()>), io::Error>
+where
+    F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let acceptor = TlsAcceptor::from(Arc::new(
+        tls.server_config()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+    ));
+
+    let listener = std::net::TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let local_addr = listener.local_addr()?;
+
+    // Handshakes run concurrently, so that a slow client doesn't hold up the
+    // others
+    let connections = stream::unfold(listener, |listener| async move {
+        let connection = listener.accept().await;
+        Some((connection, listener))
+    })
+    .filter_map(|connection| async move {
+        match connection {
+            Ok((stream, _)) => Some(stream),
+            Err(error) => {
+                // Running out of file descriptors is the usual cause, so back
+                // off a little
+                warn!(?error, "failed to accept connection");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                None
+            }
+        }
+    })
+    .map(move |stream: TcpStream| {
+        let acceptor = acceptor.clone();
+        async move { tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await }
+    })
+    .buffer_unordered(MAX_CONCURRENT_HANDSHAKES)
+    .filter_map(|handshake| async move {
+        match handshake {
+            Ok(Ok(stream)) => Some(Ok::<_, Infallible>(stream)),
+            Ok(Err(error)) => {
+                debug!(?error, "TLS handshake failed");
+                None
+            }
+            Err(_) => {
+                debug!("TLS handshake timed out");
+                None
+            }
+        }
+    });
+
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |stream: &TlsStream<TcpStream>| {
+        let client_certificate = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .cloned()
+            .map(ClientCertificate);
+        let service = service.clone();
+
+        future::ok::<_, Infallible>(service_fn(move |mut request: Request<Body>| {
+            if let Some(client_certificate) = &client_certificate {
+                request.extensions_mut().insert(client_certificate.clone());
+            }
+            service.clone().call(request)
+        }))
+    });
+
+    let server = Server::builder(accept::from_stream(connections))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown);
+
+    Ok((
+        local_addr,
+        async move {
+            if let Err(error) = server.await {
+                warn!(?error, "server failed");
+            }
+        }
+        .boxed(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    const LEADER_CERTIFICATE: &[u8] = include_bytes!("../sample-config/tls/leader.pem");
+    const LEADER_KEY: &[u8] = include_bytes!("../sample-config/tls/leader-key.pem");
+    const HELPER_CERTIFICATE: &[u8] = include_bytes!("../sample-config/tls/helper.pem");
+    const HELPER_KEY: &[u8] = include_bytes!("../sample-config/tls/helper-key.pem");
+
+    #[test]
+    fn load_config() {
+        let config = TlsConfig::from_pem(HELPER_CERTIFICATE, HELPER_KEY)
+            .unwrap()
+            .pin_leader_certificate(LEADER_CERTIFICATE)
+            .unwrap();
+        assert_eq!(config.certificate_chain.len(), 1);
+        assert!(config.leader_certificate.is_some());
+        assert!(!format!("{:?}", config).contains("private_key"));
+
+        let config_file = TlsConfigFile::from_json_reader(
+            &include_bytes!("../sample-config/leader-tls.json")[..],
+        )
+        .unwrap();
+        let config = config_file.load(Path::new("sample-config")).unwrap();
+        assert_eq!(config.trusted_certificates.len(), 1);
+        config
+            .configure_client(reqwest::Client::builder())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert_matches!(
+            TlsConfig::from_pem(LEADER_CERTIFICATE, LEADER_CERTIFICATE),
+            Err(Error::NoPrivateKey)
+        );
+        assert_matches!(
+            TlsConfig::from_pem(LEADER_KEY, LEADER_KEY),
+            Err(Error::NoCertificate)
+        );
+    }
+}
@@ -1,25 +1,55 @@
 use assert_matches::assert_matches;
 use color_eyre::Result;
 use http::StatusCode;
+use http_api_problem::HttpApiProblem;
 use ppm_prototype::{
+    aggregate::{Aggregate, AggregateMessage, AggregateShareReq},
+    aggregation_job::AggregationDriverConfig,
     client::{self, PpmClient},
-    collect::{self, run_collect},
-    helper::run_helper,
+    clock::{Clock, MockClock},
+    collect::{
+        self, run_collect, run_collect_with_http_client, run_heavy_hitters, CollectRequest,
+        CollectResponse,
+    },
+    differential_privacy::{DifferentialPrivacy, NoisyAggregateShare},
+    health::Readiness,
+    helper::{run_helper, HelperBuilder},
+    helper_state::HelperStateKey,
     hpke,
-    leader::run_leader,
-    parameters::Parameters,
-    trace, Duration, Interval, Time,
+    leader::{run_leader, LeaderBuilder, COLLECT_JOB_TTL},
+    parameters::{Parameters, Poplar1Aes128, TaskId, VdafInstance, VdafLabel},
+    storage::{
+        self, AccumulatorRecord, FileStore, MemoryStore, Mutation, ReportRecord, ReportRecordState,
+        Store,
+    },
+    task::{ServeTasks, Task, TaskOptions},
+    tls::TlsConfig,
+    trace, Duration, Interval, Role, Time,
 };
 use prio::{
+    codec::{Decode, Encode},
     field::Field128,
     vdaf::{
-        prio3::{Prio3Aes128Sum, Prio3InputShare},
+        self,
+        poplar1::IdpfInput,
+        prio3::{Prio3Aes128Sum, Prio3InputShare, Prio3VerifyParam},
         Vdaf,
     },
 };
+use serde::{de::DeserializeOwned, Serialize};
 use serial_test::serial;
-use std::{io::Cursor, sync::Once};
+use std::{
+    collections::BTreeSet,
+    io::Cursor,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Once,
+    },
+};
 use tokio::task::JoinHandle;
+use url::Url;
+use warp::Filter;
 
 const INTERVAL_START: u64 = 1631907500;
 
@@ -31,64 +61,94 @@ struct TestCase {
     hpke_config: hpke::ConfigFile,
     client: PpmClient<Prio3Aes128Sum>,
     vdaf: Prio3Aes128Sum,
+    verify_parameters: Vec<Prio3VerifyParam<16>>,
+    options: AggregatorOptions,
     leader_handle: JoinHandle<Result<()>>,
     helper_handle: JoinHandle<Result<()>>,
 }
 
 impl TestCase {
     async fn new_tamper(tamper_leader_proof: bool, tamper_helper_proof: bool) -> Self {
-        INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+        Self::new_with_stores(
+            tamper_leader_proof,
+            tamper_helper_proof,
+            Arc::new(MemoryStore::default()),
+            Arc::new(MemoryStore::default()),
+            AggregatorOptions::default(),
+        )
+        .await
+    }
 
+    async fn new_with_stores(
+        tamper_leader_proof: bool,
+        tamper_helper_proof: bool,
+        leader_store: Arc<dyn Store>,
+        helper_store: Arc<dyn Store>,
+        options: AggregatorOptions,
+    ) -> Self {
         let parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
             "../sample-config/parameters.json"
         )))
         .unwrap();
+
+        Self::new_with_parameters(
+            parameters,
+            tamper_leader_proof,
+            tamper_helper_proof,
+            leader_store,
+            helper_store,
+            options,
+        )
+        .await
+    }
+
+    async fn new_with_parameters(
+        parameters: Parameters,
+        tamper_leader_proof: bool,
+        tamper_helper_proof: bool,
+        leader_store: Arc<dyn Store>,
+        helper_store: Arc<dyn Store>,
+        options: AggregatorOptions,
+    ) -> Self {
+        INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
         let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
             "../sample-config/hpke.json"
         )))
         .unwrap();
 
-        let leader_parameters = parameters.clone();
-        let helper_parameters = parameters.clone();
-
         let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
-        let leader_vdaf = vdaf.clone();
-        let helper_vdaf = leader_vdaf.clone();
-        let client_vdaf = leader_vdaf.clone();
-
-        let leader_hpke_config = hpke_config.leader.clone();
-        let helper_hpke_config = hpke_config.helper.clone();
 
         // Simulate negotation of verify parameter
         let (_, verify_parameters) = vdaf.setup().unwrap();
 
-        let leader_verify_parameter = verify_parameters[0].clone();
-        let helper_verify_parameter = verify_parameters[1].clone();
-
         // Spawn leader and helper tasks
-        let leader_handle = tokio::spawn(async move {
-            run_leader(
-                &leader_parameters,
-                &leader_vdaf,
-                &leader_verify_parameter,
-                &(),
-                &leader_hpke_config,
-            )
-            .await
-        });
-        let helper_handle = tokio::spawn(async move {
-            run_helper(
-                &helper_parameters,
-                &helper_vdaf,
-                &helper_verify_parameter,
-                &(),
-                &helper_hpke_config,
-            )
-            .await
-        });
+        let leader_handle = spawn_leader(
+            tasks(
+                Role::Leader,
+                vec![(parameters.clone(), leader_store)],
+                &vdaf,
+                &verify_parameters,
+                &options,
+            ),
+            &hpke_config,
+            &options,
+        );
+        let helper_handle = spawn_helper(
+            tasks(
+                Role::Helper,
+                vec![(parameters.clone(), helper_store)],
+                &vdaf,
+                &verify_parameters,
+                &options,
+            ),
+            &hpke_config,
+        );
 
         // Generate and upload 100 reports, with timestamps one second apart
-        let client = PpmClient::new(&parameters, &client_vdaf, ()).await.unwrap();
+        let client = PpmClient::new(&parameters, &vdaf, (), Arc::new(options.clock.clone()))
+            .await
+            .unwrap();
 
         // libprio doesn't currently expose a way to tamper with input shares
         // (all fields of [`Prio3InputShare`] are private) so we neuter this
@@ -105,10 +165,11 @@ impl TestCase {
             |s: &Prio3InputShare<Field128, 16>| s.clone()
         };
 
-        for count in 0..100 {
+        // The clock starts at INTERVAL_START
+        for _ in 0..100 {
             client
                 .do_upload_tamper(
-                    INTERVAL_START + count,
+                    options.clock.now().0,
                     &1,
                     &tamper_leader_proof_func
                         as &dyn Fn(&Prio3InputShare<Field128, 16>) -> Prio3InputShare<Field128, 16>,
@@ -117,6 +178,7 @@ impl TestCase {
                 )
                 .await
                 .unwrap();
+            options.clock.advance(Duration(1));
         }
 
         client.run_aggregate().await.unwrap();
@@ -126,6 +188,8 @@ impl TestCase {
             hpke_config,
             client,
             vdaf,
+            verify_parameters,
+            options,
             leader_handle,
             helper_handle,
         }
@@ -135,6 +199,50 @@ impl TestCase {
         Self::new_tamper(false, false).await
     }
 
+    /// Kill the leader and helper tasks and start new ones backed by the
+    /// provided stores.
+    async fn restart(&mut self, leader_store: Arc<dyn Store>, helper_store: Arc<dyn Store>) {
+        self.leader_handle.abort();
+        self.helper_handle.abort();
+        assert!((&mut self.leader_handle).await.unwrap_err().is_cancelled());
+        assert!((&mut self.helper_handle).await.unwrap_err().is_cancelled());
+
+        self.leader_handle = spawn_leader(
+            tasks(
+                Role::Leader,
+                vec![(self.parameters.clone(), leader_store)],
+                &self.vdaf,
+                &self.verify_parameters,
+                &self.options,
+            ),
+            &self.hpke_config,
+            &self.options,
+        );
+        self.helper_handle = spawn_helper(
+            tasks(
+                Role::Helper,
+                vec![(self.parameters.clone(), helper_store)],
+                &self.vdaf,
+                &self.verify_parameters,
+                &self.options,
+            ),
+            &self.hpke_config,
+        );
+
+        // Wait for both servers to come up
+        let http_client = reqwest::Client::new();
+        for role in [Role::Leader, Role::Helper] {
+            while self
+                .parameters
+                .hpke_config(role, &http_client)
+                .await
+                .is_err()
+            {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }
+    }
+
     async fn teardown(self) {
         // Kill leader and helper tasks
         self.leader_handle.abort();
@@ -145,6 +253,167 @@ impl TestCase {
     }
 }
 
+/// A memory store whose writes of accumulators and collected batch intervals,
+/// as well as its checks, fail while `fail` is set, and whose writes of reports
+/// fail while `fail_reports` is set
+#[derive(Debug, Default)]
+struct FailingStore {
+    memory: MemoryStore,
+    fail: AtomicBool,
+    fail_reports: AtomicBool,
+    /// If set, the number of accumulator writes that succeed before `fail` is
+    /// set
+    accumulator_writes_left: Mutex<Option<usize>>,
+}
+
+impl FailingStore {
+    fn count_accumulator_write(&self) {
+        if let Some(left) = self.accumulator_writes_left.lock().unwrap().as_mut() {
+            match left.checked_sub(1) {
+                Some(remaining) => *left = remaining,
+                None => self.fail.store(true, Ordering::SeqCst),
+            }
+        }
+    }
+
+    fn fail_if_set(&self) -> Result<(), storage::Error> {
+        Self::fail_if(&self.fail)
+    }
+
+    fn fail_if(flag: &AtomicBool) -> Result<(), storage::Error> {
+        if flag.load(Ordering::SeqCst) {
+            Err(storage::Error::File(
+                std::io::Error::new(std::io::ErrorKind::Other, "injected failure"),
+                "failing-store".into(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Store for FailingStore {
+    fn commit(&self, mutations: Vec<Mutation>) -> Result<(), storage::Error> {
+        for mutation in &mutations {
+            match mutation {
+                Mutation::PutReport(_) => Self::fail_if(&self.fail_reports)?,
+                Mutation::PutAccumulator(_) => {
+                    self.count_accumulator_write();
+                    self.fail_if_set()?
+                }
+                Mutation::PutCollectedBatchInterval(_) => self.fail_if_set()?,
+                _ => {}
+            }
+        }
+        self.memory.commit(mutations)
+    }
+
+    fn reports(&self) -> Result<Vec<ReportRecord>, storage::Error> {
+        self.memory.reports()
+    }
+
+    fn accumulators(&self) -> Result<Vec<AccumulatorRecord>, storage::Error> {
+        self.memory.accumulators()
+    }
+
+    fn collected_batch_intervals(&self) -> Result<Vec<Interval>, storage::Error> {
+        self.memory.collected_batch_intervals()
+    }
+
+    fn check(&self) -> Result<(), storage::Error> {
+        self.fail_if_set()
+    }
+}
+
+/// Aggregator settings that aren't part of the task parameters
+#[derive(Clone)]
+struct AggregatorOptions {
+    helper_state_key: Option<HelperStateKey>,
+    /// If set, the leader aggregates on its own schedule
+    aggregation_driver: Option<AggregationDriverConfig>,
+    /// Clock shared by the aggregators and clients, starting at
+    /// INTERVAL_START
+    clock: MockClock,
+}
+
+impl Default for AggregatorOptions {
+    fn default() -> Self {
+        Self {
+            helper_state_key: None,
+            aggregation_driver: None,
+            clock: MockClock::new(Time(INTERVAL_START)),
+        }
+    }
+}
+
+/// Construct the tasks an aggregator in `role` serves from each task's
+/// parameters and store.
+fn tasks<A: vdaf::Aggregator<AggregationParam = ()> + Clone>(
+    role: Role,
+    tasks: Vec<(Parameters, Arc<dyn Store>)>,
+    vdaf: &A,
+    verify_parameters: &[A::VerifyParam],
+    options: &AggregatorOptions,
+) -> Vec<Task<A>> {
+    tasks_with_aggregation_parameter(role, tasks, vdaf, verify_parameters, Some(()), options)
+}
+
+/// Like [`tasks`], but with the provided eager aggregation parameter, or none.
+fn tasks_with_aggregation_parameter<A: vdaf::Aggregator + Clone>(
+    role: Role,
+    tasks: Vec<(Parameters, Arc<dyn Store>)>,
+    vdaf: &A,
+    verify_parameters: &[A::VerifyParam],
+    aggregation_parameter: Option<A::AggregationParam>,
+    options: &AggregatorOptions,
+) -> Vec<Task<A>> {
+    tasks
+        .into_iter()
+        .map(|(parameters, store)| Task {
+            parameters,
+            vdaf: vdaf.clone(),
+            verify_parameter: verify_parameters[role.index()].clone(),
+            aggregation_parameter: aggregation_parameter.clone(),
+            store,
+            helper_state_key: options.helper_state_key.clone(),
+            clock: Arc::new(options.clock.clone()),
+        })
+        .collect()
+}
+
+fn spawn_leader<A>(
+    tasks: Vec<Task<A>>,
+    hpke_config: &hpke::ConfigFile,
+    options: &AggregatorOptions,
+) -> JoinHandle<Result<()>>
+where
+    A: vdaf::Aggregator + 'static + Send + Sync,
+    A::VerifyParam: Send + Sync,
+    A::AggregationParam: Send + Sync,
+    A::PrepareStep: Send + Sync,
+    A::AggregateShare: Send + Sync + Serialize + DeserializeOwned + NoisyAggregateShare,
+    A::PrepareMessage: Send + Sync,
+    A::OutputShare: Send + Sync,
+{
+    let hpke_config = hpke_config.leader.clone();
+    let aggregation_driver = options.aggregation_driver.clone();
+
+    tokio::spawn(async move { run_leader(tasks, &hpke_config, aggregation_driver).await })
+}
+
+fn spawn_helper<A>(tasks: Vec<Task<A>>, hpke_config: &hpke::ConfigFile) -> JoinHandle<Result<()>>
+where
+    A: vdaf::Aggregator + 'static + Send + Sync,
+    A::VerifyParam: Send + Sync,
+    A::AggregationParam: Send + Sync,
+    A::PrepareStep: Send + Sync,
+    A::AggregateShare: Send + Sync + Serialize + DeserializeOwned + NoisyAggregateShare,
+{
+    let hpke_config = hpke_config.helper.clone();
+
+    tokio::spawn(async move { run_helper(tasks, &hpke_config).await })
+}
+
 #[tokio::test]
 #[serial]
 async fn successful_aggregate() {
@@ -201,6 +470,164 @@ async fn insufficient_batch_size() {
         assert_eq!(problem_document.type_url, Some("urn:ietf:params:ppm:error:insufficientBatchSize".to_string()));
     });
 
+    // The failed collect request didn't consume any privacy budget
+    let sum = run_collect(
+        &test_case.parameters,
+        &test_case.hpke_config.collector,
+        Interval {
+            start: Time(INTERVAL_START),
+            duration: Duration(100),
+        },
+        test_case.vdaf.clone(),
+        &(),
+        aggregate_share_len,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(sum.0, 100);
+
+    test_case.teardown().await;
+}
+
+#[tokio::test]
+#[serial]
+async fn helper_failure_during_collect() {
+    let helper_store = Arc::new(FailingStore::default());
+    let test_case = TestCase::new_with_stores(
+        false,
+        false,
+        Arc::new(MemoryStore::default()),
+        helper_store.clone(),
+        AggregatorOptions::default(),
+    )
+    .await;
+    let collect_interval = Interval {
+        start: Time(INTERVAL_START),
+        duration: Duration(100),
+    };
+
+    // The helper can't record that it released its aggregate share, so it
+    // doesn't release it
+    helper_store.fail.store(true, Ordering::SeqCst);
+    let error_document = run_collect(
+        &test_case.parameters,
+        &test_case.hpke_config.collector,
+        collect_interval,
+        test_case.vdaf.clone(),
+        &(),
+        test_case.vdaf.output_len(),
+    )
+    .await
+    .unwrap_err();
+
+    assert_matches!(error_document, collect::Error::ProblemDocument(problem_document) => {
+        assert_eq!(problem_document.instance, Some("collect".to_string()));
+        assert_eq!(problem_document.status, Some(StatusCode::INTERNAL_SERVER_ERROR));
+    });
+
+    // None of the release made it into the helper's store
+    assert!(helper_store.collected_batch_intervals().unwrap().is_empty());
+    assert!(helper_store
+        .accumulators()
+        .unwrap()
+        .iter()
+        .all(|accumulator| accumulator.consumed_privacy_budget == 0));
+
+    // Both aggregators gave back the privacy budget, so the interval isn't
+    // considered collected and still takes reports
+    test_case
+        .client
+        .do_upload(INTERVAL_START + 10, &1)
+        .await
+        .unwrap();
+
+    // Once the helper's store recovers, the interval can be collected
+    helper_store.fail.store(false, Ordering::SeqCst);
+    let sum = run_collect(
+        &test_case.parameters,
+        &test_case.hpke_config.collector,
+        collect_interval,
+        test_case.vdaf.clone(),
+        &(),
+        test_case.vdaf.output_len(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(sum.0, 100);
+
+    test_case.teardown().await;
+}
+
+#[tokio::test]
+#[serial]
+async fn leader_failure_during_collect() {
+    let leader_store = Arc::new(FailingStore::default());
+    let helper_store: Arc<dyn Store> = Arc::new(MemoryStore::default());
+    let test_case = TestCase::new_with_stores(
+        false,
+        false,
+        leader_store.clone(),
+        helper_store.clone(),
+        AggregatorOptions::default(),
+    )
+    .await;
+    let collect_interval = Interval {
+        start: Time(INTERVAL_START),
+        duration: Duration(100),
+    };
+
+    // The leader gets the helper's aggregate share but can't record that it
+    // released its own, so it releases neither
+    leader_store.fail.store(true, Ordering::SeqCst);
+    let error_document = run_collect(
+        &test_case.parameters,
+        &test_case.hpke_config.collector,
+        collect_interval,
+        test_case.vdaf.clone(),
+        &(),
+        test_case.vdaf.output_len(),
+    )
+    .await
+    .unwrap_err();
+
+    assert_matches!(error_document, collect::Error::ProblemDocument(problem_document) => {
+        assert_eq!(problem_document.instance, Some("collect".to_string()));
+        assert_eq!(problem_document.status, Some(StatusCode::INTERNAL_SERVER_ERROR));
+    });
+
+    // The leader gave back its privacy budget, but the helper isn't told that
+    // the share wasn't released and keeps its budget spent
+    assert!(leader_store.collected_batch_intervals().unwrap().is_empty());
+    assert_eq!(
+        helper_store.collected_batch_intervals().unwrap(),
+        vec![collect_interval]
+    );
+    assert!(helper_store
+        .accumulators()
+        .unwrap()
+        .iter()
+        .all(|accumulator| accumulator.consumed_privacy_budget == 1));
+
+    // So even once the leader's store recovers, the helper refuses to release
+    // the interval again
+    leader_store.fail.store(false, Ordering::SeqCst);
+    let error_document = run_collect(
+        &test_case.parameters,
+        &test_case.hpke_config.collector,
+        collect_interval,
+        test_case.vdaf.clone(),
+        &(),
+        test_case.vdaf.output_len(),
+    )
+    .await
+    .unwrap_err();
+
+    assert_matches!(error_document, collect::Error::ProblemDocument(problem_document) => {
+        assert_eq!(problem_document.type_url, Some("urn:ietf:params:ppm:error:privacyBudgetExceeded".to_string()));
+    });
+
     test_case.teardown().await;
 }
 
@@ -230,8 +657,9 @@ async fn exceed_privacy_budget() {
 
     assert_eq!(sum.0, 100);
 
-    // Collect again over same interval. Should fail because privacy budget is
-    // exceeded.
+    // Collect again over same interval, once the first collect job is gone.
+    // Should fail because privacy budget is exceeded.
+    test_case.options.clock.advance(COLLECT_JOB_TTL);
     let error_document = run_collect(
         &test_case.parameters,
         &test_case.hpke_config.collector,
@@ -418,3 +846,2560 @@ async fn report_uploaded_after_interval_collected() {
 
     test_case.teardown().await;
 }
+
+#[tokio::test]
+#[serial]
+async fn restart_between_aggregate_and_collect() {
+    let state_dir =
+        std::env::temp_dir().join(format!("ppm-prototype-restart-{}", rand::random::<u64>()));
+    std::fs::create_dir_all(&state_dir).unwrap();
+    let leader_state = state_dir.join("leader-state.json");
+    let helper_state = state_dir.join("helper-state.json");
+
+    let mut test_case = TestCase::new_with_stores(
+        false,
+        false,
+        Arc::new(FileStore::open(&leader_state).unwrap()),
+        Arc::new(FileStore::open(&helper_state).unwrap()),
+        AggregatorOptions::default(),
+    )
+    .await;
+    let aggregate_share_len = test_case.vdaf.output_len();
+
+    // Kill leader and helper after aggregation and bring them back up from what
+    // they wrote to disk.
+    test_case
+        .restart(
+            Arc::new(FileStore::open(&leader_state).unwrap()),
+            Arc::new(FileStore::open(&helper_state).unwrap()),
+        )
+        .await;
+
+    let collect_interval = Interval {
+        start: Time(INTERVAL_START),
+        duration: Duration(100),
+    };
+
+    let sum = run_collect(
+        &test_case.parameters,
+        &test_case.hpke_config.collector,
+        collect_interval,
+        test_case.vdaf.clone(),
+        &(),
+        aggregate_share_len,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(sum.0, 100);
+
+    // Restart again. The collected intervals' privacy budget must still be
+    // consumed.
+    test_case
+        .restart(
+            Arc::new(FileStore::open(&leader_state).unwrap()),
+            Arc::new(FileStore::open(&helper_state).unwrap()),
+        )
+        .await;
+
+    let error_document = run_collect(
+        &test_case.parameters,
+        &test_case.hpke_config.collector,
+        collect_interval,
+        test_case.vdaf.clone(),
+        &(),
+        aggregate_share_len,
+    )
+    .await
+    .unwrap_err();
+
+    assert_matches!(error_document, collect::Error::ProblemDocument(problem_document) => {
+        assert_eq!(problem_document.type_url, Some("urn:ietf:params:ppm:error:privacyBudgetExceeded".to_string()));
+    });
+
+    test_case.teardown().await;
+    std::fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn unauthenticated_aggregate_share_request() {
+    let test_case = TestCase::new().await;
+
+    // A third party that doesn't know the aggregator auth key can't get the
+    // helper to release its aggregate share.
+    let aggregate_message = AggregateMessage {
+        aggregate: Aggregate::ShareRequest(AggregateShareReq {
+            task_id: test_case.parameters.task_id,
+            batch_interval: Interval {
+                start: Time(INTERVAL_START),
+                duration: Duration(100),
+            },
+            aggregation_parameter: vec![],
+        }),
+        tag: [0u8; 32],
+    };
+
+    let response = reqwest::Client::new()
+        .post(test_case.parameters.aggregate_share_endpoint().unwrap())
+        .body(aggregate_message.get_encoded())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let problem_document: HttpApiProblem = response.json().await.unwrap();
+    assert_eq!(
+        problem_document.instance,
+        Some("aggregate_share".to_string())
+    );
+    assert_eq!(
+        problem_document.type_url,
+        Some("urn:ietf:params:ppm:error:invalidHmac".to_string())
+    );
+
+    // The rejected request must not have consumed the privacy budget
+    let sum = run_collect(
+        &test_case.parameters,
+        &test_case.hpke_config.collector,
+        Interval {
+            start: Time(INTERVAL_START),
+            duration: Duration(100),
+        },
+        test_case.vdaf.clone(),
+        &(),
+        test_case.vdaf.output_len(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(sum.0, 100);
+
+    test_case.teardown().await;
+}
+
+#[tokio::test]
+#[serial]
+async fn poll_collect_job() {
+    let test_case = TestCase::new().await;
+    let batch_interval = Interval {
+        start: Time(INTERVAL_START),
+        duration: Duration(100),
+    };
+    let collect_request: CollectRequest<Prio3Aes128Sum> = CollectRequest {
+        task_id: test_case.parameters.task_id,
+        batch_interval,
+        aggregation_parameter: (),
+    };
+    let http_client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    // The leader answers the collect request with the URI of a collect job
+    let response = http_client
+        .post(test_case.parameters.collect_endpoint().unwrap())
+        .body(collect_request.get_encoded())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let collect_job_uri = response.headers()[http::header::LOCATION]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert_eq!(
+        collect_job_uri,
+        test_case
+            .parameters
+            .collect_job_uri(collect::CollectJobId(0))
+            .unwrap()
+            .as_str()
+    );
+
+    // Poll until the job finishes
+    let collect_response = loop {
+        let response = http_client.get(&collect_job_uri).send().await.unwrap();
+        match response.status() {
+            StatusCode::ACCEPTED => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            StatusCode::OK => break response.bytes().await.unwrap(),
+            status => panic!("unexpected status polling collect job: {}", status),
+        }
+    };
+    assert_eq!(
+        CollectResponse::get_decoded(&collect_response)
+            .unwrap()
+            .encrypted_agg_shares
+            .len(),
+        2
+    );
+
+    // Polling a finished job again returns the same shares without consuming
+    // privacy budget again
+    let response = http_client.get(&collect_job_uri).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.bytes().await.unwrap(), collect_response);
+
+    // Jobs that were never created can't be polled
+    let response = http_client
+        .get(
+            test_case
+                .parameters
+                .collect_job_uri(collect::CollectJobId(1))
+                .unwrap(),
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let problem_document: HttpApiProblem = response.json().await.unwrap();
+    assert_eq!(problem_document.instance, Some("collect".to_string()));
+
+    // A new collect request over the same interval gets the finished job
+    // back, without consuming privacy budget
+    let response = http_client
+        .post(test_case.parameters.collect_endpoint().unwrap())
+        .body(collect_request.get_encoded())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(
+        response.headers()[http::header::LOCATION].to_str().unwrap(),
+        collect_job_uri
+    );
+
+    // Once the job has been evicted, it can't be polled any more, and a new
+    // collect request over the same interval does consume budget. The new
+    // job's failure is reported when the job is polled.
+    test_case.options.clock.advance(COLLECT_JOB_TTL);
+    let response = http_client.get(&collect_job_uri).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let error_document = run_collect(
+        &test_case.parameters,
+        &test_case.hpke_config.collector,
+        batch_interval,
+        test_case.vdaf.clone(),
+        &(),
+        test_case.vdaf.output_len(),
+    )
+    .await
+    .unwrap_err();
+
+    assert_matches!(error_document, collect::Error::ProblemDocument(problem_document) => {
+        assert_eq!(problem_document.instance, Some("collect".to_string()));
+        assert_eq!(problem_document.status, Some(StatusCode::BAD_REQUEST));
+        assert_eq!(problem_document.type_url, Some("urn:ietf:params:ppm:error:privacyBudgetExceeded".to_string()));
+    });
+
+    test_case.teardown().await;
+}
+
+#[tokio::test]
+#[serial]
+async fn aggregators_disagree_on_batch() {
+    // Seed the helper with an accumulator claiming a report the leader never
+    // saw, so that the aggregate shares cover different sets of reports
+    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let helper_store = Arc::new(MemoryStore::default());
+    helper_store
+        .put_accumulator(AccumulatorRecord {
+            interval: Interval {
+                start: Time(INTERVAL_START),
+                duration: Duration(50),
+            },
+            aggregation_parameter: vec![],
+            accumulated: serde_json::to_value(vdaf::Aggregator::aggregate(&vdaf, &(), []).unwrap())
+                .unwrap(),
+            contributions: 1,
+            checksum: [1; 32],
+            consumed_privacy_budget: 0,
+        })
+        .unwrap();
+
+    let test_case = TestCase::new_with_stores(
+        false,
+        false,
+        Arc::new(MemoryStore::default()),
+        helper_store,
+        AggregatorOptions::default(),
+    )
+    .await;
+
+    let error = run_collect(
+        &test_case.parameters,
+        &test_case.hpke_config.collector,
+        Interval {
+            start: Time(INTERVAL_START),
+            duration: Duration(100),
+        },
+        test_case.vdaf.clone(),
+        &(),
+        test_case.vdaf.output_len(),
+    )
+    .await
+    .unwrap_err();
+
+    assert_matches!(
+        error,
+        collect::Error::BatchMismatch {
+            leader_report_count: 100,
+            helper_report_count: 101,
+        }
+    );
+
+    test_case.teardown().await;
+}
+
+#[tokio::test]
+#[serial]
+async fn multiple_tasks() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let first_parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+    let mut second_parameters = first_parameters.clone();
+    second_parameters.task_id = TaskId::random();
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+
+    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let (_, verify_parameters) = vdaf.setup().unwrap();
+    let options = AggregatorOptions::default();
+
+    let stores = |parameters: &[&Parameters]| {
+        parameters
+            .iter()
+            .map(|p| {
+                (
+                    (*p).clone(),
+                    Arc::new(MemoryStore::default()) as Arc<dyn Store>,
+                )
+            })
+            .collect()
+    };
+    let both_tasks = [&first_parameters, &second_parameters];
+    let leader_handle = spawn_leader(
+        tasks(
+            Role::Leader,
+            stores(&both_tasks),
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+        &options,
+    );
+    let helper_handle = spawn_helper(
+        tasks(
+            Role::Helper,
+            stores(&both_tasks),
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+    );
+
+    // Upload a different number of reports to each task so that the tasks'
+    // aggregates can't be mixed up.
+    let first_client = PpmClient::new(
+        &first_parameters,
+        &vdaf,
+        (),
+        Arc::new(options.clock.clone()),
+    )
+    .await
+    .unwrap();
+    let second_client = PpmClient::new(
+        &second_parameters,
+        &vdaf,
+        (),
+        Arc::new(options.clock.clone()),
+    )
+    .await
+    .unwrap();
+    for count in 0..100 {
+        first_client
+            .do_upload(INTERVAL_START + count, &1)
+            .await
+            .unwrap();
+        second_client
+            .do_upload(INTERVAL_START + count, &2)
+            .await
+            .unwrap();
+    }
+
+    // A single aggregate request aggregates the reports of every task
+    first_client.run_aggregate().await.unwrap();
+
+    let collect_interval = Interval {
+        start: Time(INTERVAL_START),
+        duration: Duration(100),
+    };
+    for (parameters, expected_sum) in [(&first_parameters, 100), (&second_parameters, 200)] {
+        let sum = run_collect(
+            parameters,
+            &hpke_config.collector,
+            collect_interval,
+            vdaf.clone(),
+            &(),
+            vdaf.output_len(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(sum.0, expected_sum);
+    }
+
+    // Reports for a task the leader doesn't serve are rejected
+    let mut unknown_parameters = first_parameters.clone();
+    unknown_parameters.task_id = TaskId::random();
+    let unknown_client = PpmClient::new(
+        &unknown_parameters,
+        &vdaf,
+        (),
+        Arc::new(options.clock.clone()),
+    )
+    .await
+    .unwrap();
+    let error_document = unknown_client
+        .do_upload(INTERVAL_START, &1)
+        .await
+        .unwrap_err();
+
+    assert_matches!(error_document, client::Error::ProblemDocument(problem_document) => {
+        assert_eq!(problem_document.instance, Some("upload".to_string()));
+        assert_eq!(problem_document.status, Some(StatusCode::BAD_REQUEST));
+        assert_eq!(problem_document.type_url, Some("urn:ietf:params:ppm:error:unrecognizedTask".to_string()));
+    });
+
+    leader_handle.abort();
+    helper_handle.abort();
+    assert!(leader_handle.await.unwrap_err().is_cancelled());
+    assert!(helper_handle.await.unwrap_err().is_cancelled());
+}
+
+#[tokio::test]
+#[serial]
+async fn duplicate_task_ids() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let (_, verify_parameters) = vdaf.setup().unwrap();
+    let options = AggregatorOptions::default();
+    let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+
+    // Aggregators refuse to start rather than serve one of two tasks with the
+    // same ID
+    let stores = || {
+        vec![
+            (
+                parameters.clone(),
+                Arc::new(MemoryStore::default()) as Arc<dyn Store>,
+            ),
+            (parameters.clone(), Arc::new(MemoryStore::default())),
+        ]
+    };
+    let error = LeaderBuilder::new(&hpke_config.leader)
+        .tasks(tasks(
+            Role::Leader,
+            stores(),
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ))
+        .address(localhost)
+        .start()
+        .unwrap_err();
+    assert!(
+        error.to_string().contains("configured more than once"),
+        "{}",
+        error
+    );
+    let error = HelperBuilder::new(&hpke_config.helper)
+        .tasks(tasks(
+            Role::Helper,
+            stores(),
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ))
+        .address(localhost)
+        .start()
+        .unwrap_err();
+    assert!(
+        error.to_string().contains("configured more than once"),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn tasks_with_different_vdafs() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let sum_parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+    let count_parameters = Parameters {
+        task_id: TaskId::random(),
+        vdaf: VdafLabel::Prio3Count64,
+        ..sum_parameters.clone()
+    };
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+
+    let sum_vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let (_, sum_verify_parameters) = sum_vdaf.setup().unwrap();
+    let count_vdaf = assert_matches!(
+        count_parameters.vdaf_instance(),
+        Ok(VdafInstance::Prio3Count64(vdaf)) => vdaf
+    );
+    let options = AggregatorOptions::default();
+
+    // One leader and one helper serve both tasks. The count task is
+    // constructed from its parameters, as the aggregator binaries do.
+    let store = |parameters: &Parameters| {
+        vec![(
+            parameters.clone(),
+            Arc::new(MemoryStore::default()) as Arc<dyn Store>,
+        )]
+    };
+    let task_options = || TaskOptions {
+        store: Arc::new(MemoryStore::default()),
+        clock: Arc::new(options.clock.clone()),
+        helper_state_key: None,
+    };
+    let leader = LeaderBuilder::new(&hpke_config.leader)
+        .tasks(tasks(
+            Role::Leader,
+            store(&sum_parameters),
+            &sum_vdaf,
+            &sum_verify_parameters,
+            &options,
+        ))
+        .task_from_parameters(count_parameters.clone(), task_options())
+        .unwrap()
+        .start()
+        .unwrap();
+    let helper = HelperBuilder::new(&hpke_config.helper)
+        .tasks(tasks(
+            Role::Helper,
+            store(&sum_parameters),
+            &sum_vdaf,
+            &sum_verify_parameters,
+            &options,
+        ))
+        .task_from_parameters(count_parameters.clone(), task_options())
+        .unwrap()
+        .start()
+        .unwrap();
+
+    let sum_client = PpmClient::new(
+        &sum_parameters,
+        &sum_vdaf,
+        (),
+        Arc::new(options.clock.clone()),
+    )
+    .await
+    .unwrap();
+    let count_client = PpmClient::new(
+        &count_parameters,
+        &count_vdaf,
+        (),
+        Arc::new(options.clock.clone()),
+    )
+    .await
+    .unwrap();
+    for count in 0..100 {
+        sum_client
+            .do_upload(INTERVAL_START + count, &3)
+            .await
+            .unwrap();
+        count_client
+            .do_upload(INTERVAL_START + count, &(count % 2))
+            .await
+            .unwrap();
+    }
+    sum_client.run_aggregate().await.unwrap();
+
+    let collect_interval = Interval {
+        start: Time(INTERVAL_START),
+        duration: Duration(100),
+    };
+    let sum = run_collect(
+        &sum_parameters,
+        &hpke_config.collector,
+        collect_interval,
+        sum_vdaf.clone(),
+        &(),
+        sum_vdaf.output_len(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(sum.0, 300);
+
+    let count = run_collect(
+        &count_parameters,
+        &hpke_config.collector,
+        collect_interval,
+        count_vdaf.clone(),
+        &(),
+        count_vdaf.output_len(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(count.0, 50);
+
+    leader.shutdown().await.unwrap();
+    helper.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn stateless_helper() {
+    let helper_store = Arc::new(MemoryStore::default());
+    let test_case = TestCase::new_with_stores(
+        false,
+        false,
+        Arc::new(MemoryStore::default()),
+        helper_store.clone(),
+        AggregatorOptions {
+            helper_state_key: Some(HelperStateKey::generate()),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // The helper only remembers which reports it accumulated, until they are
+    // collected
+    let reports = helper_store.reports().unwrap();
+    assert_eq!(reports.len(), 100);
+    assert!(reports
+        .iter()
+        .all(|report| report.state == ReportRecordState::Accumulated));
+
+    let sum = run_collect(
+        &test_case.parameters,
+        &test_case.hpke_config.collector,
+        Interval {
+            start: Time(INTERVAL_START),
+            duration: Duration(100),
+        },
+        test_case.vdaf.clone(),
+        &(),
+        test_case.vdaf.output_len(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(sum.0, 100);
+
+    test_case.teardown().await;
+}
+
+#[tokio::test]
+#[serial]
+async fn bounded_aggregation_jobs() {
+    let mut parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+    parameters.max_aggregation_job_size = 7;
+
+    let test_case = TestCase::new_with_parameters(
+        parameters,
+        false,
+        false,
+        Arc::new(MemoryStore::default()),
+        Arc::new(MemoryStore::default()),
+        AggregatorOptions::default(),
+    )
+    .await;
+
+    // Upload reports into the next batch interval and aggregate again. Only
+    // the new reports go into new jobs.
+    for _ in 100..200 {
+        test_case.client.upload(&1).await.unwrap();
+        test_case.options.clock.advance(Duration(1));
+    }
+    test_case.client.run_aggregate().await.unwrap();
+    // Nothing left to aggregate
+    test_case.client.run_aggregate().await.unwrap();
+
+    let sum = run_collect(
+        &test_case.parameters,
+        &test_case.hpke_config.collector,
+        Interval {
+            start: Time(INTERVAL_START),
+            duration: Duration(200),
+        },
+        test_case.vdaf.clone(),
+        &(),
+        test_case.vdaf.output_len(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(sum.0, 200);
+
+    test_case.teardown().await;
+}
+
+#[tokio::test]
+#[serial]
+async fn scheduled_aggregation() {
+    let test_case = TestCase::new_with_stores(
+        false,
+        false,
+        Arc::new(MemoryStore::default()),
+        Arc::new(MemoryStore::default()),
+        AggregatorOptions {
+            aggregation_driver: Some(AggregationDriverConfig {
+                interval: Duration(1),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Upload reports into the next batch interval without asking the leader to
+    // aggregate them, then give the leader time to do so on its own.
+    for _ in 100..200 {
+        test_case.client.upload(&1).await.unwrap();
+        test_case.options.clock.advance(Duration(1));
+    }
+    tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+
+    let sum = run_collect(
+        &test_case.parameters,
+        &test_case.hpke_config.collector,
+        Interval {
+            start: Time(INTERVAL_START + 100),
+            duration: Duration(100),
+        },
+        test_case.vdaf.clone(),
+        &(),
+        test_case.vdaf.output_len(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(sum.0, 100);
+
+    test_case.teardown().await;
+}
+
+#[tokio::test]
+#[serial]
+async fn upload_during_aggregation() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let (_, verify_parameters) = vdaf.setup().unwrap();
+    let options = AggregatorOptions::default();
+
+    let leader_handle = spawn_leader(
+        tasks(
+            Role::Leader,
+            vec![(parameters.clone(), Arc::new(MemoryStore::default()))],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+        &options,
+    );
+
+    // Stand in for a helper that never answers aggregate requests
+    let helper_port = parameters.aggregator_endpoints[Role::Helper.index()]
+        .port()
+        .unwrap();
+    let stalled_aggregate = warp::post()
+        .and(warp::path("aggregate"))
+        .then(futures::future::pending::<&'static str>);
+    let helper_handle = tokio::spawn(
+        warp::serve(
+            hpke_config
+                .helper
+                .warp_endpoint()
+                .unwrap()
+                .or(stalled_aggregate),
+        )
+        .run(([127, 0, 0, 1], helper_port)),
+    );
+
+    let client = PpmClient::new(&parameters, &vdaf, (), Arc::new(options.clock.clone()))
+        .await
+        .unwrap();
+    for count in 0..10 {
+        client.do_upload(INTERVAL_START + count, &1).await.unwrap();
+    }
+
+    let aggregate_client = PpmClient::new(&parameters, &vdaf, (), Arc::new(options.clock.clone()))
+        .await
+        .unwrap();
+    let mut aggregate_handle = tokio::spawn(async move { aggregate_client.run_aggregate().await });
+    // Give the leader time to send the aggregate request to the helper
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    // Uploads don't wait for the aggregation to finish
+    tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        client.do_upload(INTERVAL_START + 10, &1),
+    )
+    .await
+    .expect("upload blocked by aggregation")
+    .unwrap();
+    // The aggregation is still waiting on the helper
+    assert!(futures::FutureExt::now_or_never(&mut aggregate_handle).is_none());
+
+    aggregate_handle.abort();
+    helper_handle.abort();
+    leader_handle.abort();
+    assert!(leader_handle.await.unwrap_err().is_cancelled());
+}
+
+#[tokio::test]
+#[serial]
+async fn overlapping_batch_intervals() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let parameters = Parameters {
+        max_batch_lifetime: 3,
+        min_batch_size: 1,
+        ..Parameters::from_json_reader(Cursor::new(include_bytes!(
+            "../sample-config/parameters.json"
+        )))
+        .unwrap()
+    };
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let (_, verify_parameters) = vdaf.setup().unwrap();
+    let options = AggregatorOptions::default();
+
+    let leader_handle = spawn_leader(
+        tasks(
+            Role::Leader,
+            vec![(parameters.clone(), Arc::new(MemoryStore::default()))],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+        &options,
+    );
+    let helper_handle = spawn_helper(
+        tasks(
+            Role::Helper,
+            vec![(parameters.clone(), Arc::new(MemoryStore::default()))],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+    );
+
+    // One report per second over three minimum batch durations
+    let client = PpmClient::new(&parameters, &vdaf, (), Arc::new(options.clock.clone()))
+        .await
+        .unwrap();
+    for _ in 0..150 {
+        client.upload(&1).await.unwrap();
+        options.clock.advance(Duration(1));
+    }
+    client.run_aggregate().await.unwrap();
+
+    let collect = |start: u64, duration: u64| {
+        run_collect(
+            &parameters,
+            &hpke_config.collector,
+            Interval {
+                start: Time(INTERVAL_START + start),
+                duration: Duration(duration),
+            },
+            vdaf.clone(),
+            &(),
+            vdaf.output_len(),
+        )
+    };
+
+    assert_eq!(collect(0, 100).await.unwrap().0, 100);
+
+    // Intervals that partially overlap the collected one would let the
+    // collector difference the results
+    for (start, duration) in [(50, 100), (0, 50), (0, 150)] {
+        assert_matches!(
+            collect(start, duration).await.unwrap_err(),
+            collect::Error::ProblemDocument(problem_document) => {
+                assert_eq!(problem_document.instance, Some("collect".to_string()));
+                assert_eq!(problem_document.status, Some(StatusCode::BAD_REQUEST));
+                assert_eq!(
+                    problem_document.type_url,
+                    Some("urn:ietf:params:ppm:error:batchOverlap".to_string())
+                );
+            }
+        );
+    }
+
+    // Disjoint intervals and exact repeats are fine
+    assert_eq!(collect(100, 50).await.unwrap().0, 50);
+    assert_eq!(collect(0, 100).await.unwrap().0, 100);
+
+    leader_handle.abort();
+    helper_handle.abort();
+    assert!(leader_handle.await.unwrap_err().is_cancelled());
+    assert!(helper_handle.await.unwrap_err().is_cancelled());
+}
+
+#[tokio::test]
+#[serial]
+async fn report_time_bounds() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let parameters = Parameters {
+        max_report_age: Some(Duration(3600)),
+        ..Parameters::from_json_reader(Cursor::new(include_bytes!(
+            "../sample-config/parameters.json"
+        )))
+        .unwrap()
+    };
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let (_, verify_parameters) = vdaf.setup().unwrap();
+    let options = AggregatorOptions::default();
+
+    let leader_handle = spawn_leader(
+        tasks(
+            Role::Leader,
+            vec![(parameters.clone(), Arc::new(MemoryStore::default()))],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+        &options,
+    );
+    let helper_handle = spawn_helper(
+        tasks(
+            Role::Helper,
+            vec![(parameters.clone(), Arc::new(MemoryStore::default()))],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+    );
+
+    let client = PpmClient::new(&parameters, &vdaf, (), Arc::new(options.clock.clone()))
+        .await
+        .unwrap();
+
+    let assert_problem = |error: client::Error, problem_type: &str| {
+        assert_matches!(error, client::Error::ProblemDocument(problem_document) => {
+            assert_eq!(problem_document.instance, Some("upload".to_string()));
+            assert_eq!(problem_document.status, Some(StatusCode::BAD_REQUEST));
+            assert_eq!(
+                problem_document.type_url,
+                Some(format!("urn:ietf:params:ppm:error:{problem_type}"))
+            );
+        });
+    };
+
+    // Beyond the tolerable clock skew
+    assert_problem(
+        client
+            .do_upload(INTERVAL_START + 3600, &1)
+            .await
+            .unwrap_err(),
+        "reportTooEarly",
+    );
+
+    for _ in 0..100 {
+        client.upload(&1).await.unwrap();
+        options.clock.advance(Duration(1));
+    }
+
+    // Two hours pass before the leader gets around to aggregating. By then,
+    // the leader refuses new reports from the first interval and the helper
+    // refuses to aggregate the ones the leader already has.
+    options.clock.advance(Duration(7200));
+    assert_problem(
+        client.do_upload(INTERVAL_START, &1).await.unwrap_err(),
+        "reportTooLate",
+    );
+    client.run_aggregate().await.unwrap();
+
+    let error = run_collect(
+        &parameters,
+        &hpke_config.collector,
+        Interval {
+            start: Time(INTERVAL_START),
+            duration: Duration(100),
+        },
+        vdaf.clone(),
+        &(),
+        vdaf.output_len(),
+    )
+    .await
+    .unwrap_err();
+    assert_matches!(error, collect::Error::ProblemDocument(problem_document) => {
+        assert_eq!(problem_document.type_url, Some("urn:ietf:params:ppm:error:insufficientBatchSize".to_string()));
+    });
+
+    leader_handle.abort();
+    helper_handle.abort();
+    assert!(leader_handle.await.unwrap_err().is_cancelled());
+    assert!(helper_handle.await.unwrap_err().is_cancelled());
+}
+
+#[tokio::test]
+#[serial]
+async fn purge_collected_reports() {
+    let leader_store = Arc::new(MemoryStore::default());
+    let helper_store = Arc::new(MemoryStore::default());
+    let test_case = TestCase::new_with_stores(
+        false,
+        false,
+        leader_store.clone(),
+        helper_store.clone(),
+        AggregatorOptions::default(),
+    )
+    .await;
+
+    // Both aggregators keep the reports they accumulated until they're
+    // collected
+    for store in [&leader_store, &helper_store] {
+        let reports = store.reports().unwrap();
+        assert_eq!(reports.len(), 100);
+        assert!(reports
+            .iter()
+            .all(|report| report.state == ReportRecordState::Accumulated));
+    }
+
+    let sum = run_collect(
+        &test_case.parameters,
+        &test_case.hpke_config.collector,
+        Interval {
+            start: Time(INTERVAL_START),
+            duration: Duration(100),
+        },
+        test_case.vdaf.clone(),
+        &(),
+        test_case.vdaf.output_len(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(sum.0, 100);
+
+    assert!(leader_store.reports().unwrap().is_empty());
+    assert!(helper_store.reports().unwrap().is_empty());
+
+    test_case.teardown().await;
+}
+
+#[tokio::test]
+#[serial]
+async fn purge_reports_after_retention() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let parameters = Parameters {
+        max_report_age: Some(Duration(3600)),
+        report_retention: Some(Duration(3600)),
+        ..Parameters::from_json_reader(Cursor::new(include_bytes!(
+            "../sample-config/parameters.json"
+        )))
+        .unwrap()
+    };
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let (_, verify_parameters) = vdaf.setup().unwrap();
+    let options = AggregatorOptions::default();
+    let leader_store = Arc::new(MemoryStore::default());
+    let helper_store = Arc::new(MemoryStore::default());
+
+    let leader_handle = spawn_leader(
+        tasks(
+            Role::Leader,
+            vec![(parameters.clone(), leader_store.clone())],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+        &options,
+    );
+    let helper_handle = spawn_helper(
+        tasks(
+            Role::Helper,
+            vec![(parameters.clone(), helper_store.clone())],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+    );
+
+    let client = PpmClient::new(&parameters, &vdaf, (), Arc::new(options.clock.clone()))
+        .await
+        .unwrap();
+    for _ in 0..100 {
+        client.upload(&1).await.unwrap();
+        options.clock.advance(Duration(1));
+    }
+    client.run_aggregate().await.unwrap();
+    assert_eq!(leader_store.reports().unwrap().len(), 100);
+    assert_eq!(helper_store.reports().unwrap().len(), 100);
+
+    // Two hours later, a new report arrives. Aggregating it gives both
+    // aggregators the chance to purge the old reports.
+    options.clock.advance(Duration(7200));
+    client.upload(&1).await.unwrap();
+    client.run_aggregate().await.unwrap();
+
+    for store in [&leader_store, &helper_store] {
+        let reports = store.reports().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].nonce.time, Time(INTERVAL_START + 7300));
+    }
+
+    // The purged reports' contributions can still be collected
+    let sum = run_collect(
+        &parameters,
+        &hpke_config.collector,
+        Interval {
+            start: Time(INTERVAL_START),
+            duration: Duration(100),
+        },
+        vdaf.clone(),
+        &(),
+        vdaf.output_len(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(sum.0, 100);
+
+    leader_handle.abort();
+    helper_handle.abort();
+    assert!(leader_handle.await.unwrap_err().is_cancelled());
+    assert!(helper_handle.await.unwrap_err().is_cancelled());
+}
+
+#[tokio::test]
+#[serial]
+async fn reject_replayed_reports_after_purge() {
+    let mut parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+    parameters.max_report_age = Some(Duration(3600));
+    parameters.report_retention = Some(Duration(3600));
+    let leader_store = Arc::new(MemoryStore::default());
+    let helper_store = Arc::new(MemoryStore::default());
+
+    let mut test_case = TestCase::new_with_parameters(
+        parameters,
+        false,
+        false,
+        leader_store.clone(),
+        helper_store.clone(),
+        AggregatorOptions::default(),
+    )
+    .await;
+
+    let report = test_case
+        .client
+        .build_report(test_case.options.clock.now().0, &1)
+        .unwrap();
+    test_case.client.upload_report(&report).await.unwrap();
+    test_case.client.run_aggregate().await.unwrap();
+
+    // Two hours later, the first interval's reports are purged without having
+    // been collected
+    test_case.options.clock.advance(Duration(7200));
+    test_case.client.upload(&1).await.unwrap();
+    test_case.client.run_aggregate().await.unwrap();
+    assert_eq!(leader_store.reports().unwrap().len(), 1);
+    assert_eq!(helper_store.reports().unwrap().len(), 1);
+
+    // After a restart, the aggregators no longer know the purged reports, but
+    // the reports are too old to be accepted again anyway
+    test_case
+        .restart(leader_store.clone(), helper_store.clone())
+        .await;
+    let error = test_case.client.upload_report(&report).await.unwrap_err();
+    assert_matches!(error, client::Error::ProblemDocument(problem_document) => {
+        assert_eq!(
+            problem_document.type_url,
+            Some("urn:ietf:params:ppm:error:reportTooLate".to_string())
+        );
+    });
+    assert_eq!(leader_store.reports().unwrap().len(), 1);
+
+    test_case.teardown().await;
+}
+
+#[tokio::test]
+#[serial]
+async fn reject_replayed_reports() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let (_, verify_parameters) = vdaf.setup().unwrap();
+    let options = AggregatorOptions::default();
+
+    let leader_handle = spawn_leader(
+        tasks(
+            Role::Leader,
+            vec![(parameters.clone(), Arc::new(MemoryStore::default()))],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+        &options,
+    );
+    let helper_handle = spawn_helper(
+        tasks(
+            Role::Helper,
+            vec![(parameters.clone(), Arc::new(MemoryStore::default()))],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+    );
+
+    let client = PpmClient::new(&parameters, &vdaf, (), Arc::new(options.clock.clone()))
+        .await
+        .unwrap();
+
+    let mut reports = vec![];
+    for _ in 0..100 {
+        let report = client.build_report(options.clock.now().0, &1).unwrap();
+        client.upload_report(&report).await.unwrap();
+        reports.push(report);
+        options.clock.advance(Duration(1));
+    }
+
+    // The leader refuses to take on a report it has already seen, whether
+    // or not it has been aggregated yet
+    let assert_replayed = |error: client::Error| {
+        assert_matches!(error, client::Error::ProblemDocument(problem_document) => {
+            assert_eq!(problem_document.instance, Some("upload".to_string()));
+            assert_eq!(problem_document.status, Some(StatusCode::BAD_REQUEST));
+            assert_eq!(
+                problem_document.type_url,
+                Some("urn:ietf:params:ppm:error:reportReplayed".to_string())
+            );
+        });
+    };
+    assert_replayed(client.upload_report(&reports[0]).await.unwrap_err());
+    client.run_aggregate().await.unwrap();
+    assert_replayed(client.upload_report(&reports[1]).await.unwrap_err());
+    client.run_aggregate().await.unwrap();
+
+    let sum = run_collect(
+        &parameters,
+        &hpke_config.collector,
+        Interval {
+            start: Time(INTERVAL_START),
+            duration: Duration(100),
+        },
+        vdaf.clone(),
+        &(),
+        vdaf.output_len(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(sum.0, 100);
+
+    leader_handle.abort();
+    helper_handle.abort();
+    assert!(leader_handle.await.unwrap_err().is_cancelled());
+    assert!(helper_handle.await.unwrap_err().is_cancelled());
+}
+
+#[tokio::test]
+#[serial]
+async fn retry_upload_after_store_failure() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let (_, verify_parameters) = vdaf.setup().unwrap();
+    let options = AggregatorOptions::default();
+    let leader_store = Arc::new(FailingStore::default());
+
+    let leader_handle = spawn_leader(
+        tasks(
+            Role::Leader,
+            vec![(parameters.clone(), leader_store.clone())],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+        &options,
+    );
+    let helper_handle = spawn_helper(
+        tasks(
+            Role::Helper,
+            vec![(parameters.clone(), Arc::new(MemoryStore::default()))],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+    );
+
+    let client = PpmClient::new(&parameters, &vdaf, (), Arc::new(options.clock.clone()))
+        .await
+        .unwrap();
+    let report = client.build_report(options.clock.now().0, &1).unwrap();
+
+    // The leader can't store the report, so the upload fails
+    leader_store.fail_reports.store(true, Ordering::SeqCst);
+    assert_matches!(
+        client.upload_report(&report).await.unwrap_err(),
+        client::Error::ProblemDocument(problem_document) => {
+            assert_eq!(problem_document.status, Some(StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    );
+
+    // Retrying the same report isn't mistaken for a replay
+    leader_store.fail_reports.store(false, Ordering::SeqCst);
+    client.upload_report(&report).await.unwrap();
+    assert_eq!(leader_store.reports().unwrap().len(), 1);
+
+    leader_handle.abort();
+    helper_handle.abort();
+    assert!(leader_handle.await.unwrap_err().is_cancelled());
+    assert!(helper_handle.await.unwrap_err().is_cancelled());
+}
+
+#[tokio::test]
+#[serial]
+async fn leader_store_failure_during_aggregate_response() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let (_, verify_parameters) = vdaf.setup().unwrap();
+    let options = AggregatorOptions::default();
+    let leader_store = Arc::new(FailingStore::default());
+    let helper_store: Arc<dyn Store> = Arc::new(MemoryStore::default());
+
+    let leader_handle = spawn_leader(
+        tasks(
+            Role::Leader,
+            vec![(parameters.clone(), leader_store.clone())],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+        &options,
+    );
+    let helper_handle = spawn_helper(
+        tasks(
+            Role::Helper,
+            vec![(parameters.clone(), helper_store.clone())],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+    );
+
+    let client = PpmClient::new(&parameters, &vdaf, (), Arc::new(options.clock.clone()))
+        .await
+        .unwrap();
+    for count in 0..10 {
+        client.do_upload(INTERVAL_START + count, &1).await.unwrap();
+    }
+
+    // The leader can only store the first half of the reports the helper
+    // finished in its response to the one aggregation job
+    *leader_store.accumulator_writes_left.lock().unwrap() = Some(5);
+    client.run_aggregate().await.unwrap_err();
+    assert_eq!(contributions(helper_store.as_ref()), 10);
+    assert_eq!(contributions(leader_store.as_ref()), 5);
+
+    // The restarted job only holds the reports the leader couldn't store. The
+    // helper has accumulated those already, so it rejects them, but the
+    // reports the leader did accumulate stay accumulated.
+    *leader_store.accumulator_writes_left.lock().unwrap() = None;
+    leader_store.fail.store(false, Ordering::SeqCst);
+    client.run_aggregate().await.unwrap();
+
+    let report_states: Vec<_> = leader_store
+        .reports()
+        .unwrap()
+        .iter()
+        .map(|report| report.state)
+        .collect();
+    let count = |state| report_states.iter().filter(|s| **s == state).count();
+    assert_eq!(count(ReportRecordState::Accumulated), 5);
+    assert_eq!(count(ReportRecordState::Failed), 5);
+    assert_eq!(contributions(leader_store.as_ref()), 5);
+
+    leader_handle.abort();
+    helper_handle.abort();
+    assert!(leader_handle.await.unwrap_err().is_cancelled());
+    assert!(helper_handle.await.unwrap_err().is_cancelled());
+}
+
+/// Fetch the metrics of the aggregator and return the value of the sample with
+/// the given name and labels, as it appears in the Prometheus text format.
+async fn metric(parameters: &Parameters, role: Role, sample: &str) -> Option<f64> {
+    let metrics = reqwest::get(parameters.metrics_endpoint(role).unwrap())
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    metrics.lines().find_map(|line| {
+        line.strip_prefix(sample)
+            .and_then(|value| value.strip_prefix(' '))
+            .map(|value| value.parse().unwrap())
+    })
+}
+
+#[tokio::test]
+#[serial]
+async fn metrics() {
+    let test_case = TestCase::new().await;
+    let parameters = &test_case.parameters;
+    let collect_interval = Interval {
+        start: Time(INTERVAL_START),
+        duration: Duration(100),
+    };
+
+    let report = test_case
+        .client
+        .build_report(test_case.options.clock.now().0, &1)
+        .unwrap();
+    test_case.client.upload_report(&report).await.unwrap();
+    test_case.client.upload_report(&report).await.unwrap_err();
+
+    for _ in 0..2 {
+        let _ = run_collect(
+            parameters,
+            &test_case.hpke_config.collector,
+            collect_interval,
+            test_case.vdaf.clone(),
+            &(),
+            test_case.vdaf.output_len(),
+        )
+        .await;
+        // Make the next collect request start a new job
+        test_case.options.clock.advance(COLLECT_JOB_TTL);
+    }
+
+    assert_eq!(
+        metric(parameters, Role::Leader, "ppm_uploads_accepted_total").await,
+        Some(101.0)
+    );
+    assert_eq!(
+        metric(
+            parameters,
+            Role::Leader,
+            "ppm_uploads_rejected_total{problem_type=\"reportReplayed\"}"
+        )
+        .await,
+        Some(1.0)
+    );
+    // The leader only notices the replay after preparing the report
+    assert_eq!(
+        metric(parameters, Role::Leader, "ppm_reports_prepared_total").await,
+        Some(102.0)
+    );
+    assert_eq!(
+        metric(parameters, Role::Helper, "ppm_reports_prepared_total").await,
+        Some(100.0)
+    );
+    assert_eq!(
+        metric(parameters, Role::Leader, "ppm_collect_requests_total").await,
+        Some(2.0)
+    );
+    assert_eq!(
+        metric(
+            parameters,
+            Role::Leader,
+            "ppm_privacy_budget_rejections_total"
+        )
+        .await,
+        Some(1.0)
+    );
+    // The second collect request is turned down by the leader before it asks
+    // the helper for its aggregate share
+    assert_eq!(
+        metric(parameters, Role::Helper, "ppm_collect_requests_total").await,
+        Some(1.0)
+    );
+    assert_eq!(
+        metric(
+            parameters,
+            Role::Leader,
+            "ppm_helper_request_duration_seconds_count{endpoint=\"aggregate_share\"}"
+        )
+        .await,
+        Some(1.0)
+    );
+
+    let aggregate_rounds = metric(parameters, Role::Leader, "ppm_aggregate_rounds_total").await;
+    assert!(aggregate_rounds.unwrap() > 0.0);
+    assert_eq!(
+        metric(parameters, Role::Helper, "ppm_aggregate_rounds_total").await,
+        aggregate_rounds
+    );
+    assert_eq!(
+        metric(parameters, Role::Helper, "ppm_hpke_decrypt_failures_total").await,
+        Some(0.0)
+    );
+
+    test_case.teardown().await;
+}
+
+/// Wait for the aggregator to come up, then ask whether it's ready.
+async fn readiness(parameters: &Parameters, role: Role) -> (StatusCode, Readiness) {
+    let endpoint = &parameters.aggregator_endpoints[role.index()];
+    while reqwest::get(endpoint.join("healthz").unwrap())
+        .await
+        .is_err()
+    {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    let response = reqwest::get(endpoint.join("readyz").unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+
+    (status, response.json().await.unwrap())
+}
+
+/// The error of the named check, which must have been run.
+fn check_error<'a>(readiness: &'a Readiness, name: &str) -> Option<&'a str> {
+    readiness
+        .checks
+        .iter()
+        .find(|check| check.name == name)
+        .unwrap()
+        .error
+        .as_deref()
+}
+
+#[tokio::test]
+#[serial]
+async fn health_and_readiness() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let (_, verify_parameters) = vdaf.setup().unwrap();
+    let options = AggregatorOptions::default();
+
+    let leader_handle = spawn_leader(
+        tasks(
+            Role::Leader,
+            vec![(parameters.clone(), Arc::new(MemoryStore::default()))],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+        &options,
+    );
+
+    // Without its helper, the leader is alive but not ready
+    let (status, leader_readiness) = readiness(&parameters, Role::Leader).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(!leader_readiness.ready);
+    assert!(check_error(&leader_readiness, "helper").is_some());
+    assert_eq!(check_error(&leader_readiness, "storage"), None);
+    assert_eq!(check_error(&leader_readiness, "hpke_config"), None);
+    assert_eq!(check_error(&leader_readiness, "config"), None);
+
+    let helper_store = Arc::new(FailingStore::default());
+    helper_store.fail.store(true, Ordering::SeqCst);
+    let helper_handle = spawn_helper(
+        tasks(
+            Role::Helper,
+            vec![(parameters.clone(), helper_store.clone())],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+    );
+
+    let (status, helper_readiness) = readiness(&parameters, Role::Helper).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(check_error(&helper_readiness, "storage")
+        .unwrap()
+        .contains("injected failure"));
+
+    // The helper needn't be ready for the leader to be
+    let (status, leader_readiness) = readiness(&parameters, Role::Leader).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(leader_readiness.ready);
+
+    helper_store.fail.store(false, Ordering::SeqCst);
+    let (status, helper_readiness) = readiness(&parameters, Role::Helper).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(helper_readiness.ready);
+
+    leader_handle.abort();
+    helper_handle.abort();
+    assert!(leader_handle.await.unwrap_err().is_cancelled());
+    assert!(helper_handle.await.unwrap_err().is_cancelled());
+}
+
+#[tokio::test]
+#[serial]
+async fn vdaf_from_parameters() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let sample_parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+
+    let parameters = Parameters {
+        vdaf: VdafLabel::Prio3Count64,
+        ..sample_parameters.clone()
+    };
+    let vdaf = assert_matches!(
+        parameters.vdaf_instance(),
+        Ok(VdafInstance::Prio3Count64(vdaf)) => vdaf
+    );
+    let aggregate_share_len = vdaf.output_len();
+    let count = aggregate_from_parameters(parameters, vdaf, &[1, 0, 1], aggregate_share_len).await;
+    assert_eq!(count.0, 2);
+
+    let parameters = Parameters {
+        vdaf: VdafLabel::Prio3Histogram64 {
+            buckets: vec![10, 20],
+        },
+        ..sample_parameters
+    };
+    let vdaf = assert_matches!(
+        parameters.vdaf_instance(),
+        Ok(VdafInstance::Prio3Histogram64(vdaf)) => vdaf
+    );
+    let aggregate_share_len = vdaf.output_len();
+    let histogram =
+        aggregate_from_parameters(parameters, vdaf, &[5, 15, 25, 35], aggregate_share_len).await;
+    assert_eq!(histogram.0, vec![1, 1, 2]);
+}
+
+#[tokio::test]
+#[serial]
+async fn differential_privacy() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let sample_parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+
+    // One measurement in each of 21 buckets
+    let parameters = Parameters {
+        vdaf: VdafLabel::Prio3Histogram64 {
+            buckets: (1..=20).collect(),
+        },
+        differential_privacy: Some(DifferentialPrivacy::DiscreteLaplace { epsilon: 1.0 }),
+        ..sample_parameters.clone()
+    };
+    let vdaf = assert_matches!(
+        parameters.vdaf_instance(),
+        Ok(VdafInstance::Prio3Histogram64(vdaf)) => vdaf
+    );
+    let aggregate_share_len = vdaf.output_len();
+    let measurements: Vec<u128> = (0..=20).collect();
+    let histogram =
+        aggregate_from_parameters(parameters, vdaf, &measurements, aggregate_share_len).await;
+
+    // Each aggregator adds discrete Laplace noise with scale 1 to each bucket.
+    // The chance of the noise exceeding 20 in a bucket is negligible, as is
+    // the chance of no bucket getting any noise.
+    assert_eq!(histogram.0.len(), 21);
+    assert!(
+        histogram.0.iter().all(|count| *count <= 21),
+        "{:?}",
+        histogram
+    );
+    assert_ne!(histogram.0, vec![1; 21]);
+
+    // Sums of many bits would need too much noise to be useful
+    let parameters = Parameters {
+        vdaf: VdafLabel::Prio3Sum64 { bits: 63 },
+        differential_privacy: Some(DifferentialPrivacy::DiscreteLaplace { epsilon: 1.0 }),
+        ..sample_parameters
+    };
+    assert!(parameters.noise().is_err());
+}
+
+/// Run a leader and helper serving a single task with the provided VDAF,
+/// upload one report of each measurement and collect the result. The batch
+/// size requirement is lowered to the number of measurements.
+async fn aggregate_from_parameters<V>(
+    parameters: Parameters,
+    vdaf: V,
+    measurements: &[V::Measurement],
+    aggregate_share_len: usize,
+) -> V::AggregateResult
+where
+    V: vdaf::Client<PublicParam = ()>
+        + vdaf::Collector
+        + vdaf::Aggregator<AggregationParam = ()>
+        + Clone
+        + 'static
+        + Send
+        + Sync,
+    V::VerifyParam: Send + Sync,
+    V::PrepareStep: Send + Sync,
+    V::AggregateShare: Send + Sync + Serialize + DeserializeOwned + NoisyAggregateShare,
+    V::PrepareMessage: Send + Sync,
+    V::OutputShare: Send + Sync,
+{
+    let parameters = Parameters {
+        min_batch_size: measurements.len() as u64,
+        ..parameters
+    };
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+    let (_, verify_parameters) = vdaf.setup().unwrap();
+    let options = AggregatorOptions::default();
+
+    let leader_handle = spawn_leader(
+        tasks(
+            Role::Leader,
+            vec![(parameters.clone(), Arc::new(MemoryStore::default()))],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+        &options,
+    );
+    let helper_handle = spawn_helper(
+        tasks(
+            Role::Helper,
+            vec![(parameters.clone(), Arc::new(MemoryStore::default()))],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ),
+        &hpke_config,
+    );
+
+    // Wait for both servers to come up
+    let http_client = reqwest::Client::new();
+    for role in [Role::Leader, Role::Helper] {
+        while parameters.hpke_config(role, &http_client).await.is_err() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    let client = PpmClient::new(&parameters, &vdaf, (), Arc::new(options.clock.clone()))
+        .await
+        .unwrap();
+    for measurement in measurements {
+        client.upload(measurement).await.unwrap();
+        options.clock.advance(Duration(1));
+    }
+    client.run_aggregate().await.unwrap();
+
+    let result = run_collect(
+        &parameters,
+        &hpke_config.collector,
+        Interval {
+            start: Time(INTERVAL_START),
+            duration: Duration(100),
+        },
+        vdaf,
+        &(),
+        aggregate_share_len,
+    )
+    .await
+    .unwrap();
+
+    leader_handle.abort();
+    helper_handle.abort();
+    assert!(leader_handle.await.unwrap_err().is_cancelled());
+    assert!(helper_handle.await.unwrap_err().is_cancelled());
+
+    result
+}
+
+#[tokio::test]
+#[serial]
+async fn heavy_hitters() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let sample_parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+    // Two-bit strings, each bit of which is a level of the prefix tree.
+    // IdpfInput takes bits from the least significant end of each byte.
+    let measurements: Vec<_> = [1u8, 1, 1, 2, 2, 3]
+        .iter()
+        .map(|string| IdpfInput::new(&[*string], 2).unwrap())
+        .collect();
+    let parameters = Parameters {
+        vdaf: VdafLabel::Hits { bits: 2 },
+        min_batch_size: measurements.len() as u64,
+        // Enough to collect both levels of the tree and then some
+        max_batch_lifetime: 3,
+        ..sample_parameters
+    };
+    let vdaf = assert_matches!(
+        parameters.vdaf_instance(),
+        Ok(VdafInstance::Hits { vdaf, .. }) => vdaf
+    );
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+    let collect_interval = Interval {
+        start: Time(INTERVAL_START),
+        duration: Duration(100),
+    };
+
+    // Reports get prepared anew for each collect request, both by a helper
+    // that keeps prepare state and by one that hands it to the leader
+    for helper_state_key in [None, Some(HelperStateKey::generate())] {
+        let options = AggregatorOptions {
+            helper_state_key,
+            ..Default::default()
+        };
+
+        let (leader_handle, helper_handle) = spawn_heavy_hitters_aggregators(
+            &parameters,
+            &vdaf,
+            &hpke_config,
+            &options,
+            &measurements,
+        )
+        .await;
+
+        let mut counts_by_level = vec![];
+        for (level, strings) in [(1, vec![0u8, 1]), (2, vec![1, 2, 3])] {
+            let prefixes: BTreeSet<_> = strings
+                .iter()
+                .map(|string| IdpfInput::new(&[*string], level).unwrap())
+                .collect();
+            let counts = run_collect(
+                &parameters,
+                &hpke_config.collector,
+                collect_interval,
+                vdaf.clone(),
+                &prefixes,
+                prefixes.len(),
+            )
+            .await
+            .unwrap();
+            counts_by_level.push(counts.into_values().collect::<Vec<_>>());
+        }
+        assert_eq!(counts_by_level, vec![vec![2, 4], vec![3, 2, 1]]);
+
+        // Prefixes longer than the strings can't be aggregated. Asking for
+        // them doesn't use up privacy budget.
+        let prefixes = BTreeSet::from([IdpfInput::new(&[0], 3).unwrap()]);
+        let error = run_collect(
+            &parameters,
+            &hpke_config.collector,
+            collect_interval,
+            vdaf.clone(),
+            &prefixes,
+            prefixes.len(),
+        )
+        .await
+        .unwrap_err();
+        assert_matches!(error, collect::Error::ProblemDocument(problem_document) => {
+            assert_eq!(problem_document.type_url, Some("urn:ietf:params:ppm:error:unknownAggregationParameter".to_string()));
+        });
+
+        let prefixes = BTreeSet::from([IdpfInput::new(&[0], 1).unwrap()]);
+        let counts = run_collect(
+            &parameters,
+            &hpke_config.collector,
+            collect_interval,
+            vdaf.clone(),
+            &prefixes,
+            prefixes.len(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(counts.into_values().collect::<Vec<_>>(), vec![2]);
+
+        // Each collect job used up privacy budget for the interval
+        options.clock.advance(COLLECT_JOB_TTL);
+        let error = run_collect(
+            &parameters,
+            &hpke_config.collector,
+            collect_interval,
+            vdaf.clone(),
+            &prefixes,
+            prefixes.len(),
+        )
+        .await
+        .unwrap_err();
+        assert_matches!(error, collect::Error::ProblemDocument(problem_document) => {
+            assert_eq!(problem_document.type_url, Some("urn:ietf:params:ppm:error:privacyBudgetExceeded".to_string()));
+        });
+
+        leader_handle.abort();
+        helper_handle.abort();
+        assert!(leader_handle.await.unwrap_err().is_cancelled());
+        assert!(helper_handle.await.unwrap_err().is_cancelled());
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn heavy_hitters_restart() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let sample_parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+    let measurements: Vec<_> = [1u8, 1, 1, 2, 2, 3]
+        .iter()
+        .map(|string| IdpfInput::new(&[*string], 2).unwrap())
+        .collect();
+    let parameters = Parameters {
+        vdaf: VdafLabel::Hits { bits: 2 },
+        min_batch_size: measurements.len() as u64,
+        max_batch_lifetime: 2,
+        ..sample_parameters
+    };
+    let vdaf = assert_matches!(
+        parameters.vdaf_instance(),
+        Ok(VdafInstance::Hits { vdaf, .. }) => vdaf
+    );
+    let (_, verify_parameters) = vdaf.setup().unwrap();
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+    let collect_interval = Interval {
+        start: Time(INTERVAL_START),
+        duration: Duration(100),
+    };
+    let prefixes: BTreeSet<_> = [0u8, 1]
+        .iter()
+        .map(|string| IdpfInput::new(&[*string], 1).unwrap())
+        .collect();
+
+    for helper_state_key in [None, Some(HelperStateKey::generate())] {
+        let options = AggregatorOptions {
+            helper_state_key,
+            ..Default::default()
+        };
+        let state_dir = std::env::temp_dir().join(format!(
+            "ppm-prototype-heavy-hitters-restart-{}",
+            rand::random::<u64>()
+        ));
+        std::fs::create_dir_all(&state_dir).unwrap();
+        let leader_state = state_dir.join("leader-state.json");
+        let helper_state = state_dir.join("helper-state.json");
+
+        let (leader_handle, helper_handle) = serve_heavy_hitters_task(
+            &parameters,
+            &vdaf,
+            &verify_parameters,
+            &hpke_config,
+            &options,
+            Arc::new(FileStore::open(&leader_state).unwrap()),
+            Arc::new(FileStore::open(&helper_state).unwrap()),
+        )
+        .await;
+        upload_heavy_hitters_measurements(&parameters, &vdaf, &options, &measurements).await;
+
+        let counts = run_collect(
+            &parameters,
+            &hpke_config.collector,
+            collect_interval,
+            vdaf.clone(),
+            &prefixes,
+            prefixes.len(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(counts.into_values().collect::<Vec<_>>(), vec![2, 4]);
+
+        leader_handle.abort();
+        helper_handle.abort();
+        assert!(leader_handle.await.unwrap_err().is_cancelled());
+        assert!(helper_handle.await.unwrap_err().is_cancelled());
+
+        // After a restart, the aggregators must remember which reports they
+        // aggregated under the prefixes rather than aggregate them again
+        let (leader_handle, helper_handle) = serve_heavy_hitters_task(
+            &parameters,
+            &vdaf,
+            &verify_parameters,
+            &hpke_config,
+            &options,
+            Arc::new(FileStore::open(&leader_state).unwrap()),
+            Arc::new(FileStore::open(&helper_state).unwrap()),
+        )
+        .await;
+
+        let counts = run_collect(
+            &parameters,
+            &hpke_config.collector,
+            collect_interval,
+            vdaf.clone(),
+            &prefixes,
+            prefixes.len(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(counts.into_values().collect::<Vec<_>>(), vec![2, 4]);
+
+        leader_handle.abort();
+        helper_handle.abort();
+        assert!(leader_handle.await.unwrap_err().is_cancelled());
+        assert!(helper_handle.await.unwrap_err().is_cancelled());
+        std::fs::remove_dir_all(&state_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn heavy_hitters_driver() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let sample_parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+    let measurements: Vec<_> = [
+        0b0101u8, 0b0101, 0b0101, 0b0101, 0b0110, 0b0110, 0b0110, 0b1111, 0b0001,
+    ]
+    .iter()
+    .map(|string| IdpfInput::new(&[*string], 4).unwrap())
+    .collect();
+    let parameters = Parameters {
+        vdaf: VdafLabel::Hits { bits: 4 },
+        min_batch_size: measurements.len() as u64,
+        // One collect request per level of the tree
+        max_batch_lifetime: 4,
+        ..sample_parameters
+    };
+    let vdaf = assert_matches!(
+        parameters.vdaf_instance(),
+        Ok(VdafInstance::Hits { vdaf, .. }) => vdaf
+    );
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+    let options = AggregatorOptions::default();
+
+    let (leader_handle, helper_handle) =
+        spawn_heavy_hitters_aggregators(&parameters, &vdaf, &hpke_config, &options, &measurements)
+            .await;
+
+    let heavy_hitters = run_heavy_hitters(
+        &parameters,
+        &hpke_config.collector,
+        Interval {
+            start: Time(INTERVAL_START),
+            duration: Duration(100),
+        },
+        vdaf,
+        4,
+        3,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        heavy_hitters.into_iter().collect::<Vec<_>>(),
+        vec![(0b0101, 4), (0b0110, 3)]
+    );
+
+    leader_handle.abort();
+    helper_handle.abort();
+    assert!(leader_handle.await.unwrap_err().is_cancelled());
+    assert!(helper_handle.await.unwrap_err().is_cancelled());
+}
+
+/// Run a leader and helper serving a single heavy hitters task, which
+/// aggregate reports under the aggregation parameter of each collect request,
+/// and upload one report of each measurement.
+async fn spawn_heavy_hitters_aggregators(
+    parameters: &Parameters,
+    vdaf: &Poplar1Aes128,
+    hpke_config: &hpke::ConfigFile,
+    options: &AggregatorOptions,
+    measurements: &[IdpfInput],
+) -> (JoinHandle<Result<()>>, JoinHandle<Result<()>>) {
+    let (_, verify_parameters) = vdaf.setup().unwrap();
+
+    let handles = serve_heavy_hitters_task(
+        parameters,
+        vdaf,
+        &verify_parameters,
+        hpke_config,
+        options,
+        Arc::new(MemoryStore::default()),
+        Arc::new(MemoryStore::default()),
+    )
+    .await;
+    upload_heavy_hitters_measurements(parameters, vdaf, options, measurements).await;
+
+    handles
+}
+
+/// Run a leader and helper serving a single heavy hitters task out of the
+/// stores, and wait for both to come up.
+async fn serve_heavy_hitters_task(
+    parameters: &Parameters,
+    vdaf: &Poplar1Aes128,
+    verify_parameters: &[<Poplar1Aes128 as Vdaf>::VerifyParam],
+    hpke_config: &hpke::ConfigFile,
+    options: &AggregatorOptions,
+    leader_store: Arc<dyn Store>,
+    helper_store: Arc<dyn Store>,
+) -> (JoinHandle<Result<()>>, JoinHandle<Result<()>>) {
+    let leader_handle = spawn_leader(
+        tasks_with_aggregation_parameter(
+            Role::Leader,
+            vec![(parameters.clone(), leader_store)],
+            vdaf,
+            verify_parameters,
+            None,
+            options,
+        ),
+        hpke_config,
+        options,
+    );
+    let helper_handle = spawn_helper(
+        tasks_with_aggregation_parameter(
+            Role::Helper,
+            vec![(parameters.clone(), helper_store)],
+            vdaf,
+            verify_parameters,
+            None,
+            options,
+        ),
+        hpke_config,
+    );
+
+    let http_client = reqwest::Client::new();
+    for role in [Role::Leader, Role::Helper] {
+        while parameters.hpke_config(role, &http_client).await.is_err() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    (leader_handle, helper_handle)
+}
+
+async fn upload_heavy_hitters_measurements(
+    parameters: &Parameters,
+    vdaf: &Poplar1Aes128,
+    options: &AggregatorOptions,
+    measurements: &[IdpfInput],
+) {
+    let client = PpmClient::new(parameters, vdaf, (), Arc::new(options.clock.clone()))
+        .await
+        .unwrap();
+    for measurement in measurements {
+        client.upload(measurement).await.unwrap();
+        options.clock.advance(Duration(1));
+    }
+    // Without an aggregation parameter, there is nothing to aggregate yet
+    client.run_aggregate().await.unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn invalid_aggregation_parameter() {
+    let test_case = TestCase::new().await;
+    let batch_interval = Interval {
+        start: Time(INTERVAL_START),
+        duration: Duration(100),
+    };
+
+    // The helper only releases aggregate shares under the aggregation
+    // parameter the task aggregates under, even to the leader
+    let aggregate_message = AggregateMessage::new(
+        Aggregate::ShareRequest(AggregateShareReq {
+            task_id: test_case.parameters.task_id,
+            batch_interval,
+            aggregation_parameter: vec![1],
+        }),
+        &test_case.parameters.aggregator_auth_key,
+    );
+
+    let response = reqwest::Client::new()
+        .post(test_case.parameters.aggregate_share_endpoint().unwrap())
+        .body(aggregate_message.get_encoded())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let problem_document: HttpApiProblem = response.json().await.unwrap();
+    assert_eq!(
+        problem_document.type_url,
+        Some("urn:ietf:params:ppm:error:invalidAggregationParameter".to_string())
+    );
+
+    let sum = run_collect(
+        &test_case.parameters,
+        &test_case.hpke_config.collector,
+        batch_interval,
+        test_case.vdaf.clone(),
+        &(),
+        test_case.vdaf.output_len(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(sum.0, 100);
+
+    test_case.teardown().await;
+}
+
+/// Sum of the contributions to all accumulators in a store
+fn contributions(store: &dyn Store) -> u64 {
+    store
+        .accumulators()
+        .unwrap()
+        .iter()
+        .map(|accumulator| accumulator.contributions)
+        .sum()
+}
+
+#[tokio::test]
+#[serial]
+async fn graceful_shutdown() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let mut parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let (_, verify_parameters) = vdaf.setup().unwrap();
+    let options = AggregatorOptions::default();
+    let leader_store: Arc<dyn Store> = Arc::new(MemoryStore::default());
+    let helper_store: Arc<dyn Store> = Arc::new(MemoryStore::default());
+    let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+
+    // Bind both servers to unused ports, and point the leader and the client
+    // at wherever they ended up
+    let (stop_helper, helper_stopped) = tokio::sync::oneshot::channel::<()>();
+    let helper = HelperBuilder::new(&hpke_config.helper)
+        .tasks(tasks(
+            Role::Helper,
+            vec![(parameters.clone(), helper_store.clone())],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ))
+        .address(localhost)
+        .shutdown_signal(async move {
+            let _ = helper_stopped.await;
+        })
+        .start()
+        .unwrap();
+    parameters.aggregator_endpoints[Role::Helper.index()] =
+        Url::parse(&format!("http://{}", helper.local_addr())).unwrap();
+
+    let leader = LeaderBuilder::new(&hpke_config.leader)
+        .tasks(tasks(
+            Role::Leader,
+            vec![(parameters.clone(), leader_store.clone())],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ))
+        .address(localhost)
+        .start()
+        .unwrap();
+    parameters.aggregator_endpoints[Role::Leader.index()] =
+        Url::parse(&format!("http://{}", leader.local_addr())).unwrap();
+
+    let client = PpmClient::new(&parameters, &vdaf, (), Arc::new(options.clock.clone()))
+        .await
+        .unwrap();
+    for _ in 0..100 {
+        client.do_upload(options.clock.now().0, &1).await.unwrap();
+        options.clock.advance(Duration(1));
+    }
+    client.run_aggregate().await.unwrap();
+
+    // The leader keeps its accumulators in memory until it shuts down
+    assert_eq!(contributions(helper_store.as_ref()), 100);
+    leader.shutdown().await.unwrap();
+    assert_eq!(contributions(leader_store.as_ref()), 100);
+
+    // The helper stops once its shutdown signal resolves
+    stop_helper.send(()).unwrap();
+    helper.stopped().await.unwrap();
+
+    // Nobody accepts uploads anymore
+    client
+        .do_upload(options.clock.now().0, &1)
+        .await
+        .unwrap_err();
+}
+
+#[tokio::test]
+#[serial]
+async fn mutual_tls() {
+    INSTALL_TRACE_SUBSCRIBER.call_once(trace::install_subscriber);
+
+    let leader_certificate = include_bytes!("../sample-config/tls/leader.pem");
+    let leader_key = include_bytes!("../sample-config/tls/leader-key.pem");
+    let helper_certificate = include_bytes!("../sample-config/tls/helper.pem");
+    let helper_key = include_bytes!("../sample-config/tls/helper-key.pem");
+
+    let mut parameters = Parameters::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/parameters.json"
+    )))
+    .unwrap();
+    let hpke_config = hpke::ConfigFile::from_json_reader(Cursor::new(include_bytes!(
+        "../sample-config/hpke.json"
+    )))
+    .unwrap();
+    let vdaf = Prio3Aes128Sum::new(2, 63).unwrap();
+    let (_, verify_parameters) = vdaf.setup().unwrap();
+    let options = AggregatorOptions::default();
+    let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+    let helper_store = Arc::new(MemoryStore::default());
+
+    // The helper pins the leader's certificate, and the leader trusts the
+    // helper's self-signed certificate
+    let helper = HelperBuilder::new(&hpke_config.helper)
+        .tasks(tasks(
+            Role::Helper,
+            vec![(parameters.clone(), helper_store.clone())],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ))
+        .address(localhost)
+        .tls(
+            TlsConfig::from_pem(helper_certificate, helper_key)
+                .unwrap()
+                .pin_leader_certificate(leader_certificate)
+                .unwrap(),
+        )
+        .start()
+        .unwrap();
+    parameters.aggregator_endpoints[Role::Helper.index()] =
+        Url::parse(&format!("https://localhost:{}", helper.local_addr().port())).unwrap();
+
+    // The leader hands out collect job URIs under its own endpoint, so it
+    // listens on the port in its parameters
+    parameters.aggregator_endpoints[Role::Leader.index()]
+        .set_scheme("https")
+        .unwrap();
+    let leader = LeaderBuilder::new(&hpke_config.leader)
+        .tasks(tasks(
+            Role::Leader,
+            vec![(parameters.clone(), Arc::new(MemoryStore::default()))],
+            &vdaf,
+            &verify_parameters,
+            &options,
+        ))
+        .tls(
+            TlsConfig::from_pem(leader_certificate, leader_key)
+                .unwrap()
+                .trust_certificates(helper_certificate)
+                .unwrap(),
+        )
+        .start()
+        .unwrap();
+
+    let client = |identity: Option<(&[u8], &[u8])>| {
+        let mut builder = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(leader_certificate).unwrap())
+            .add_root_certificate(reqwest::Certificate::from_pem(helper_certificate).unwrap());
+        if let Some((certificate, key)) = identity {
+            builder = builder
+                .identity(reqwest::Identity::from_pem(&[certificate, key].concat()).unwrap());
+        }
+        builder.build().unwrap()
+    };
+    let anonymous = client(None);
+
+    // Anyone may fetch the helper's HPKE config
+    let helper_hpke_config = parameters
+        .hpke_config(Role::Helper, &anonymous)
+        .await
+        .unwrap();
+    assert_eq!(helper_hpke_config.id, hpke_config.helper.id);
+
+    // Only the leader may ask the helper to aggregate
+    let response = anonymous
+        .post(parameters.aggregate_endpoint().unwrap())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = anonymous
+        .post(parameters.aggregate_share_endpoint().unwrap())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // The helper doesn't complete a handshake with a client presenting any
+    // other certificate
+    client(Some((helper_certificate, helper_key)))
+        .post(parameters.aggregate_endpoint().unwrap())
+        .send()
+        .await
+        .unwrap_err();
+
+    // With the leader's certificate, the request gets as far as being decoded
+    let response = client(Some((leader_certificate, leader_key)))
+        .post(parameters.aggregate_endpoint().unwrap())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // The leader serves HTTPS too, and reaches the helper over it
+    let response = anonymous
+        .get(
+            parameters.aggregator_endpoints[Role::Leader.index()]
+                .join("readyz")
+                .unwrap(),
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let readiness: Readiness = response.json().await.unwrap();
+    assert_eq!(check_error(&readiness, "helper"), None);
+
+    // Reports uploaded over HTTPS are aggregated and collected with the leader
+    // authenticating to the helper
+    let ppm_client = PpmClient::with_http_client(
+        &parameters,
+        &vdaf,
+        (),
+        Arc::new(options.clock.clone()),
+        client::http_client_builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(leader_certificate).unwrap())
+            .add_root_certificate(reqwest::Certificate::from_pem(helper_certificate).unwrap())
+            .build()
+            .unwrap(),
+    )
+    .await
+    .unwrap();
+    for _ in 0..100 {
+        ppm_client.upload(&1).await.unwrap();
+        options.clock.advance(Duration(1));
+    }
+    ppm_client.run_aggregate().await.unwrap();
+    assert_eq!(contributions(helper_store.as_ref()), 100);
+
+    let sum = run_collect_with_http_client(
+        &collect::http_client_builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(leader_certificate).unwrap())
+            .build()
+            .unwrap(),
+        &parameters,
+        &hpke_config.collector,
+        Interval {
+            start: Time(INTERVAL_START),
+            duration: Duration(100),
+        },
+        vdaf.clone(),
+        &(),
+        vdaf.output_len(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(sum.0, 100);
+
+    leader.shutdown().await.unwrap();
+    helper.shutdown().await.unwrap();
+}